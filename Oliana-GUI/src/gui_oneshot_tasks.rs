@@ -5,25 +5,34 @@ pub fn determine_if_we_have_local_gpu(mut commands: Commands) {
 
     commands.spawn_task(|| async move {
 
-        let sentinel_val = std::collections::HashMap::<String, u32>::new();
-        if let Err(e) = oliana_lib::files::set_cache_file_server_proc_restart_data(&sentinel_val) {
+        let sentinel_val = std::collections::HashMap::<String, oliana_lib::launchers::ProcStatus>::new();
+        if let Err(e) = oliana_lib::files::set_cache_file_server_proc_statuses_data(&sentinel_val) {
             eprintln!("{}:{} {:?}", file!(), line!(), e);
         }
 
-        tokio::time::sleep(std::time::Duration::from_millis(1400)).await; // Allow one 1/2 tick for file to be cleared
-
-        let t0_restarts = tally_server_subproc_restarts();
-
-        tokio::time::sleep(std::time::Duration::from_millis(3 * 2600)).await;
-
-        let t1_restarts = tally_server_subproc_restarts();
-
-        let expected_num_subprocs = tally_expected_num_subprocs();
-
-        eprintln!("t0_restarts={t0_restarts} t1_restarts={t1_restarts}");
+        // The server gives up on a sub-process which keeps exiting (ProcState::Failed) within a minute or so, and marks the ones which have loaded
+        // their models Healthy; we wait for one or the other, and keep using the local tools if neither happens in time.
+        // Only the backends our jobs are routed to count: with OLIANA_IMAGE_BACKEND=auto a Failed oliana_images just sends images to
+        // oliana_images_procedural, which needs no GPU, so its own failures say nothing about our hardware either.
+        let image_backend_policy = oliana_server_lib::ImageBackendPolicy::from_env();
+        let mut failed_subprocs: Vec<String> = vec![];
+        for _ in 0..90 {
+            tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+            let statuses = read_server_subproc_statuses();
+            let routed_subprocs = [
+                oliana_server_lib::JobKind::Text.backend_bin_name(),
+                oliana_server_lib::job_queue::pick_backend(oliana_server_lib::JobKind::Image, image_backend_policy, &statuses),
+            ];
+            let gpu_subprocs: Vec<&str> = routed_subprocs.into_iter().filter(|name| *name != oliana_server_lib::job_queue::PROCEDURAL_IMAGE_BACKEND).collect();
+            failed_subprocs = gpu_subprocs.iter().filter(|name| statuses.get(**name).map(|v| v.state) == Some(oliana_lib::launchers::ProcState::Failed)).map(|name| name.to_string()).collect();
+            let all_healthy = gpu_subprocs.iter().all(|name| statuses.get(*name).map(|v| v.state) == Some(oliana_lib::launchers::ProcState::Healthy));
+            if failed_subprocs.len() > 0 || (statuses.len() > 0 && all_healthy) {
+                break;
+            }
+        }
 
-        if t1_restarts > expected_num_subprocs && t1_restarts > t0_restarts {
-            eprintln!("We think we do not have GPU hardware because t1_restarts={t1_restarts} > t0_restarts={t0_restarts} (expected expected_num_subprocs={expected_num_subprocs}");
+        if failed_subprocs.len() > 0 {
+            eprintln!("We think we do not have GPU hardware because these server sub-processes failed to stay running: {:?}", failed_subprocs);
             let mut maybe_tokio_rt: Option<tokio::runtime::Handle> = None;
             if let Ok(mut globals_wl) = GLOBALS.try_write() {
                 maybe_tokio_rt = globals_wl.tokio_rt.clone();
@@ -50,7 +59,7 @@ pub fn determine_if_we_have_local_gpu(mut commands: Commands) {
             }
         }
         else {
-          eprintln!("We know our local AI tools are working, no longer checking process restart counts!");
+          eprintln!("We know our local AI tools are working, no longer checking process statuses!");
         }
 
 
@@ -73,32 +82,12 @@ pub fn scan_for_an_open_tcp_port_at(servers: &[&str]) -> String {
     return picked;
 }
 
-pub fn tally_server_subproc_restarts() -> usize {
-    let mut num_restarts: usize = 0;
-    match oliana_lib::files::get_cache_file_server_proc_restart_data() {
-        Ok(data) => {
-            for (_k,v) in data.into_iter() {
-                num_restarts += v as usize;
-            }
-        }
-        Err(e) => {
-            eprintln!("{}:{} {:?}", file!(), line!(), e);
-        }
-    }
-    return num_restarts;
-}
-
-pub fn tally_expected_num_subprocs() -> usize {
-    let mut num_subprocs: usize = 0;
-    match oliana_lib::files::get_cache_file_server_proc_restart_data() {
-        Ok(data) => {
-            for (_k,_v) in data.into_iter() {
-                num_subprocs += 1;
-            }
-        }
+pub fn read_server_subproc_statuses() -> std::collections::HashMap::<String, oliana_lib::launchers::ProcStatus> {
+    match oliana_lib::files::get_cache_file_server_proc_statuses_data() {
+        Ok(data) => data,
         Err(e) => {
             eprintln!("{}:{} {:?}", file!(), line!(), e);
+            std::collections::HashMap::new()
         }
     }
-    return num_subprocs;
 }
@@ -203,6 +203,16 @@ pub fn gui_setup(
                 },
                 gui_structs::Background_Image,
             ));
+            node.spawn(( // Transparent everywhere but the character, so the background shows around them
+                Sprite {
+                    anchor: bevy::sprite::Anchor::Center,
+                    image_mode: bevy::ui::prelude::SpriteImageMode::Auto,
+                    custom_size: Some(bevy::math::f32::Vec2{x: 240.0, y:351.0}), // The "portrait" image size, 832x1216, scaled down
+                    ..default()
+                },
+                Transform::from_xyz(200.0, 0.0, 1.0),
+                gui_structs::Foreground_Character,
+            ));
         });
     });
 
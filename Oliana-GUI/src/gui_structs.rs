@@ -8,7 +8,8 @@ use crate::*;
 #[derive(Debug, bevy::ecs::event::Event)]
 pub struct PromptToAI(pub String, pub String);
 
-// first string is type of prompt, second string is prompt reply. if "text" second string is simply the string, if "image" the second string is a file path to a .png.
+// first string is type of prompt, second string is prompt reply. if "text" second string is simply the string, if "image" or "character" the second string is a file path to a .png.
+// "image-preview:N" is also a file path, to the background as it looked after diffusion step N.
 #[derive(Debug, bevy::ecs::event::Event)]
 pub struct ResponseFromAI(pub String, pub String);
 
@@ -80,7 +80,7 @@ pub async fn ask_server_for_pci_devices_async(server_url: &str, pcie_devices: &m
 
     let client = oliana_server_lib::OlianaClient::new(tarpc::client::Config::default(), transport.await?).spawn();
 
-    let mut hardware_names = client.fetch_pci_hw_device_names(tarpc::context::current()).await?;
+    let mut hardware_names = client.fetch_pci_hw_device_names(tarpc::context::current()).await??;
 
     pcie_devices.append(&mut hardware_names);
 
@@ -102,7 +102,8 @@ pub fn read_ai_response_events(
     mut event_reader: EventReader<gui_structs::ResponseFromAI>,
     mut llm_reply_text_q: Query<&mut Text, With<gui_structs::LLM_ReplyText>>,
     mut scrollable_text_q: Query<&mut ScrollableContent>,
-    mut bg_sprite_q: Query<&mut Sprite, With<gui_structs::Background_Image>>,
+    mut bg_sprite_q: Query<&mut Sprite, (With<gui_structs::Background_Image>, Without<gui_structs::Foreground_Character>)>,
+    mut fg_sprite_q: Query<&mut Sprite, (With<gui_structs::Foreground_Character>, Without<gui_structs::Background_Image>)>,
     asset_server: Res<AssetServer>,
 ) {
     for ev in event_reader.read() {
@@ -148,6 +149,25 @@ pub fn read_ai_response_events(
                 for mut bg_sprite in &mut bg_sprite_q {
                     bg_sprite.image = Handle::<Image>::default();
                     bg_sprite.image = img_handle.clone();
+                    bg_sprite.color = Color::WHITE;
+                }
+            }
+            preview_type if preview_type.starts_with("image-preview:") => {
+                // Fade in as diffusion goes on, so the unfinished image reads as "still coming" rather than as a broken picture
+                let step = preview_type["image-preview:".len()..].parse::<u32>().unwrap_or(0);
+                let alpha = (step as f32 / IMAGE_INFERENCE_STEPS as f32).clamp(0.1, 1.0);
+                let png_file_path = ev.1.to_string();
+                let img_handle: Handle<Image> = asset_server.load(png_file_path);
+                for mut bg_sprite in &mut bg_sprite_q {
+                    bg_sprite.image = img_handle.clone();
+                    bg_sprite.color = Color::srgba(1.0, 1.0, 1.0, alpha);
+                }
+            }
+            "character" => {
+                let png_file_path = ev.1.to_string();
+                let img_handle: Handle<Image> = asset_server.load(png_file_path);
+                for mut fg_sprite in &mut fg_sprite_q {
+                    fg_sprite.image = img_handle.clone();
                 }
             }
             unk => {
@@ -192,16 +212,41 @@ pub fn read_ai_prompt_events(
 
                             let client = oliana_server_lib::OlianaClient::new(tarpc::client::Config::default(), transport).spawn();
 
-                            let mut generate_text_has_begun = false;
-                            let mut generate_image_must_be_loaded = false;
+                            // The player is watching for this reply, so it goes ahead of batch jobs other clients have queued
+                            match client.set_job_priority(tarpc::context::current(), oliana_server_lib::JobPriority::Interactive).await {
+                                Ok(Ok(())) => { }
+                                Ok(Err(oliana_err)) => {
+                                    eprintln!("[ set_job_priority ] {:?}", &oliana_err);
+                                }
+                                Err(e) => {
+                                    eprintln!("{}:{} {:?}", file!(), line!(), e);
+                                }
+                            }
 
-                            match client.generate_text_begin(tarpc::context::current(),
-                                "You are an ancient storytelling diety named Olly who answers in parables and short stories.".into(),
-                                ev_txt.clone().into()
-                            ).await {
-                                Ok(response) => {
-                                    eprintln!("[ generate_text_begin ] response = {}", &response);
-                                    generate_text_has_begun = true;
+                            // A new prompt replaces whatever the previous one was still generating
+                            cancel_in_flight_jobs(&client).await;
+
+                            let mut text_job: Option<oliana_server_lib::JobId> = None;
+                            let mut image_jobs: Vec<(oliana_server_lib::JobId, &'static str)> = vec![]; // Each with the ResponseFromAI type its .png is sent as
+
+                            // The server keeps Olly's system prompt and everything said so far in our session, and adds this turn once the reply is done
+                            let session_say_result = match get_or_create_session(&client).await {
+                                Ok(session) => client.session_say(tarpc::context::current(), session, ev_txt.clone()).await,
+                                Err(e) => Ok(Err(e)),
+                            };
+                            match session_say_result {
+                                Ok(Ok(job)) => {
+                                    eprintln!("[ session_say ] job = {}", &job);
+                                    text_job = Some(job);
+                                    push_in_flight_job(job);
+                                },
+                                Ok(Err(oliana_err)) => {
+                                    eprintln!("[ session_say ] {:?}", &oliana_err);
+                                    if let Ok(mut globals_wl) = GLOBALS.write() {
+                                        globals_wl.response_from_ai_events.push(
+                                            gui_structs::ResponseFromAI("text".into(), format!("[ {} ]", oliana_err) )
+                                        );
+                                    }
                                 },
                                 Err(e) => {
                                     let msg = format!("{}:{} {:?}", file!(), line!(), e);
@@ -216,21 +261,63 @@ pub fn read_ai_prompt_events(
                                 }
                             }
 
-                            match client.generate_image_begin(tarpc::context::current(),
-                                ev_txt.clone().into(),
-                                "".to_string(), 3.5, 12
-                            ).await {
-                                Ok(response) => {
-                                    eprintln!("[ generate_image_begin ] response = {}", &response);
-                                    generate_image_must_be_loaded = true;
-                                },
-                                Err(e) => {
-                                    let msg = format!("{}:{} {:?}", file!(), line!(), e);
-                                    eprintln!("{}", &msg);
+                            let mut image_prompts = vec![(ev_txt.clone(), false, "square", "image")];
+                            let mut background_png: Vec<u8> = vec![];
+                            if let Ok(globals_rl) = GLOBALS.read() {
+                                if !globals_rl.has_character_image {
+                                    image_prompts.push((CHARACTER_PROMPT.to_string(), true, "portrait", "character"));
+                                }
+                                background_png = globals_rl.background_png.clone();
+                            }
+                            for (image_prompt, transparent_background, size, event_type) in image_prompts {
+                                let (width, height) = oliana_lib::jobs::parse_image_size(size).unwrap_or((1024, 1024));
+                                let image_options = oliana_server_lib::ImageOptions {
+                                    width: width,
+                                    height: height,
+                                    ..oliana_server_lib::ImageOptions::default()
+                                };
+                                let begin_result = if event_type == "image" && background_png.len() > 0 {
+                                    let init = oliana_server_lib::InitImage {
+                                        png: background_png.clone(),
+                                        strength: oliana_lib::jobs::DEFAULT_IMAGE_STRENGTH,
+                                        mask_png: None,
+                                    };
+                                    client.edit_image_begin(tarpc::context::current(),
+                                        image_prompt,
+                                        "".to_string(), 3.5, IMAGE_INFERENCE_STEPS, transparent_background, image_options, init
+                                    ).await
+                                }
+                                else {
+                                    client.generate_image_begin(tarpc::context::current(),
+                                        image_prompt,
+                                        "".to_string(), 3.5, IMAGE_INFERENCE_STEPS, transparent_background, image_options
+                                    ).await
+                                };
+                                match begin_result {
+                                    Ok(Ok(job)) => {
+                                        eprintln!("[ generate_image_begin ] {} job = {}", event_type, &job);
+                                        push_in_flight_job(job);
+                                        if event_type == "image" {
+                                            // Previews and then the final background come from one task, so a late preview can never replace the final image
+                                            tokio::spawn(stream_image_previews_then_result(client.clone(), job));
+                                        }
+                                        else {
+                                            image_jobs.push((job, event_type));
+                                        }
+                                    },
+                                    Ok(Err(oliana_err)) => {
+                                        eprintln!("[ generate_image_begin ] {:?}", &oliana_err);
+                                    },
+                                    Err(e) => {
+                                        let msg = format!("{}:{} {:?}", file!(), line!(), e);
+                                        eprintln!("{}", &msg);
+                                    }
                                 }
                             }
 
-                            if generate_text_has_begun {
+                            let began_jobs: Vec<oliana_server_lib::JobId> = text_job.iter().copied().chain(image_jobs.iter().map(|(job, _)| *job)).collect();
+
+                            if let Some(text_job) = text_job {
                                 // Poll continuously, sending state up to the GUI text.
                                 // TODO the LAST event in this does not return None as expected, so we do not exit smoothly and we block the UI thread!
                                 let mut remaining_allowed_errs: isize = 12;
@@ -238,15 +325,25 @@ pub fn read_ai_prompt_events(
                                     if remaining_allowed_errs < 1 {
                                         break;
                                     }
-                                    match client.generate_text_next_token(tarpc::context::current()).await {
-                                        Ok(Some(next_token)) => {
+                                    match client.generate_text_next_token(tarpc::context::current(), text_job).await {
+                                        Ok(Ok(Some(next_token))) => {
                                           if let Ok(mut globals_wl) = GLOBALS.write() {
                                             globals_wl.response_from_ai_events.push(
                                               gui_structs::ResponseFromAI("text".into(), next_token.to_string() )
                                             );
                                           }
                                         }
-                                        Ok(None) => {
+                                        Ok(Ok(None)) => {
+                                          remaining_allowed_errs -= 10;
+                                        }
+                                        Ok(Err(oliana_err)) => {
+                                          // The server knows why generation stopped; show that to the player instead of silently ending the reply
+                                          eprintln!("[ generate_text_next_token ] {:?}", &oliana_err);
+                                          if let Ok(mut globals_wl) = GLOBALS.write() {
+                                            globals_wl.response_from_ai_events.push(
+                                              gui_structs::ResponseFromAI("text".into(), format!("\n[ {} ]\n", oliana_err) )
+                                            );
+                                          }
                                           remaining_allowed_errs -= 10;
                                         }
                                         Err(server_err) => {
@@ -254,25 +351,37 @@ pub fn read_ai_prompt_events(
                                         }
                                     }
 
-                                    if generate_image_must_be_loaded {
-                                        match client.generate_image_result_exists(tarpc::context::current()).await {
-                                            Ok(result_exists_bool) => {
+                                    let mut still_pending_image_jobs = Vec::with_capacity(image_jobs.len());
+                                    for (pending_image_job, event_type) in image_jobs.drain(..) {
+                                        match client.generate_image_result_exists(tarpc::context::current(), pending_image_job).await {
+                                            Ok(Ok(result_exists_bool)) => {
                                                 if result_exists_bool {
-                                                    read_image_from_server_and_push_event_to_globals(&client).await;
-                                                    generate_image_must_be_loaded = false;
+                                                    read_image_from_server_and_push_event_to_globals(&client, pending_image_job, event_type).await;
+                                                }
+                                                else {
+                                                    still_pending_image_jobs.push((pending_image_job, event_type));
                                                 }
                                             }
+                                            Ok(Err(oliana_err)) => {
+                                                eprintln!("[ generate_image_result_exists ] {:?}", &oliana_err);
+                                            }
                                             Err(server_err) => {
                                                 remaining_allowed_errs -= 1;
+                                                still_pending_image_jobs.push((pending_image_job, event_type));
                                             }
                                         }
                                     }
+                                    image_jobs = still_pending_image_jobs;
 
                                 }
                             }
 
-                            if generate_image_must_be_loaded {
-                                read_image_from_server_and_push_event_to_globals(&client).await;
+                            for (pending_image_job, event_type) in image_jobs {
+                                read_image_from_server_and_push_event_to_globals(&client, pending_image_job, event_type).await;
+                            }
+
+                            if let Ok(mut globals_wl) = GLOBALS.write() {
+                                globals_wl.in_flight_jobs.retain(|j| !began_jobs.contains(j));
                             }
 
                         }
@@ -298,17 +407,159 @@ pub fn read_ai_prompt_events(
     }
 }
 
-async fn read_image_from_server_and_push_event_to_globals(client: &oliana_server_lib::OlianaClient) {
-    match client.generate_image_get_result(tarpc::context::current()).await {
-        Ok(png_vec_u8) => {
-            eprintln!("Read {} bytes of PNG image from AI server!", png_vec_u8.len());
-            let tmp_png_file_path = oliana_lib::files::get_cache_file("tmp.png").expect("Fatal Filesystem error // todo remove me");
+// Our session if we have one, else a new one with the default (Olly) settings which later prompts then continue.
+async fn get_or_create_session(client: &oliana_server_lib::OlianaClient) -> Result<oliana_server_lib::SessionId, oliana_server_lib::OlianaError> {
+    let mut session: Option<oliana_server_lib::SessionId> = None;
+    if let Ok(globals_rl) = GLOBALS.read() {
+        session = globals_rl.session;
+    }
+    if let Some(session) = session {
+        return Ok(session);
+    }
+    let session = match client.session_create(tarpc::context::current(), oliana_server_lib::SessionSettings::default()).await {
+        Ok(session_result) => session_result?,
+        Err(e) => return Err(oliana_server_lib::OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
+    };
+    eprintln!("Began {}; run with OLIANA_SESSION={} to continue this story later or on another device", &session, &session);
+    if let Ok(mut globals_wl) = GLOBALS.write() {
+        globals_wl.session = Some(session);
+    }
+    Ok(session)
+}
+
+fn push_in_flight_job(job: oliana_server_lib::JobId) {
+    if let Ok(mut globals_wl) = GLOBALS.write() {
+        globals_wl.in_flight_jobs.push(job);
+    }
+}
+
+async fn cancel_in_flight_jobs(client: &oliana_server_lib::OlianaClient) {
+    let mut jobs = vec![];
+    if let Ok(mut globals_wl) = GLOBALS.write() {
+        jobs.append(&mut globals_wl.in_flight_jobs);
+    }
+    for job in jobs {
+        match client.cancel(tarpc::context::current(), job).await {
+            Ok(Ok(())) => {
+                eprintln!("[ cancel ] cancelled {}", &job);
+            }
+            Ok(Err(oliana_err)) => {
+                eprintln!("[ cancel ] {} {:?}", &job, &oliana_err);
+            }
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+            }
+        }
+    }
+}
+
+// Escape stops whatever the AI is currently generating
+pub fn cancel_listener(keys: Res<ButtonInput<KeyCode>>) {
+    if !keys.just_pressed(KeyCode::Escape) {
+        return;
+    }
+    let mut server_url = String::new();
+    let mut maybe_tokio_rt: Option<tokio::runtime::Handle> = None;
+    if let Ok(globals_rl) = GLOBALS.try_read() {
+        if globals_rl.in_flight_jobs.len() < 1 {
+            return;
+        }
+        server_url.push_str(&globals_rl.server_url);
+        maybe_tokio_rt = globals_rl.tokio_rt.clone();
+    }
+    if let Some(tokio_rt) = maybe_tokio_rt {
+        tokio_rt.spawn(async move {
+            let mut transport = tarpc::serde_transport::tcp::connect(server_url, tarpc::tokio_serde::formats::Bincode::default);
+            transport.config_mut().max_frame_length(usize::MAX);
+            match transport.await {
+                Ok(transport) => {
+                    let client = oliana_server_lib::OlianaClient::new(tarpc::client::Config::default(), transport).spawn();
+                    cancel_in_flight_jobs(&client).await;
+                }
+                Err(e) => {
+                    eprintln!("{}:{} {:?}", file!(), line!(), e);
+                }
+            }
+        });
+    }
+}
+
+// Sends job's previews to the background as they arrive and then the final image; runs beside the text polling loop so neither holds up the other.
+async fn stream_image_previews_then_result(client: oliana_server_lib::OlianaClient, job: oliana_server_lib::JobId) {
+    loop {
+        match client.generate_image_next_preview(tarpc::context::current(), job).await {
+            Ok(Ok(Some(preview))) => {
+                if preview.png.len() < 1 {
+                    continue; // Nothing new yet
+                }
+                // Named after the job and step, because the asset server will not re-load a path it has already loaded
+                let preview_png_file_path = match oliana_lib::files::get_cache_file(&format!("tmp-{}.step-{}.png", job, preview.step)) {
+                    Ok(preview_png_file_path) => preview_png_file_path,
+                    Err(e) => {
+                        eprintln!("{}:{} {:?}", file!(), line!(), e);
+                        break;
+                    }
+                };
+                if let Err(e) = tokio::fs::write(&preview_png_file_path, &preview.png[..]).await {
+                    eprintln!("{}:{} {:?}", file!(), line!(), e);
+                    continue;
+                }
+                if let Ok(mut globals_wl) = GLOBALS.write() {
+                    globals_wl.response_from_ai_events.push(
+                        gui_structs::ResponseFromAI(format!("image-preview:{}", preview.step), preview_png_file_path.to_string_lossy().to_string())
+                    );
+                }
+            }
+            Ok(Ok(None)) => {
+                break;
+            }
+            Ok(Err(oliana_err)) => {
+                // Also reported, to the player, when the final image is read below
+                eprintln!("[ generate_image_next_preview ] {:?}", &oliana_err);
+                break;
+            }
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+                break;
+            }
+        }
+    }
+    read_image_from_server_and_push_event_to_globals(&client, job, "image").await;
+    if let Ok(mut globals_wl) = GLOBALS.write() {
+        globals_wl.in_flight_jobs.retain(|j| *j != job);
+    }
+}
+
+// event_type is "image" for the background or "character" for the sprite in front of it
+async fn read_image_from_server_and_push_event_to_globals(client: &oliana_server_lib::OlianaClient, job: oliana_server_lib::JobId, event_type: &str) {
+    match client.generate_image_get_result(tarpc::context::current(), job).await {
+        Ok(Ok(images)) => {
+            let (seed, png_vec_u8) = match images.into_iter().next() {
+                Some(image) => (image.seed, image.png),
+                None => return,
+            };
+            eprintln!("Read {} bytes of PNG image (seed {}) from AI server!", png_vec_u8.len(), seed);
+            let tmp_png_file_path = oliana_lib::files::get_cache_file(&format!("tmp-{}.png", event_type)).expect("Fatal Filesystem error // todo remove me");
             if let Err(e) = tokio::fs::write(&tmp_png_file_path, &png_vec_u8[..]).await {
                 eprintln!("{}:{} {:?}", file!(), line!(), e);
             }
+            if let Ok(mut globals_wl) = GLOBALS.write() {
+              if event_type == "character" {
+                globals_wl.has_character_image = true;
+              }
+              else {
+                globals_wl.background_png = png_vec_u8.clone();
+              }
+              globals_wl.response_from_ai_events.push(
+                gui_structs::ResponseFromAI(event_type.into(), tmp_png_file_path.to_string_lossy().to_string())
+              );
+            }
+        }
+        Ok(Err(oliana_err)) => {
+            eprintln!("[ generate_image_get_result ] {:?}", &oliana_err);
             if let Ok(mut globals_wl) = GLOBALS.write() {
               globals_wl.response_from_ai_events.push(
-                gui_structs::ResponseFromAI("image".into(), tmp_png_file_path.to_string_lossy().to_string())
+                gui_structs::ResponseFromAI("text".into(), format!("\n[ No {}: {} ]\n", event_type, oliana_err))
               );
             }
         }
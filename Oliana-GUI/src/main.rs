@@ -20,6 +20,12 @@ use bevy_simple_scroll_view::*;
 
 const CLEAR_TOKEN: &'static str = "!!!CLEAR!!!";
 
+// Diffusion steps for every image we ask for; previews fade the background in over this many steps
+const IMAGE_INFERENCE_STEPS: u32 = 12;
+
+// Drawn once per run with a transparent background and shown in front of the scene as the one speaking
+const CHARACTER_PROMPT: &'static str = "Olly, an ancient storytelling deity in flowing robes, full body portrait facing the viewer";
+
 use clap::Parser;
 
 mod structs;
@@ -63,83 +69,49 @@ fn main() -> Result<(), Box<dyn std::error::Error>>  {
   // Ok(())
 }
 
-pub fn poll_until_exit_or_elapsed(sys: &mut sysinfo::System, pid: usize, ms_to_poll_for: isize) {
-    let mut remaining_ms: isize = ms_to_poll_for;
-    const POLL_MS: isize = 50;
-    let pid = sysinfo::Pid::from(pid);
-    while remaining_ms > 1 {
-        remaining_ms -= POLL_MS;
-        sys.refresh_processes(sysinfo::ProcessesToUpdate::Some(&[pid]), true);
-        if let Some(_process) = sys.process(sysinfo::Pid::from(pid)) {
-            // Process is still running!
-        }
-        else {
-            break;
-        }
-        std::thread::sleep(std::time::Duration::from_millis(POLL_MS as u64));
-    }
-}
-
 pub fn cleanup_child_procs() {
   if let Ok(mut globals_wl) = GLOBALS.write() {
 
-    let mut sys = sysinfo::System::new_all();
-    sys.refresh_all();
+    // On SIGTERM oliana_server stops its own backends (see TrackedProcs::shutdown()), giving each of them up to shutdown_grace_ms to exit.
+    let shutdown_grace_ms = oliana_lib::launchers::RestartPolicy::from_env().shutdown_grace_ms;
 
     if let Some(ref mut server_proc) = &mut globals_wl.server_proc {
-        if let Some(process) = sys.process(sysinfo::Pid::from(server_proc.id() as usize)) {
+        let mut sys = sysinfo::System::new();
+        let server_pid = sysinfo::Pid::from(server_proc.id() as usize);
+        sys.refresh_processes(sysinfo::ProcessesToUpdate::Some(&[server_pid]), true);
+        if let Some(process) = sys.process(server_pid) {
             process.kill_with(sysinfo::Signal::Term);
         }
-        poll_until_exit_or_elapsed(&mut sys, server_proc.id() as usize, 1800);
-        if let Some(_process) = sys.process(sysinfo::Pid::from(server_proc.id() as usize)) {
-            eprintln!("[ Note ] oliana_server did not exit in 1800ms, killing...");
-            if let Err(e) = server_proc.kill() {
-                eprintln!("{}:{} {:?}", file!(), line!(), e);
+        let ms_to_wait_for = shutdown_grace_ms + 1000;
+        let deadline = std::time::Instant::now() + std::time::Duration::from_millis(ms_to_wait_for);
+        let mut server_exited = false;
+        while !server_exited && std::time::Instant::now() < deadline {
+            match server_proc.try_wait() {
+                Ok(Some(_exit_status)) => server_exited = true,
+                Ok(None) => std::thread::sleep(std::time::Duration::from_millis(50)),
+                Err(e) => {
+                    eprintln!("{}:{} {:?}", file!(), line!(), e);
+                    break;
+                }
             }
         }
-        else {
+        if server_exited {
             eprintln!("[ Note ] oliana_server cleanly exited with SigTerm");
         }
-    }
-    // We walk globals_wl.track_proc_dir for "*-pid.txt" files, read the first integer, and kill those processes as well.
-    let mut potential_child_pids: Vec<usize> = vec![];
-    match std::fs::read_dir(&globals_wl.track_proc_dir) {
-        Ok(child_dirents) => {
-            for dirent in child_dirents {
-                if let Ok(dirent) = dirent {
-                    let path = dirent.path();
-                    let path_s = path.to_string_lossy();
-                    if path_s.ends_with("-pid.txt") || path_s.ends_with("-pid.TXT") {
-                        if let Ok(path_content_s) = std::fs::read_to_string(&path) {
-                            let path_content_s_trimmed = path_content_s.trim();
-                            if let Ok(child_pid_num) = path_content_s_trimmed.parse::<usize>() {
-                                potential_child_pids.push(child_pid_num);
-                            }
-                        }
-                    }
-                }
+        else {
+            eprintln!("[ Note ] oliana_server did not exit in {}ms, killing...", ms_to_wait_for);
+            if let Err(e) = server_proc.kill() {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+            }
+            if let Err(e) = server_proc.wait() {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
             }
-        }
-        Err(e) => {
-            eprintln!("{}:{} {:?}", file!(), line!(), e);
-        }
-    }
-
-    for child_pid_num in &potential_child_pids {
-        if let Some(process) = sys.process(sysinfo::Pid::from(*child_pid_num)) {
-            eprintln!("[ Note ] Sending SigTerm to {:?} ({})", process.exe(), child_pid_num );
-            process.kill_with(sysinfo::Signal::Term);
         }
     }
 
-    std::thread::sleep(std::time::Duration::from_millis(1800));
-
-    for child_pid_num in &potential_child_pids {
-        if let Some(process) = sys.process(sysinfo::Pid::from(*child_pid_num)) {
-            eprintln!("[ Note ] Sending SigKill to {:?} ({})", process.exe(), child_pid_num );
-            process.kill_with(sysinfo::Signal::Kill);
-        }
-    }
+    // A server which exited cleanly removed its backends' *-pid.txt files; one which was killed (above, or by anything else) left them behind,
+    // and outside Linux its backends keep running, so stop whatever they still point at.
+    oliana_lib::launchers::stop_stale_procs(&globals_wl.track_proc_dir, std::time::Duration::from_millis(shutdown_grace_ms));
 
   }
 }
@@ -209,6 +181,7 @@ pub async fn main_async(cli_args: &structs::Args) -> Result<(), Box<dyn std::err
     .add_systems(Startup, (gui_setup::gui_setup, gui_oneshot_tasks::determine_if_we_have_local_gpu) )
     .add_systems(Update, gui_painters::focus.before(TextInputSystem))
     .add_systems(Update, gui_updaters::text_listener.after(TextInputSystem))
+    .add_systems(Update, gui_updaters::cancel_listener)
     .add_systems(Update, gui_updaters::read_ai_response_events)
     .add_systems(Update, gui_updaters::read_ai_prompt_events)
     .add_systems(Update, gui_painters::reset_scroll) // TODO move this down/make it accessible someplace
@@ -41,6 +41,18 @@ pub struct Globals {
     pub server_pcie_devices: std::collections::HashMap<String, Vec<String>>,
 
     pub response_from_ai_events: Vec<crate::gui_structs::ResponseFromAI>,
+
+    // Jobs we began and have not finished reading; a new prompt or the Escape key cancels these.
+    pub in_flight_jobs: Vec<oliana_server_lib::JobId>,
+
+    // The server-side session holding our conversation with Olly; created by the first prompt unless OLIANA_SESSION=session-N names one to continue.
+    pub session: Option<oliana_server_lib::SessionId>,
+
+    // Set once Olly's portrait (see CHARACTER_PROMPT) has arrived; until then every prompt also asks for it.
+    pub has_character_image: bool,
+
+    // The latest background, which the next prompt's background is repainted from (img2img) so the scene changes gradually; empty until the first one arrives.
+    pub background_png: Vec<u8>,
 }
 
 impl Globals {
@@ -53,6 +65,10 @@ impl Globals {
             server_url: std::env::var("OLIANA_SERVER").unwrap_or_else(|_|"127.0.0.1:9050".into()), // Users may set OLIANA_SERVER=<host>:<port> to default to a different server
             server_pcie_devices: std::collections::HashMap::new(),
             response_from_ai_events: Vec::with_capacity(16),
+            in_flight_jobs: Vec::with_capacity(4),
+            session: std::env::var("OLIANA_SESSION").ok().and_then(|v| v.parse().ok()),
+            has_character_image: false,
+            background_png: vec![],
         }
     }
 
@@ -0,0 +1,373 @@
+
+// Answers ImageJobs without python or a GPU by drawing deterministic procedural art: a gradient under value noise, both seeded from
+// the prompt and the job's seed, with the combined seed written along the bottom edge so images from the same prompt and seed are
+// recognisably the same.
+// Jobs with transparent_background get a "character" instead: the same art inside an outlined oval on a white backdrop, which
+// oliana_lib::segment then removes.
+// Img2Img jobs blend that art over their init image in proportion to the job's strength, and Inpaint jobs do the same only where
+// their mask is white, so a scene edited several times in a row visibly drifts while keeping its layout.
+// Used by tests, on machines without a GPU, and by oliana_server as a fallback while oliana_images is crash-looping.
+
+pub const BACKEND_BIN_NAME: &str = "oliana_images_procedural";
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+
+  let args: Vec<String> = std::env::args().collect();
+  let mut env_var_work_dir = std::env::var("WORK_DIR").unwrap_or("".to_string());
+
+  if let Some(work_dir_i) = args.iter().position(|n| n == "--work-dir" || n == "--workdir") {
+    if work_dir_i < args.len()-1 {
+      env_var_work_dir = args[work_dir_i+1].clone();
+    }
+  }
+
+  if env_var_work_dir.len() < 1 {
+    eprintln!("Error, must have either WORK_DIR as an environment variable OR pass --work-dir as an argument, exiting!");
+    return Ok(());
+  }
+
+  println!("");
+  println!("Using {env_var_work_dir} as a work directory.");
+  println!("write files named 'NAME.json' containing objects like:");
+  println!(r#" {{"prompt": "A cow jumps over the moon while fireworks explode in the air", "negative_prompt": "", "guidance_scale": 3.5, "num_inference_steps": 10 }}"#);
+  println!(r#"followed by 'NAME.state' containing {{"state": "queued"}}, and wait for 'NAME.state' to become done (read 'NAME.png') or failed (read 'NAME.error.json')."#);
+  println!("Images are procedural placeholders; the same prompt and seed always give the same image, and more num_inference_steps give finer noise.");
+  println!(r#"Adding "preview_interval": N also writes 'NAME.step-N.png', 'NAME.step-2N.png' and so on before 'NAME.png'."#);
+  println!(r#"Adding "transparent_background": true draws a character instead, and writes it as an RGBA PNG whose background has alpha 0."#);
+  println!("Only queued jobs are processed, so finished jobs are never re-run after a restart; see oliana_lib::jobs for the details.");
+  println!("");
+
+  std::fs::create_dir_all(&env_var_work_dir).map_err(oliana_lib::eloc!())?;
+
+  // Subscribing before the first scan means a job queued while we scan still wakes the wait below
+  let workdir_watcher = oliana_lib::watch::WorkdirWatcher::new(&[&env_var_work_dir]).map_err(oliana_lib::eloc!())?;
+  let mut workdir_changes = workdir_watcher.subscribe();
+  println!("Watching {env_var_work_dir} for new jobs ({:?})", workdir_watcher.backend);
+
+  // Nothing to load, so we take jobs straight away; see oliana_lib::heartbeat
+  let heartbeat = oliana_lib::heartbeat::Heartbeat::from_env();
+  heartbeat.ready("idle");
+
+  let mut allowed_errors_remaining = 100;
+  while allowed_errors_remaining > 0 {
+    match oliana_lib::jobs::find_queued_jobs(&env_var_work_dir, BACKEND_BIN_NAME) {
+      Ok(queued_jobs) => {
+        for job_files in queued_jobs {
+          heartbeat.beat(&format!("working on {}", job_files.name));
+          if let Err(e) = process_image_job(&job_files) {
+            allowed_errors_remaining -= 1;
+            eprintln!("{}:{} {:?}", file!(), line!(), e);
+          }
+        }
+      }
+      Err(e) => {
+        allowed_errors_remaining -= 1;
+        eprintln!("{}:{} {:?}", file!(), line!(), e);
+      }
+    }
+    heartbeat.beat("idle");
+    // Sleep until the work directory changes; the timeout is only a safety net for a watch which missed an event
+    workdir_changes.wait_blocking(std::time::Duration::from_secs(5));
+  }
+
+  Ok(())
+}
+
+// Moves one queued job through Running to Done, Failed or Cancelled.
+fn process_image_job(job_files: &oliana_lib::jobs::JobFiles) -> Result<(), Box<dyn std::error::Error>> {
+  println!("Processing {}", job_files.input_path().display());
+  // Writes the job's final state when we return, so error? returns below leave it Failed rather than Running
+  let mut final_state_writer = oliana_lib::jobs::WriteStatusOnDropped::new(job_files.clone(), BACKEND_BIN_NAME);
+  job_files.write_state(oliana_lib::jobs::JobState::Running)?;
+
+  let image_job: oliana_lib::jobs::ImageJob = match job_files.read_input() {
+    Ok(image_job) => image_job,
+    Err(e) => {
+      final_state_writer.set_outcome(Err(oliana_lib::jobs::JobError::new(oliana_lib::jobs::JobErrorKind::InvalidInput, format!("Cannot read {}: {:?}", job_files.input_path().display(), e))));
+      return Err(e.into());
+    }
+  };
+  println!("Read image_job = {:?}", &image_job);
+
+  if job_files.is_cancel_requested() {
+    println!("Not processing {} because it was cancelled", job_files.input_path().display());
+    final_state_writer.set_outcome(Ok(oliana_lib::jobs::JobState::Cancelled));
+    return Ok(());
+  }
+
+  let init_images = match load_init_images(&image_job, job_files) {
+    Ok(init_images) => init_images,
+    Err(e) => {
+      final_state_writer.set_outcome(Err(oliana_lib::jobs::JobError::new(oliana_lib::jobs::JobErrorKind::InvalidInput, format!("Cannot read the init image of {}: {:?}", job_files.input_path().display(), e))));
+      return Err(e);
+    }
+  };
+
+  // Previews are the image with fewer octaves of noise; like oliana_images' previews, they come before any background removal
+  if image_job.preview_interval > 0 {
+    for step in (image_job.preview_interval..image_job.num_inference_steps).step_by(image_job.preview_interval as usize) {
+      if job_files.is_cancel_requested() {
+        println!("Cancelling {} at step {} because {} exists", job_files.input_path().display(), step, job_files.cancel_request_path().display());
+        final_state_writer.set_outcome(Ok(oliana_lib::jobs::JobState::Cancelled));
+        return Ok(());
+      }
+      // The final image has up to 6 octaves (see render_image()); a preview part of the way there has that part of them
+      let preview_job = oliana_lib::jobs::ImageJob {
+        num_inference_steps: (image_job.num_inference_steps.clamp(1, 6) * step / image_job.num_inference_steps).max(1),
+        ..image_job.clone()
+      };
+      let preview = render_image(&preview_job, 0, init_images.as_ref());
+      if let Err(e) = save_png_atomically(&preview.into(), &[], &job_files.preview_path(step)) {
+        eprintln!("{}:{} {:?}", file!(), line!(), e); // A missing preview only costs the player a fade-in
+      }
+    }
+  }
+
+  for image_i in 0..image_job.options.num_images {
+    let image = render_image(&image_job, image_i, init_images.as_ref());
+    let image: image::DynamicImage = if image_job.transparent_background {
+      let mut rgba_image = image::DynamicImage::ImageRgb8(image).to_rgba8();
+      let num_transparent = oliana_lib::segment::flood_fill_background(&mut rgba_image, oliana_lib::segment::DEFAULT_TOLERANCE);
+      println!("Made {} of {} pixels transparent", num_transparent, rgba_image.width() * rgba_image.height());
+      rgba_image.into()
+    }
+    else {
+      image.into()
+    };
+
+    let out_png_file = job_files.nth_image_output_path(image_i);
+    let text = oliana_lib::png_text::generation_text(&image_job, image_i, "oliana_images_procedural");
+    if let Err(e) = save_png_atomically(&image, &text, &out_png_file) {
+      final_state_writer.set_outcome(Err(oliana_lib::jobs::JobError::new(oliana_lib::jobs::JobErrorKind::Io, format!("Cannot write {}: {:?}", out_png_file.display(), e))));
+      return Err(e);
+    }
+  }
+  final_state_writer.set_outcome(Ok(oliana_lib::jobs::JobState::Done));
+
+  Ok(())
+}
+
+// Saved with text (see oliana_lib::png_text) to a temporary file which we rename into place, so png_file is only ever seen complete
+fn save_png_atomically(image: &image::DynamicImage, text: &[(String, String)], png_file: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+  let tmp_png_file = oliana_lib::jobs::temp_path_for(png_file);
+  image.save_with_format(&tmp_png_file, image::ImageFormat::Png)?;
+  if text.len() > 0 {
+    oliana_lib::png_text::add_text(&tmp_png_file, text)?;
+  }
+  std::fs::rename(&tmp_png_file, png_file)?;
+  Ok(())
+}
+
+// What Img2Img and Inpaint jobs draw over, stretched to the job's size.
+pub struct InitImages {
+  pub init: image::RgbImage,
+  pub mask: Option<image::GrayImage>, // Only for Inpaint jobs
+}
+
+fn load_init_images(image_job: &oliana_lib::jobs::ImageJob, job_files: &oliana_lib::jobs::JobFiles) -> Result<Option<InitImages>, Box<dyn std::error::Error>> {
+  let (width, height) = (image_job.options.width, image_job.options.height);
+  let resize = |png_file: std::path::PathBuf| -> Result<image::DynamicImage, Box<dyn std::error::Error>> {
+    let decoded = image::ImageReader::open(&png_file)?.with_guessed_format()?.decode()?;
+    Ok(decoded.resize_exact(width, height, image::imageops::FilterType::Triangle))
+  };
+  match image_job.mode {
+    oliana_lib::jobs::ImageMode::Text => Ok(None),
+    oliana_lib::jobs::ImageMode::Img2Img => Ok(Some(InitImages {
+      init: resize(job_files.init_image_path())?.to_rgb8(),
+      mask: None,
+    })),
+    oliana_lib::jobs::ImageMode::Inpaint => Ok(Some(InitImages {
+      init: resize(job_files.init_image_path())?.to_rgb8(),
+      mask: Some(resize(job_files.mask_image_path())?.to_luma8()),
+    })),
+  }
+}
+
+// Seeds image image_i of a job from its prompts and the job's seed; jobs without a seed give the same images as seed 0.
+pub fn image_seed(image_job: &oliana_lib::jobs::ImageJob, image_i: u32) -> u64 {
+  prompt_seed(image_job) ^ image_job.options.seed.unwrap_or(0).wrapping_add(image_i as u64)
+}
+
+// FNV-1a over both prompts; stable across platforms and releases, unlike std's DefaultHasher.
+pub fn prompt_seed(image_job: &oliana_lib::jobs::ImageJob) -> u64 {
+  let mut hash: u64 = 0xcbf29ce484222325;
+  for byte in image_job.prompt.as_bytes().iter().chain([0u8].iter()).chain(image_job.negative_prompt.as_bytes().iter()) {
+    hash ^= *byte as u64;
+    hash = hash.wrapping_mul(0x100000001b3);
+  }
+  hash
+}
+
+// splitmix64; tiny, and good enough to pick colors and noise values.
+struct SeededRng {
+  state: u64,
+}
+
+impl SeededRng {
+  fn next_u64(&mut self) -> u64 {
+    self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = self.state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+  }
+  // Uniform in [0, 1)
+  fn next_f32(&mut self) -> f32 {
+    (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+  }
+  fn next_color(&mut self) -> [f32; 3] {
+    [self.next_f32() * 255.0, self.next_f32() * 255.0, self.next_f32() * 255.0]
+  }
+}
+
+// One octave of value noise: random values on a (cells+1) x (cells+1) lattice, smoothly interpolated in between.
+struct NoiseOctave {
+  cells: usize,
+  lattice: Vec<f32>,
+  amplitude: f32,
+}
+
+impl NoiseOctave {
+  fn new(rng: &mut SeededRng, cells: usize, amplitude: f32) -> Self {
+    let lattice = (0..(cells+1)*(cells+1)).map(|_| rng.next_f32()).collect();
+    Self { cells, lattice, amplitude }
+  }
+  // u and v are in [0, 1]
+  fn sample(&self, u: f32, v: f32) -> f32 {
+    let x = u * self.cells as f32;
+    let y = v * self.cells as f32;
+    let x0 = (x.floor() as usize).min(self.cells - 1);
+    let y0 = (y.floor() as usize).min(self.cells - 1);
+    let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+    let tx = smooth(x - x0 as f32);
+    let ty = smooth(y - y0 as f32);
+    let at = |xi: usize, yi: usize| self.lattice[yi * (self.cells+1) + xi];
+    let top = at(x0, y0) + (at(x0+1, y0) - at(x0, y0)) * tx;
+    let bottom = at(x0, y0+1) + (at(x0+1, y0+1) - at(x0, y0+1)) * tx;
+    (top + (bottom - top) * ty) * self.amplitude
+  }
+}
+
+pub fn render_image(image_job: &oliana_lib::jobs::ImageJob, image_i: u32, init_images: Option<&InitImages>) -> image::RgbImage {
+  let (width, height) = (image_job.options.width, image_job.options.height);
+  let seed = image_seed(image_job, image_i);
+  let mut rng = SeededRng { state: seed };
+
+  let color_a = rng.next_color();
+  let color_b = rng.next_color();
+  let angle = rng.next_f32() * std::f32::consts::TAU;
+  let (gradient_dx, gradient_dy) = (angle.cos(), angle.sin());
+
+  // Each inference step past the first adds a finer octave, up to 6
+  let num_octaves = image_job.num_inference_steps.clamp(1, 6) as usize;
+  let mut octaves = Vec::with_capacity(num_octaves);
+  let mut total_amplitude = 0.0;
+  for octave_i in 0..num_octaves {
+    let amplitude = 1.0 / (1 << octave_i) as f32;
+    octaves.push(NoiseOctave::new(&mut rng, 4 << octave_i, amplitude));
+    total_amplitude += amplitude;
+  }
+
+  let mut image = image::RgbImage::new(width, height);
+  for (x, y, pixel) in image.enumerate_pixels_mut() {
+    let u = x as f32 / (width.max(2) - 1) as f32;
+    let v = y as f32 / (height.max(2) - 1) as f32;
+    // Project onto the gradient direction, mapping the image's corners into [0, 1]
+    let t = (((u - 0.5) * gradient_dx + (v - 0.5) * gradient_dy) / std::f32::consts::SQRT_2 + 0.5).clamp(0.0, 1.0);
+    let noise = octaves.iter().map(|o| o.sample(u, v)).sum::<f32>() / total_amplitude;
+    let brightness = 0.55 + 0.45 * noise;
+    for c in 0..3 {
+      pixel.0[c] = ((color_a[c] + (color_b[c] - color_a[c]) * t) * brightness).clamp(0.0, 255.0) as u8;
+    }
+  }
+
+  // Before the backdrop and the seed, which should stay as crisp as in any other image
+  if let Some(init_images) = init_images {
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+      let mask = init_images.mask.as_ref().map(|mask| mask.get_pixel(x, y).0[0] as f32 / 255.0).unwrap_or(1.0);
+      let repaint = image_job.strength.clamp(0.0, 1.0) * mask;
+      let init_pixel = init_images.init.get_pixel(x, y);
+      for c in 0..3 {
+        pixel.0[c] = (init_pixel.0[c] as f32 + (pixel.0[c] as f32 - init_pixel.0[c] as f32) * repaint).round().clamp(0.0, 255.0) as u8;
+      }
+    }
+  }
+
+  if image_job.transparent_background {
+    draw_character_backdrop(&mut image);
+  }
+
+  // The seed along the bottom edge, with a drop shadow so it is readable on any background
+  let scale = (width / 128).max(1);
+  let text = format!("{:016x}", seed);
+  let text_x = scale * 4;
+  let text_y = height.saturating_sub(scale * 10);
+  draw_hex_text(&mut image, &text, text_x + scale / 2, text_y + scale / 2, scale, image::Rgb([0, 0, 0]));
+  draw_hex_text(&mut image, &text, text_x, text_y, scale, image::Rgb([255, 255, 255]));
+
+  image
+}
+
+// Keeps an upright oval of the image as the "character", outlined in black so the flood fill cannot leak into it, and paints everything else white.
+fn draw_character_backdrop(image: &mut image::RgbImage) {
+  let (width, height) = (image.width() as f32, image.height() as f32);
+  let (center_x, center_y) = (width / 2.0, height / 2.0);
+  let (radius_x, radius_y) = (width * 0.3, height * 0.42);
+  let outline_width = (width / 128.0).max(1.0);
+  for (x, y, pixel) in image.enumerate_pixels_mut() {
+    let (dx, dy) = ((x as f32 + 0.5 - center_x) / radius_x, (y as f32 + 0.5 - center_y) / radius_y);
+    let distance_to_edge = ((dx * dx + dy * dy).sqrt() - 1.0) * radius_x.min(radius_y);
+    if distance_to_edge > 0.0 {
+      *pixel = image::Rgb([255, 255, 255]);
+    }
+    else if distance_to_edge > -outline_width {
+      *pixel = image::Rgb([0, 0, 0]);
+    }
+  }
+}
+
+// 3x5 pixel glyphs for 0-9 and a-f; each row holds 3 bits, most significant bit on the left.
+const HEX_GLYPHS: [[u8; 5]; 16] = [
+  [0b111, 0b101, 0b101, 0b101, 0b111], // 0
+  [0b010, 0b110, 0b010, 0b010, 0b111], // 1
+  [0b111, 0b001, 0b111, 0b100, 0b111], // 2
+  [0b111, 0b001, 0b111, 0b001, 0b111], // 3
+  [0b101, 0b101, 0b111, 0b001, 0b001], // 4
+  [0b111, 0b100, 0b111, 0b001, 0b111], // 5
+  [0b111, 0b100, 0b111, 0b101, 0b111], // 6
+  [0b111, 0b001, 0b010, 0b010, 0b010], // 7
+  [0b111, 0b101, 0b111, 0b101, 0b111], // 8
+  [0b111, 0b101, 0b111, 0b001, 0b111], // 9
+  [0b010, 0b101, 0b111, 0b101, 0b101], // a
+  [0b110, 0b101, 0b110, 0b101, 0b110], // b
+  [0b011, 0b100, 0b100, 0b100, 0b011], // c
+  [0b110, 0b101, 0b101, 0b101, 0b110], // d
+  [0b111, 0b100, 0b110, 0b100, 0b111], // e
+  [0b111, 0b100, 0b110, 0b100, 0b100], // f
+];
+
+// Draws text (hex digits only; anything else is skipped) with its top-left corner at x,y, each glyph pixel becoming a scale x scale square.
+fn draw_hex_text(image: &mut image::RgbImage, text: &str, x: u32, y: u32, scale: u32, color: image::Rgb<u8>) {
+  for (char_i, c) in text.chars().enumerate() {
+    let glyph = match c.to_digit(16) {
+      Some(digit) => HEX_GLYPHS[digit as usize],
+      None => continue,
+    };
+    let glyph_x = x + char_i as u32 * 4 * scale;
+    for (row_i, row_bits) in glyph.iter().enumerate() {
+      for col_i in 0..3u32 {
+        if row_bits & (0b100 >> col_i) == 0 {
+          continue;
+        }
+        for py in 0..scale {
+          for px in 0..scale {
+            let (image_x, image_y) = (glyph_x + col_i * scale + px, y + row_i as u32 * scale + py);
+            if image_x < image.width() && image_y < image.height() {
+              image.put_pixel(image_x, image_y, color);
+            }
+          }
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,133 @@
+# Stand-ins for the diffusers pipelines oliana_images_pipeline.py drives, used by `oliana_images --self-test` (see src/self_test.rs).
+#
+# A FakePipeline takes the same keyword arguments as StableDiffusionXLPipeline and its img2img / inpainting siblings, runs the step
+# callback like they do, and returns flat-colored PIL images whose color comes from each generator's seed; img2img blends that color
+# over the init image by strength and inpainting paints it only where the mask is white, so a test can tell from the pixels which
+# pipeline ran. Only Pillow is needed: when torch is not installed a minimal fake torch module takes its place.
+#
+# Prompts may script a failure like the echo text backend does: [[fail]] raises from inside the pipeline, and [[fail-oom]] raises
+# the error torch raises when CUDA runs out of memory.
+
+import sys
+
+try:
+  import torch
+except ImportError:
+  import types
+  import contextlib
+
+  class FakeGenerator:
+    def __init__(self, device='cpu'):
+      self.device = device
+      self.seed = 0
+    def manual_seed(self, seed):
+      self.seed = seed
+      return self
+    def initial_seed(self):
+      return self.seed
+
+  torch = types.ModuleType('torch')
+  torch.Generator = FakeGenerator
+  torch.no_grad = contextlib.nullcontext
+  torch.float16 = 'float16'
+  torch.float32 = 'float32'
+  sys.modules['torch'] = torch
+
+# Every pipeline call of this run as (pipeline name, keyword arguments), oldest first
+CALLS = []
+
+class FakeOutOfMemoryError(RuntimeError):
+  pass
+
+class FakeOutput:
+  def __init__(self, images):
+    self.images = images
+
+class FakeLatents:
+  def __init__(self, step):
+    self.step = step
+  def to(self, dtype):
+    return self
+  def __truediv__(self, scaling_factor):
+    return self
+
+class FakeVaeConfig:
+  force_upcast = False
+  scaling_factor = 1.0
+
+class FakeVae:
+  def __init__(self):
+    self.dtype = torch.float32
+    self.config = FakeVaeConfig()
+  def to(self, dtype=None):
+    return self
+  def decode(self, latents, return_dict=True):
+    return (latents, )
+
+class FakeImageProcessor:
+  # A gray square which gets lighter with every step, standing in for the half-denoised picture
+  def postprocess(self, latents, output_type='pil'):
+    from PIL import Image
+    return [Image.new('RGB', (64, 64), (min(255, 16 * latents.step), ) * 3)]
+
+def seed_color(seed):
+  return ((seed * 67) % 256, (seed * 131) % 256, (seed * 197) % 256)
+
+class FakePipeline:
+  def __init__(self, name):
+    self.name = name
+    self.device = 'cpu'
+    self.vae = FakeVae()
+    self.image_processor = FakeImageProcessor()
+    self._interrupt = False
+    self.cancel_at_step = None # (step, path): create path once step is reached, as the server would to cancel the job
+
+  def __call__(self, **kwargs):
+    from PIL import Image
+    CALLS.append((self.name, kwargs))
+    self._interrupt = False
+    prompt = kwargs.get('prompt', '') or ''
+    if '[[fail]]' in prompt:
+      raise RuntimeError('FakePipeline was asked to fail by its prompt')
+    if '[[fail-oom]]' in prompt:
+      raise FakeOutOfMemoryError('CUDA out of memory. Tried to allocate 20.00 MiB (FakePipeline was asked to by its prompt)')
+
+    # Like diffusers, img2img and inpainting only run the last strength of the steps
+    num_steps = int(kwargs['num_inference_steps'])
+    if 'strength' in kwargs:
+      num_steps = max(1, int(num_steps * kwargs['strength']))
+    callback = kwargs.get('callback_on_step_end', None)
+    for step_i in range(num_steps):
+      if self.cancel_at_step is not None and step_i + 1 >= self.cancel_at_step[0]:
+        open(self.cancel_at_step[1], 'w').write(' ')
+      if callback is not None:
+        callback(self, step_i, 1000 - step_i, {'latents': FakeLatents(step_i + 1)})
+      if self._interrupt:
+        break
+
+    init_image = kwargs.get('image', None)
+    mask_image = kwargs.get('mask_image', None)
+    if init_image is not None:
+      size = init_image.size
+    else:
+      size = (int(kwargs['width']), int(kwargs['height']))
+    images = []
+    for generator in kwargs['generator'][:int(kwargs.get('num_images_per_prompt', 1))]:
+      image = Image.new('RGB', size, seed_color(generator.initial_seed()))
+      if mask_image is not None:
+        image = Image.composite(image, init_image, mask_image.convert('L'))
+      elif init_image is not None:
+        image = Image.blend(init_image.convert('RGB'), image, float(kwargs['strength']))
+      images.append(image)
+    return FakeOutput(images)
+
+# Returns the text-to-image FakePipeline to pass to generate_image(), after putting fake img2img and inpainting pipelines where
+# pipeline_module.edit_pipeline() will find them.
+def install_fakes(pipeline_module):
+  pipeline_module.edit_pipes['img2img'] = FakePipeline('img2img')
+  pipeline_module.edit_pipes['inpaint'] = FakePipeline('inpaint')
+  return FakePipeline('text')
+
+# The name of the pipeline which ran most recently and the keyword arguments it was given
+def last_call():
+  return CALLS[-1]
@@ -0,0 +1,196 @@
+# The diffusion side of oliana_images: loads the text-to-image pipeline and runs ImageJobs with it (see oliana_lib::jobs for the job format).
+#
+# oliana_images embeds this file with include_str!() and calls load_pipeline() once, then generate_image() for every job; everything
+# around a job (its state, renaming PNGs into place, background flood fill and PNG text) happens on the rust side. `oliana_images --self-test`
+# runs generate_image() against the fake pipeline in oliana_images_fakes.py, so this file can be changed without a GPU or model download.
+
+def load_pipeline(inference_type_str):
+  import traceback
+  import os
+  try:
+    if hasattr(os, 'add_dll_directory'):
+      for folder in os.environ.get('PATH', '').split(os.pathsep):
+        try:
+          if os.path.exists(folder) and os.path.isdir(folder):
+            os.add_dll_directory(folder)
+        except:
+          pass
+  except:
+    traceback.print_exc()
+
+  import torch
+  from diffusers import StableDiffusionXLPipeline, EulerDiscreteScheduler
+
+  try:
+    for i in range(torch.cuda.device_count()):
+      print('We can see the CUDA device named ', torch.cuda.get_device_properties(i).name)
+    if torch.cuda.device_count() < 1:
+      print('NO CUDA DEVICES DETECTED!')
+      raise Exception('NO CUDA DEVICES DETECTED!')
+  except:
+    if 'cuda' in inference_type_str:
+      raise
+    # Otherwise we simply continue & rely on Torch to allocate CPU space
+
+  try:
+    fraction = float(os.environ.get('PER_PROC_MEM_FRACT', '1'))
+    if 'cuda' in inference_type_str:
+      torch.cuda.set_per_process_memory_fraction(fraction)
+      print(f'torch.cuda.set_per_process_memory_fraction({fraction}) (set by PER_PROC_MEM_FRACT, from 0.0 to 1.0)')
+  except:
+    traceback.print_exc()
+
+  # You can replace the checkpoint id with several koala models as below:
+  # "etri-vilab/koala-lightning-700m"
+
+  pipe = StableDiffusionXLPipeline.from_pretrained("etri-vilab/koala-lightning-1b", torch_dtype=torch.float16) # Keep IMAGE_MODEL in the rust code in sync
+  if 'cuda' in inference_type_str:
+    pipe = pipe.to("cuda")
+
+  # Ensure sampler uses "trailing" timesteps and "sample" prediction type.
+  pipe.scheduler = EulerDiscreteScheduler.from_config(
+    pipe.scheduler.config, timestep_spacing="trailing"
+  )
+
+  return pipe
+
+# Returns image as RGBA with its background removed by a segmentation model, or image unchanged if the model is not used or cannot be,
+# in which case oliana_images falls back to the flood fill in oliana_lib::segment. The model is only used with OLIANA_SEGMENTATION=rmbg,
+# as loading it runs python code from its Hugging Face repository (trust_remote_code) at whatever revision is current.
+segmentation_pipe = None
+def remove_background(image):
+  global segmentation_pipe
+  import os
+  import traceback
+  if os.environ.get('OLIANA_SEGMENTATION', 'flood-fill') != 'rmbg' or segmentation_pipe is False:
+    return image
+  try:
+    if segmentation_pipe is None:
+      from transformers import pipeline
+      segmentation_pipe = pipeline('image-segmentation', model='briaai/RMBG-1.4', trust_remote_code=True)
+    mask = segmentation_pipe(image, return_mask=True)
+    subject = image.convert('RGBA')
+    subject.putalpha(mask.convert('L').resize(image.size))
+    return subject
+  except:
+    traceback.print_exc()
+    print('Segmentation model unavailable; falling back to a flood fill for the rest of this run')
+    segmentation_pipe = False
+    return image
+
+# Decodes the latents diffusion has reached so far into a picture and saves it to preview_png_file; previews are best-effort, so
+# failures are printed and otherwise ignored.
+def write_preview(pipe, latents, preview_png_file):
+  import os
+  import traceback
+  import torch
+  try:
+    with torch.no_grad():
+      # SDXL's VAE overflows in float16, so like the pipeline itself we decode in float32 and then put the VAE back
+      vae_dtype = pipe.vae.dtype
+      needs_upcasting = vae_dtype == torch.float16 and getattr(pipe.vae.config, 'force_upcast', False)
+      if needs_upcasting:
+        pipe.vae.to(dtype=torch.float32)
+      decoded = pipe.vae.decode(latents.to(pipe.vae.dtype) / pipe.vae.config.scaling_factor, return_dict=False)[0]
+      if needs_upcasting:
+        pipe.vae.to(dtype=vae_dtype)
+      preview = pipe.image_processor.postprocess(decoded, output_type='pil')[0]
+    # Renamed into place like every other job file, see oliana_lib::jobs::temp_path_for()
+    tmp_preview_png_file = os.path.join(os.path.dirname(preview_png_file), '.' + os.path.basename(preview_png_file) + '.tmp')
+    preview.save(tmp_preview_png_file, format='PNG')
+    os.replace(tmp_preview_png_file, preview_png_file)
+  except:
+    traceback.print_exc()
+
+# The img2img and inpainting pipelines for pipe's model, made on first use. from_pipe() shares pipe's weights, so they cost no extra VRAM.
+edit_pipes = {}
+def edit_pipeline(pipe, mode):
+  if mode not in edit_pipes:
+    from diffusers import AutoPipelineForImage2Image, AutoPipelineForInpainting
+    if mode == 'img2img':
+      edit_pipes[mode] = AutoPipelineForImage2Image.from_pipe(pipe)
+    elif mode == 'inpaint':
+      edit_pipes[mode] = AutoPipelineForInpainting.from_pipe(pipe)
+    else:
+      raise ValueError(f'Unknown image job mode {mode!r}')
+  return edit_pipes[mode]
+
+# Re-writes the heartbeat file oliana_server gave us (see oliana_lib::heartbeat) without changing the activity in it, so a long
+# diffusion is not mistaken for a hung process. Does nothing when we were not started by oliana_server.
+def touch_heartbeat():
+  import os
+  heartbeat_file = os.environ.get('OLIANA_HEARTBEAT_FILE', '')
+  if len(heartbeat_file) > 0 and os.path.exists(heartbeat_file):
+    os.utime(heartbeat_file)
+
+# Runs one ImageJob (see oliana_lib::jobs) and saves its images to out_png_files (one per num_images), and any previews it asks for to
+# out_preview_png_prefix + step + '.png'. Img2img and inpaint jobs start from in_init_png_file, and inpaint jobs only repaint the white
+# parts of in_mask_png_file. Returns False instead if in_cancel_file appeared while we were working.
+def generate_image(pipe, image_job_json, out_png_files, in_cancel_file, out_preview_png_prefix, in_init_png_file, in_mask_png_file):
+  import os
+  import json
+  import random
+  import torch
+  from PIL import Image
+
+  image_job = json.loads(image_job_json)
+  prompt = image_job.get('prompt', None)
+  negative_prompt = image_job.get('negative_prompt', None)
+  guidance_scale = image_job.get('guidance_scale', 3.5)
+  num_inference_steps = int(image_job.get('num_inference_steps', 10))
+  preview_interval = int(image_job.get('preview_interval', 0))
+  width = int(image_job.get('width', 1024))
+  height = int(image_job.get('height', 1024))
+  num_images = int(image_job.get('num_images', 1))
+  seed = image_job.get('seed', None)
+  if seed is None:
+    seed = random.randrange(2**48)
+    print(f'Using random seed {seed}')
+  # One generator per image, so image i is exactly what a one-image job with seed + i would make
+  generators = [torch.Generator(device=pipe.device).manual_seed(seed + image_i) for image_i in range(num_images)]
+  transparent_background = bool(image_job.get('transparent_background', False))
+  mode = image_job.get('mode', 'text')
+  strength = float(image_job.get('strength', 0.6))
+  if transparent_background:
+    # A plain backdrop is far easier to remove, whichever way we end up removing it
+    prompt = f'{prompt}, isolated on a plain white background'
+
+  # Called by diffusers after every step; setting pipe._interrupt makes the remaining steps no-ops.
+  def on_step_end(pipe, step_i, timestep, callback_kwargs):
+    touch_heartbeat()
+    if os.path.exists(in_cancel_file):
+      print(f'Cancelling at step {step_i} because {in_cancel_file} exists')
+      pipe._interrupt = True
+      return callback_kwargs
+    step = step_i + 1
+    if preview_interval > 0 and step % preview_interval == 0 and step < num_inference_steps:
+      write_preview(pipe, callback_kwargs['latents'], f'{out_preview_png_prefix}{step}.png')
+    return callback_kwargs
+
+  pipe_kwargs = dict(
+    prompt=prompt, negative_prompt=negative_prompt, guidance_scale=guidance_scale, num_inference_steps=num_inference_steps,
+    num_images_per_prompt=num_images, generator=generators,
+    callback_on_step_end=on_step_end
+  )
+  if mode == 'text':
+    images = pipe(width=width, height=height, **pipe_kwargs).images
+  else:
+    # The output takes the init image's size, so we stretch it to the one asked for; diffusion only runs the last strength of the steps
+    pipe_kwargs['image'] = Image.open(in_init_png_file).convert('RGB').resize((width, height))
+    pipe_kwargs['strength'] = strength
+    if mode == 'inpaint':
+      pipe_kwargs['mask_image'] = Image.open(in_mask_png_file).convert('L').resize((width, height))
+      pipe_kwargs['width'] = width
+      pipe_kwargs['height'] = height
+    images = edit_pipeline(pipe, mode)(**pipe_kwargs).images
+
+  if os.path.exists(in_cancel_file):
+    # The partially-denoised image is not what anyone asked for, so we do not save it
+    return False
+
+  for image, out_png_file in zip(images, out_png_files):
+    if transparent_background:
+      image = remove_background(image)
+    print(f'Saving {out_png_file}')
+    image.save(out_png_file, format='PNG') # out_png_file is a temporary name without a .png extension
+  return True
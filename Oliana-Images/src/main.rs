@@ -1,6 +1,9 @@
 
 #![allow(unused_variables)]
 
+mod python_deps;
+mod self_test;
+
 enum InferenceType {
   CudaOnly,
   Anything
@@ -16,6 +19,9 @@ impl InferenceType {
 
 const INFERENCE_TYPE: InferenceType = if cfg!(feature = "cuda") { InferenceType::CudaOnly } else { InferenceType::Anything };
 
+// The checkpoint python's load_pipeline() loads; recorded in every image we write (see oliana_lib::png_text)
+const IMAGE_MODEL: &str = "etri-vilab/koala-lightning-1b";
+
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let rt  = tokio::runtime::Builder::new_multi_thread()
@@ -49,25 +55,41 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
     }
   }
 
-  if env_var_work_dir.len() < 1 {
-    eprintln!("Error, must have either WORK_DIR as an environment variable OR pass --work-dir as an argument, exiting!");
-    return Ok(());
+  // Runs python/oliana_images_pipeline.py against fake pipelines and exits, see src/self_test.rs
+  let self_test = args.iter().any(|n| n == "--self-test");
+  if self_test && env_var_work_dir.len() < 1 {
+    env_var_work_dir = std::env::temp_dir().join(format!("oliana_images-self-test-{}", std::process::id())).to_string_lossy().to_string();
   }
 
-  println!("");
-  println!("Using {env_var_work_dir} as a work directory.");
-  println!("write files named 'NAME.json' containing objects like:");
-  println!(r#" {{"prompt": "A cow jumps over the moon while fireworks explode in the air", "negative_prompt": "worst quality, low quality, ugly, duplicate, morbid, mutilated, extra fingers, mutated hands, extra limbs, cloned face, disfigured, malformed limbs, missing arms, missing legs", "guidance_scale": 3.5, "num_inference_steps": 10 }}"#);
-  println!("and wait for either 'NAME.png' or 'NAME.txt' to be written back from this process.");
-  println!("'NAME.json' will remain post-generation, and if the file's mtime becomes newer it will be processed again with the new contents.");
-  println!("If 'NAME.json' has an mtime older than this process's start time, it will not be processed.");
-  println!("");
+  // Installs the pinned python requirements (see src/python_deps.rs) and exits, so no work directory is needed
+  let install_only = args.iter().any(|n| n == "--install-only");
+  let wheelhouse = python_deps::find_wheelhouse(&args);
 
-  tokio::fs::create_dir_all(&env_var_work_dir[..]).await.map_err(oliana_lib::eloc!())?;
+  if env_var_work_dir.len() < 1 && !install_only {
+    eprintln!("Error, must have either WORK_DIR as an environment variable OR pass --work-dir as an argument, exiting!");
+    return Ok(());
+  }
 
-  std::env::set_var(
-    "WORK_DIR", env_var_work_dir.clone()
-  );
+  if !install_only {
+    println!("");
+    println!("Using {env_var_work_dir} as a work directory.");
+    println!("write files named 'NAME.json' containing objects like:");
+    println!(r#" {{"prompt": "A cow jumps over the moon while fireworks explode in the air", "negative_prompt": "worst quality, low quality, ugly, duplicate, morbid, mutilated, extra fingers, mutated hands, extra limbs, cloned face, disfigured, malformed limbs, missing arms, missing legs", "guidance_scale": 3.5, "num_inference_steps": 10 }}"#);
+    println!(r#"followed by 'NAME.state' containing {{"state": "queued"}}, and wait for 'NAME.state' to become done (read 'NAME.png') or failed ('NAME.error.json' then holds the error kind, message and python traceback)."#);
+    println!(r#"Adding "transparent_background": true writes an RGBA PNG whose background has alpha 0; set OLIANA_SEGMENTATION=rmbg to use the briaai/RMBG-1.4 segmentation model (which runs code downloaded with it) instead of a flood fill."#);
+    println!(r#"Optional fields "width" and "height" (default 1024), "num_images" (default 1, written as 'NAME.png', 'NAME.1.png'...) and "seed" (image i uses seed + i) shape the output."#);
+    println!(r#"Adding "mode": "img2img" and "strength": 0.6 repaints 'NAME.init.png' towards the prompt instead; "mode": "inpaint" only repaints the white parts of 'NAME.mask.png'."#);
+    println!(r#"Adding "preview_interval": N also writes the image as it looks after every Nth step to 'NAME.step-N.png' while diffusing."#);
+    println!("Creating 'NAME.cancel' stops diffusion after the current step, in which case 'NAME.state' becomes cancelled and no 'NAME.png' is written.");
+    println!("Only queued jobs are processed, so finished jobs are never re-run after a restart; see oliana_lib::jobs for the details.");
+    println!("");
+
+    tokio::fs::create_dir_all(&env_var_work_dir[..]).await.map_err(oliana_lib::eloc!())?;
+
+    std::env::set_var(
+      "WORK_DIR", env_var_work_dir.clone()
+    );
+  }
 
   let site_packages_folder_name = format!("Oliana-Images-site_packages-{}", INFERENCE_TYPE.to_string() );
   let site_packages = oliana_lib::files::get_cache_file(&site_packages_folder_name).map_err(oliana_lib::eloc!())?;
@@ -117,12 +139,12 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
     "HF_HOME", hf_home.to_string()
   );
 
-  python_main(&site_packages, &env_var_work_dir).map_err(oliana_lib::eloc!())?;
+  python_main(&site_packages, &env_var_work_dir, self_test, install_only, wheelhouse.as_deref()).map_err(oliana_lib::eloc!())?;
 
   Ok(())
 }
 
-fn python_main(site_packages: &str, env_var_work_dir: &str) -> Result<(), Box<dyn std::error::Error>>  {
+fn python_main(site_packages: &str, env_var_work_dir: &str, self_test: bool, install_only: bool, wheelhouse: Option<&std::path::Path>) -> Result<(), Box<dyn std::error::Error>>  {
   Python::with_gil(|py| {
       let sys = py.import("sys")?;
       let version: String = sys.getattr("version").map_err(oliana_lib::eloc!())?.extract().map_err(oliana_lib::eloc!())?;
@@ -149,49 +171,55 @@ fn python_main(site_packages: &str, env_var_work_dir: &str) -> Result<(), Box<dy
       let pip = py.import("pip")?;
       let pip_main: Py<PyAny> = pip.getattr("main").map_err(oliana_lib::eloc!())?.into();
 
-      if let Err(e) = py.import("torch") {
-        eprintln!("{}:{} {:?}", file!(), line!(), e);
-        let arg_vals = match INFERENCE_TYPE {
-          InferenceType::CudaOnly =>
-            vec![
-              "install".to_string(), format!("--target={site_packages}"), "torch".to_string(), "torchvision".to_string(), "torchaudio".to_string(),
-              "--index-url".to_string(), "https://download.pytorch.org/whl/cu124".to_string(),
-            ],
-          InferenceType::Anything =>
-            vec![
-              "install".to_string(), format!("--target={site_packages}"), "torch".to_string(), "torchvision".to_string(), "torchaudio".to_string(),
-            ]
-        };
-        let args = (arg_vals, );
-        pip_main.call1(py, args).map_err(oliana_lib::eloc!())?;
+      // oliana_server counts us as starting until heartbeat.ready() below, see oliana_lib::heartbeat
+      let heartbeat = oliana_lib::heartbeat::Heartbeat::from_env();
+
+      if self_test {
+        // The fake pipelines only need Pillow, which diffusers would otherwise have brought in
+        if let Err(e) = py.import("PIL") {
+          eprintln!("{}:{} {:?}", file!(), line!(), e);
+          let mut arg_vals = vec![
+            "install".to_string(), format!("--target={site_packages}"), python_deps::pinned_requirement("pillow"),
+          ];
+          arg_vals.extend(python_deps::package_source_args(false, wheelhouse));
+          let args = (arg_vals, );
+          pip_main.call1(py, args).map_err(oliana_lib::eloc!())?;
+        }
+        let python_module = PyModule::from_code(
+            py,
+            c_str!(include_str!("../python/oliana_images_pipeline.py")),
+            c_str!("oliana_images_pipeline.py"),
+            c_str!("oliana_images_pipeline"),
+        )?;
+        return self_test::run_self_test(py, &python_module, std::path::Path::new(env_var_work_dir));
+      }
+
+      // Everything comes from python/requirements.txt in one install, so a new upstream release cannot change what we run on
+      let is_cuda = match INFERENCE_TYPE { InferenceType::CudaOnly => true, InferenceType::Anything => false };
+      let unmet_requirements = python_deps::unmet_requirements(py).map_err(oliana_lib::eloc!())?;
+      if unmet_requirements.len() > 0 {
+        println!("Installing python/requirements.txt into {site_packages}, as these requirements are unmet:");
+        for requirement in unmet_requirements.iter() {
+          println!("  {}", requirement);
+        }
+        let _installing_beats = heartbeat.keep_beating("installing python packages");
+        python_deps::install_requirements(py, &pip_main, site_packages, is_cuda, wheelhouse)?;
+      }
+      if install_only {
+        println!("All {} python requirements of python/requirements.txt are installed in {site_packages}", python_deps::pinned_requirements().len());
+        return Ok(());
       }
 
+      let loading_beats = heartbeat.keep_beating("loading model");
+
       let torch = py.import("torch").map_err(oliana_lib::eloc!())?;
       eprintln!("torch = {:?}", torch);
 
 
-      if let Err(e) = py.import("transformers") {
-        eprintln!("{}:{} {:?}", file!(), line!(), e);
-        let arg_vals = vec![
-          "install".to_string(), format!("--target={site_packages}"), "transformers".to_string(),
-        ];
-        let args = (arg_vals, );
-        pip_main.call1(py, args).map_err(oliana_lib::eloc!())?;
-      }
-
       let transformers = py.import("transformers").map_err(oliana_lib::eloc!())?;
       eprintln!("transformers = {:?}", transformers);
 
 
-      if let Err(e) = py.import("diffusers") {
-        eprintln!("{}:{} {:?}", file!(), line!(), e);
-        let arg_vals = vec![
-          "install".to_string(), format!("--target={site_packages}"), "diffusers".to_string(),
-        ];
-        let args = (arg_vals, );
-        pip_main.call1(py, args).map_err(oliana_lib::eloc!())?;
-      }
-
       let diffusers = py.import("diffusers").map_err(oliana_lib::eloc!())?;
       eprintln!("diffusers = {:?}", diffusers);
 
@@ -207,136 +235,133 @@ fn python_main(site_packages: &str, env_var_work_dir: &str) -> Result<(), Box<dy
       let accelerate = py.import("accelerate")?;
       eprintln!("accelerate = {:?}", accelerate);*/ // ^^ accelerate is more trouble than its worth
 
-      if let Err(e) = py.import("json5") {
-        eprintln!("{:?}", e);
-        let arg_vals = vec![
-          "install".to_string(), format!("--target={site_packages}"), "json5".to_string(),
-        ];
-        let args = (arg_vals, );
-        pip_main.call1(py, args).map_err(oliana_lib::eloc!())?;
-      }
-
-      let json5 = py.import("json5").map_err(oliana_lib::eloc!())?;
-      eprintln!("json5 = {:?}", json5);
-
       let python_module = PyModule::from_code(
           py,
-          c_str!(r#"
-def main(env_var_work_dir, inference_type_str):
-  import traceback
-  import os
-  import time
-  import json5
-  try:
-    if hasattr(os, 'add_dll_directory'):
-      for folder in os.environ.get('PATH', '').split(os.pathsep):
-        try:
-          if os.path.exists(folder) and os.path.isdir(folder):
-            os.add_dll_directory(folder)
-        except:
-          pass
-  except:
-    traceback.print_exc()
-
-  import torch
-  from diffusers import StableDiffusionXLPipeline, EulerDiscreteScheduler
-
-  try:
-    for i in range(torch.cuda.device_count()):
-      print('We can see the CUDA device named ', torch.cuda.get_device_properties(i).name)
-    if torch.cuda.device_count() < 0:
-      print('NO CUDA DEVICES DETECTED!')
-      raise Excepion('NO CUDA DEVICES DETECTED!')
-  except:
-    if 'cuda' in inference_type_str:
-      raise
-    # Otherwise we simply continue & rely on Torch to allocate CPU space
-
-  try:
-    fraction = float(os.environ.get('PER_PROC_MEM_FRACT', '1'))
-    if 'cuda' in inference_type_str:
-      torch.cuda.set_per_process_memory_fraction(fraction)
-      print(f'torch.cuda.set_per_process_memory_fraction({fraction}) (set by PER_PROC_MEM_FRACT, from 0.0 to 1.0)')
-  except:
-    traceback.print_exc()
-
-  # You can replace the checkpoint id with several koala models as below:
-  # "etri-vilab/koala-lightning-700m"
-
-  pipe = StableDiffusionXLPipeline.from_pretrained("etri-vilab/koala-lightning-1b", torch_dtype=torch.float16)
-  if 'cuda' in inference_type_str:
-    pipe = pipe.to("cuda")
-
-  # Ensure sampler uses "trailing" timesteps and "sample" prediction type.
-  pipe.scheduler = EulerDiscreteScheduler.from_config(
-    pipe.scheduler.config, timestep_spacing="trailing"
-  )
-
-  # Now we poll env_var_work_dir forever!
-  our_start_time = int(time.time())
-  last_seen_mtime = dict()
-  allowed_errors_remaining = 100
-  while allowed_errors_remaining > 0:
-    try:
-      for file_name in os.listdir(env_var_work_dir):
-        full_path = os.path.join(env_var_work_dir, file_name)
-        if os.path.isfile(full_path):
-          if full_path.casefold().endswith(".json".casefold()):
-            file_mtime = os.path.getmtime(full_path)
-            if file_mtime > our_start_time and last_seen_mtime.get(full_path, 0) < file_mtime:
-              # We either have NOT seen this file yet or it has been updated, process it!
-              file_name_no_extension, _unused_ext = os.path.splitext(file_name)
-              print(f'Processing {full_path}')
-              out_txt_file = os.path.join(env_var_work_dir, f'{file_name_no_extension}.txt')
-              out_png_file = os.path.join(env_var_work_dir, f'{file_name_no_extension}.png')
-              try:
-                last_seen_mtime[full_path] = file_mtime + 1
-
-                input_data = dict()
-                with open(full_path, 'r') as fd:
-                  input_data = json5.loads(fd.read())
-
-                print(f'Read input_data = {input_data}')
-
-                prompt = input_data.get('prompt', None)
-                negative_prompt = input_data.get('negative_prompt', None)
-                guidance_scale = input_data.get('guidance_scale', 3.5)
-                num_inference_steps = int(input_data.get('num_inference_steps', 10))
-
-                image = pipe(prompt=prompt, negative_prompt=negative_prompt, guidance_scale=guidance_scale, num_inference_steps=num_inference_steps).images[0]
-
-                print(f'Saving {out_png_file}')
-                image.save(out_png_file)
-
-              except:
-                allowed_errors_remaining -= 1
-                traceback.print_exc()
-                if 'KeyboardInterrupt' in exception_str: # We actually do want these to be fatal!
-                  allowed_errors_remaining -= 999
-                with open(out_txt_file, 'w') as fd:
-                  fd.write(traceback.format_exc())
-
-    except:
-      allowed_errors_remaining -= 1
-      traceback.print_exc()
-      exception_str = traceback.format_exc()
-
-      if 'KeyboardInterrupt' in exception_str: # We actually do want these to be fatal!
-        break
-
-    time.sleep(0.100) # Poll several times a second for new work
-
-"#),
-          c_str!("in_memory.py"),
-          c_str!("in_memory"),
+          c_str!(include_str!("../python/oliana_images_pipeline.py")),
+          c_str!("oliana_images_pipeline.py"),
+          c_str!("oliana_images_pipeline"),
       )?;
 
-      let python_entry_fn: Py<PyAny> = python_module.getattr("main").map_err(oliana_lib::eloc!())?.into();
-
-      python_entry_fn.call1(py, (env_var_work_dir, INFERENCE_TYPE.to_string(), ) ).map_err(oliana_lib::eloc!())?;
+      let load_pipeline_fn: Py<PyAny> = python_module.getattr("load_pipeline").map_err(oliana_lib::eloc!())?.into();
+      let generate_image_fn: Py<PyAny> = python_module.getattr("generate_image").map_err(oliana_lib::eloc!())?.into();
+
+      let pipe = load_pipeline_fn.call1(py, (INFERENCE_TYPE.to_string(), ) ).map_err(oliana_lib::eloc!())?;
+      drop(loading_beats);
+
+      // Now we watch env_var_work_dir forever! Subscribing before the first scan means a job queued while we scan still wakes the wait below
+      let workdir_watcher = oliana_lib::watch::WorkdirWatcher::new(&[env_var_work_dir]).map_err(oliana_lib::eloc!())?;
+      let mut workdir_changes = workdir_watcher.subscribe();
+      println!("Watching {} for new jobs ({:?})", env_var_work_dir, workdir_watcher.backend);
+      heartbeat.ready("idle");
+
+      let mut allowed_errors_remaining = 100;
+      while allowed_errors_remaining > 0 {
+        match oliana_lib::jobs::find_queued_jobs(env_var_work_dir, "oliana_images") {
+          Ok(queued_jobs) => {
+            for job_files in queued_jobs {
+              // python touches the heartbeat file after every diffusion step, see touch_heartbeat() in python/oliana_images_pipeline.py
+              heartbeat.beat(&format!("working on {}", job_files.name));
+              if let Err(e) = process_image_job(py, &pipe, &generate_image_fn, &job_files) {
+                allowed_errors_remaining -= 1;
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+                if let Some(py_err) = e.downcast_ref::<PyErr>() {
+                  if py_err.is_instance_of::<pyo3::exceptions::PyKeyboardInterrupt>(py) { // We actually do want these to be fatal!
+                    return Err(e);
+                  }
+                }
+              }
+            }
+          }
+          Err(e) => {
+            allowed_errors_remaining -= 1;
+            eprintln!("{}:{} {:?}", file!(), line!(), e);
+          }
+        }
+        heartbeat.beat("idle");
+        // Sleep until the work directory changes; the timeout is only a safety net for a watch which missed an event
+        py.allow_threads(|| workdir_changes.wait_blocking(std::time::Duration::from_secs(5)) );
+      }
 
       Ok(())
   })
 }
 
+// Moves one queued job through Running to Done, Failed or Cancelled; python exceptions are recorded in NAME.state and then returned.
+fn process_image_job(py: Python<'_>, pipe: &Py<PyAny>, generate_image_fn: &Py<PyAny>, job_files: &oliana_lib::jobs::JobFiles) -> Result<(), Box<dyn std::error::Error>> {
+  println!("Processing {}", job_files.input_path().display());
+  // Writes the job's final state when we return, so error? returns below leave it Failed rather than Running
+  let mut final_state_writer = oliana_lib::jobs::WriteStatusOnDropped::new(job_files.clone(), "oliana_images");
+  job_files.write_state(oliana_lib::jobs::JobState::Running)?;
+
+  let mut image_job: oliana_lib::jobs::ImageJob = match job_files.read_input() {
+    Ok(image_job) => image_job,
+    Err(e) => {
+      final_state_writer.set_outcome(Err(oliana_lib::jobs::JobError::new(oliana_lib::jobs::JobErrorKind::InvalidInput, format!("Cannot read {}: {:?}", job_files.input_path().display(), e))));
+      return Err(e.into());
+    }
+  };
+  if image_job.options.seed.is_none() {
+    // Picked here rather than in python so png_text::generation_text() can record it
+    image_job.options.seed = Some(oliana_lib::jobs::random_image_seed());
+  }
+  println!("Read image_job = {:?}", &image_job);
+
+  if job_files.is_cancel_requested() {
+    println!("Not processing {} because it was cancelled", job_files.input_path().display());
+    final_state_writer.set_outcome(Ok(oliana_lib::jobs::JobState::Cancelled));
+    return Ok(());
+  }
+
+  // Python saves to temporary files which we rename into place, so NAME.png is only ever seen complete
+  let out_png_files: Vec<std::path::PathBuf> = (0..image_job.options.num_images).map(|image_i| job_files.nth_image_output_path(image_i)).collect();
+  let tmp_png_files: Vec<std::path::PathBuf> = out_png_files.iter().map(|out_png_file| oliana_lib::jobs::temp_path_for(out_png_file)).collect();
+  let args = (
+    pipe.clone_ref(py),
+    serde_json::to_string(&image_job)?,
+    tmp_png_files.iter().map(|tmp_png_file| tmp_png_file.to_string_lossy().to_string()).collect::<Vec<String>>(),
+    job_files.cancel_request_path().to_string_lossy().to_string(),
+    job_files.workdir.join(format!("{}.step-", job_files.name)).to_string_lossy().to_string(), // See JobFiles::preview_path()
+    job_files.init_image_path().to_string_lossy().to_string(),
+    job_files.mask_image_path().to_string_lossy().to_string(),
+  );
+  match generate_image_fn.call1(py, args) {
+    Ok(finished) => {
+      if finished.extract::<bool>(py)? {
+        for (image_i, (tmp_png_file, out_png_file)) in tmp_png_files.iter().zip(out_png_files.iter()).enumerate() {
+          if image_job.transparent_background {
+            // A no-op when python's segmentation model already removed the background
+            match oliana_lib::segment::make_background_transparent(tmp_png_file, oliana_lib::segment::DEFAULT_TOLERANCE) {
+              Ok(num_transparent) => println!("Flood fill made {} pixels transparent", num_transparent),
+              Err(e) => {
+                final_state_writer.set_outcome(Err(oliana_lib::jobs::JobError::new(oliana_lib::jobs::JobErrorKind::Exception, format!("Cannot remove the background of {}: {:?}", tmp_png_file.display(), e))));
+                return Err(e);
+              }
+            }
+          }
+          let text = oliana_lib::png_text::generation_text(&image_job, image_i as u32, IMAGE_MODEL);
+          if let Err(e) = oliana_lib::png_text::add_text(tmp_png_file, &text) {
+            final_state_writer.set_outcome(Err(oliana_lib::jobs::JobError::new(oliana_lib::jobs::JobErrorKind::Io, format!("Cannot write the generation text of {}: {:?}", tmp_png_file.display(), e))));
+            return Err(e);
+          }
+          std::fs::rename(tmp_png_file, out_png_file)?;
+        }
+        final_state_writer.set_outcome(Ok(oliana_lib::jobs::JobState::Done));
+      }
+      else {
+        final_state_writer.set_outcome(Ok(oliana_lib::jobs::JobState::Cancelled));
+      }
+      Ok(())
+    }
+    Err(e) => {
+      e.clone_ref(py).print(py);
+      let traceback = match e.traceback(py) {
+        Some(tb) => tb.format().unwrap_or_default(),
+        None => String::new(),
+      };
+      // Python tracebacks end with the exception itself, eg "torch.OutOfMemoryError: CUDA out of memory...", which becomes the message
+      final_state_writer.set_outcome(Err(oliana_lib::jobs::JobError::from_traceback(oliana_lib::jobs::JobErrorKind::Exception, &format!("{}{}", traceback, e))));
+      Err(e.into())
+    }
+  }
+}
 
@@ -0,0 +1,142 @@
+// oliana_images's python packages: python/requirements.txt pins every one of them, and they are installed into our own site_packages
+// folder (see main_async) either from PyPI or, on machines without network, from a wheelhouse folder of wheels downloaded elsewhere.
+//
+// Installed versions are compared against the pins through importlib.metadata on every start, so an install made by an older
+// oliana_images (or a system package of another version) is replaced rather than trusted.
+
+use pyo3::prelude::*;
+
+pub const REQUIREMENTS_TXT: &str = include_str!("../python/requirements.txt");
+
+// Where the CUDA builds of torch, torchvision and torchaudio live; PyPI still serves everything else
+const CUDA_INDEX_URL: &str = "https://download.pytorch.org/whl/cu124";
+
+// Every (name, version, environment marker) REQUIREMENTS_TXT pins, in file order; the marker is empty for packages every platform needs.
+pub fn pinned_requirements() -> Vec<(String, String, String)> {
+  let mut pins = vec![];
+  for line in REQUIREMENTS_TXT.lines() {
+    let line = line.split('#').next().unwrap_or("").trim().trim_end_matches('\\');
+    // Marked lines look like 'triton==3.1.0 ; platform_system == "Linux"', hashed ones like "torch==2.5.1 --hash=sha256:..."
+    let (requirement, marker) = line.split_once(';').unwrap_or((line, ""));
+    if let Some((name, version)) = requirement.split_once("==") {
+      let version = version.split_whitespace().next().unwrap_or("");
+      let marker = marker.split(" --hash").next().unwrap_or("").trim();
+      pins.push((name.trim().to_string(), version.to_string(), marker.to_string()));
+    }
+  }
+  pins
+}
+
+// Whether pip hashes each download against REQUIREMENTS_TXT (see install_requirements())
+pub fn requirements_are_hashed() -> bool {
+  REQUIREMENTS_TXT.lines().any(|line| line.split('#').next().unwrap_or("").contains("--hash="))
+}
+
+// eg "pillow==11.0.0", for installing a single package at its pinned version
+pub fn pinned_requirement(name: &str) -> String {
+  match pinned_requirements().into_iter().find(|(pinned_name, _, _)| pinned_name == name) {
+    Some((name, version, _marker)) => format!("{}=={}", name, version),
+    None => name.to_string(),
+  }
+}
+
+// Where to install wheels from without network: `--wheelhouse DIR`, else OLIANA_WHEELHOUSE, else an oliana_images-wheelhouse folder next to
+// the executable (so an offline install can be shipped as one folder).
+pub fn find_wheelhouse(args: &[String]) -> Option<std::path::PathBuf> {
+  if let Some(wheelhouse_i) = args.iter().position(|n| n == "--wheelhouse") {
+    if wheelhouse_i < args.len()-1 {
+      return Some(std::path::PathBuf::from(&args[wheelhouse_i+1]));
+    }
+  }
+  if let Ok(wheelhouse) = std::env::var("OLIANA_WHEELHOUSE") {
+    if wheelhouse.len() > 0 {
+      return Some(std::path::PathBuf::from(wheelhouse));
+    }
+  }
+  let exe_wheelhouse = std::env::current_exe().ok()?.parent()?.join("oliana_images-wheelhouse");
+  if exe_wheelhouse.is_dir() {
+    return Some(exe_wheelhouse);
+  }
+  None
+}
+
+// The pip arguments which pick where packages come from
+pub fn package_source_args(cuda: bool, wheelhouse: Option<&std::path::Path>) -> Vec<String> {
+  match wheelhouse {
+    Some(wheelhouse) => vec!["--no-index".to_string(), "--find-links".to_string(), wheelhouse.to_string_lossy().to_string()],
+    None if cuda => vec!["--extra-index-url".to_string(), CUDA_INDEX_URL.to_string()],
+    None => vec![],
+  }
+}
+
+// Each pinned requirement whose installed version differs from its pin, eg "torch==2.5.1 (2.4.0 is installed)". Local version labels
+// are ignored, so the CUDA index's torch 2.5.1+cu124 meets torch==2.5.1, and so are pins whose marker excludes this platform.
+pub fn unmet_requirements(py: Python<'_>) -> PyResult<Vec<String>> {
+  py.import("importlib")?.call_method0("invalidate_caches")?;
+  let importlib_metadata = py.import("importlib.metadata")?;
+  // pip evaluates the markers with its own copy of packaging, so we do too; the real packaging may not be installed yet
+  let marker_class = py.import("pip._vendor.packaging.markers")?.getattr("Marker")?;
+  let mut unmet = vec![];
+  for (name, version, marker) in pinned_requirements() {
+    if marker.len() > 0 && !marker_class.call1((&marker, ))?.call_method0("evaluate")?.extract::<bool>()? {
+      continue;
+    }
+    match importlib_metadata.call_method1("version", (&name, )) {
+      Ok(installed_version) => {
+        let installed_version: String = installed_version.extract()?;
+        if installed_version.split('+').next() != Some(&version[..]) {
+          unmet.push(format!("{}=={} ({} is installed)", name, version, installed_version));
+        }
+      }
+      Err(_) => unmet.push(format!("{}=={} (not installed)", name, version)),
+    }
+  }
+  Ok(unmet)
+}
+
+// Installs all of REQUIREMENTS_TXT into site_packages, replacing whatever versions are there. Fails with a report of every requirement
+// still unmet afterwards, and what to try next, if pip fails or leaves any of them unmet.
+pub fn install_requirements(py: Python<'_>, pip_main: &Py<PyAny>, site_packages: &str, cuda: bool, wheelhouse: Option<&std::path::Path>) -> Result<(), Box<dyn std::error::Error>> {
+  // pip reads requirements from a file, and hashes (if the lock has them) are only checked with -r
+  let requirements_file = oliana_lib::files::get_cache_file("Oliana-Images-requirements.txt")?;
+  std::fs::write(&requirements_file, REQUIREMENTS_TXT)?;
+
+  // The lock lists every package, so pip must not add whatever the newest release of an unlisted dependency is
+  let mut arg_vals = vec![
+    "install".to_string(), format!("--target={site_packages}"), "--upgrade".to_string(), "--no-deps".to_string(), "-r".to_string(), requirements_file.to_string_lossy().to_string(),
+  ];
+  if requirements_are_hashed() {
+    arg_vals.push("--require-hashes".to_string());
+  }
+  else {
+    eprintln!("python/requirements.txt carries no hashes yet, so pip cannot check what it downloads; see python/requirements.in");
+  }
+  arg_vals.extend(package_source_args(cuda, wheelhouse));
+  let args = (arg_vals, );
+  let pip_exit_code: i32 = pip_main.call1(py, args)?.extract(py)?;
+
+  let unmet = unmet_requirements(py)?;
+  if pip_exit_code == 0 && unmet.len() < 1 {
+    return Ok(());
+  }
+  let mut report = format!("Cannot install the python requirements of oliana_images into {} (pip exited with {}).", site_packages, pip_exit_code);
+  if unmet.len() > 0 {
+    report += "\nStill unmet:";
+    for requirement in unmet.iter() {
+      report += &format!("\n  {}", requirement);
+    }
+  }
+  let python_version: String = py.import("sys")?.getattr("version")?.extract()?;
+  match wheelhouse {
+    Some(wheelhouse) => report += &format!(
+      "\npip looked for these only in the wheelhouse {}, without network; it must hold a wheel of every package above for this OS and python {}. \
+       Oliana-Images/python/requirements.txt in the Oliana sources shows how to download them.",
+      wheelhouse.display(), python_version.split_whitespace().next().unwrap_or(&python_version)
+    ),
+    None => report += &format!(
+      "\npip looked for these on PyPI{}; check the network connection and pip's output above, or install offline with --wheelhouse DIR.",
+      if cuda { format!(" and {}", CUDA_INDEX_URL) } else { String::new() }
+    ),
+  }
+  Err(report.into())
+}
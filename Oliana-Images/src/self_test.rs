@@ -0,0 +1,221 @@
+// `oliana_images --self-test`: runs real ImageJobs through process_image_job() and python/oliana_images_pipeline.py with the fake
+// pipelines of python/oliana_images_fakes.py in place of diffusers, so changes to either side can be checked in seconds without a GPU,
+// torch or any model download (only Pillow is installed if it is missing).
+//
+// Every case submits its job with oliana_lib::jobs::JobFiles::submit() exactly like the server does, then checks the job's files.
+// Each case prints PASS or FAIL; the self-test returns Err (and oliana_images exits non-zero) if any of them failed.
+
+use pyo3::prelude::*;
+use pyo3::ffi::c_str;
+
+type CaseResult = Result<(), String>;
+
+pub fn run_self_test(py: Python<'_>, pipeline_module: &Bound<'_, PyModule>, workdir: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+  // Keeps remove_background() from downloading the segmentation model even if our environment opts in to it; the flood fill is all we check here
+  std::env::set_var("OLIANA_SEGMENTATION", "flood-fill");
+
+  let fakes_module = PyModule::from_code(
+      py,
+      c_str!(include_str!("../python/oliana_images_fakes.py")),
+      c_str!("oliana_images_fakes.py"),
+      c_str!("oliana_images_fakes"),
+  )?;
+  let pipe: Py<PyAny> = fakes_module.call_method1("install_fakes", (pipeline_module, ))?.into();
+  let generate_image_fn: Py<PyAny> = pipeline_module.getattr("generate_image")?.into();
+  let harness = Harness {
+    py: py,
+    pipe: pipe,
+    generate_image_fn: generate_image_fn,
+    fakes_module: fakes_module,
+    workdir: workdir.to_path_buf(),
+  };
+
+  let cases: [(&str, fn(&Harness<'_>) -> CaseResult); 7] = [
+    ("text-to-image writes every image, its previews and each image's seed", text_to_image),
+    ("img2img runs the img2img pipeline on NAME.init.png", img2img),
+    ("inpaint runs the inpainting pipeline with NAME.mask.png", inpaint),
+    ("a cancel file stops diffusion and no image is written", cancel),
+    ("a python exception fails the job with its traceback", python_exception),
+    ("running out of memory fails the job with a retryable OutOfMemory error", out_of_memory),
+    ("an image which cannot be moved into place fails the job instead of leaving it Running", unwritable_output),
+  ];
+  let mut num_failed = 0;
+  for (name, case) in cases.iter() {
+    match case(&harness) {
+      Ok(()) => println!("PASS {}", name),
+      Err(e) => {
+        println!("FAIL {}: {}", name, e);
+        num_failed += 1;
+      }
+    }
+  }
+  if num_failed > 0 {
+    return Err(format!("{} of {} self-test cases failed; their jobs are in {}", num_failed, cases.len(), workdir.display()).into());
+  }
+  println!("All {} self-test cases passed", cases.len());
+  Ok(())
+}
+
+struct Harness<'py> {
+  py: Python<'py>,
+  pipe: Py<PyAny>,
+  generate_image_fn: Py<PyAny>,
+  fakes_module: Bound<'py, PyModule>,
+  workdir: std::path::PathBuf,
+}
+
+impl<'py> Harness<'py> {
+  // Submits image_job as job name and processes it; whether it succeeded is left to the case to check from the job's files.
+  fn run_job(&self, name: &str, image_job: oliana_lib::jobs::ImageJob) -> Result<oliana_lib::jobs::JobFiles, String> {
+    let job_files = oliana_lib::jobs::JobFiles::new(&self.workdir, name);
+    job_files.submit(&oliana_lib::jobs::JobInput::Image(image_job), "oliana_images").map_err(|e| format!("{:?}", e))?;
+    if let Err(e) = crate::process_image_job(self.py, &self.pipe, &self.generate_image_fn, &job_files) {
+      println!("(job {} returned {})", name, e);
+    }
+    Ok(job_files)
+  }
+
+  // The name of the fake pipeline which ran last and one of the keyword arguments it was given, as a string
+  fn last_call_arg(&self, arg_name: &str) -> Result<(String, Option<String>), String> {
+    let (pipeline_name, kwargs): (String, Bound<'py, pyo3::types::PyDict>) = self.fakes_module.call_method0("last_call")
+      .and_then(|last_call| last_call.extract())
+      .map_err(|e| format!("{:?}", e))?;
+    let arg = kwargs.get_item(arg_name).map_err(|e| format!("{:?}", e))?.map(|arg| arg.to_string());
+    Ok((pipeline_name, arg))
+  }
+}
+
+fn expect_state(job_files: &oliana_lib::jobs::JobFiles, expected_state: oliana_lib::jobs::JobState) -> CaseResult {
+  let status = job_files.read_status().map_err(|e| format!("{:?}", e))?;
+  match status {
+    Some(ref status) if status.state == expected_state => Ok(()),
+    _ => Err(format!("expected {:?}, {} holds {:?}", expected_state, job_files.state_path().display(), status)),
+  }
+}
+
+fn expect_text(png_file: &std::path::Path, keyword: &str, expected_value: &str) -> CaseResult {
+  let text = oliana_lib::png_text::read_text(png_file).map_err(|e| format!("{}: {:?}", png_file.display(), e))?;
+  match text.iter().find(|(k, _)| k == keyword) {
+    Some((_, value)) if value == expected_value => Ok(()),
+    found => Err(format!("expected {} {:?} in {}, found {:?}", keyword, expected_value, png_file.display(), found)),
+  }
+}
+
+fn test_image_job(prompt: &str) -> oliana_lib::jobs::ImageJob {
+  oliana_lib::jobs::ImageJob {
+    prompt: prompt.to_string(),
+    num_inference_steps: 4,
+    options: oliana_lib::jobs::ImageOptions {
+      width: 256,
+      height: 256,
+      seed: Some(5),
+      num_images: 1,
+    },
+    ..oliana_lib::jobs::ImageJob::default()
+  }
+}
+
+fn text_to_image(harness: &Harness<'_>) -> CaseResult {
+  let mut image_job = test_image_job("A red barn");
+  image_job.preview_interval = 2;
+  image_job.options.num_images = 2;
+  let job_files = harness.run_job("text", image_job)?;
+  expect_state(&job_files, oliana_lib::jobs::JobState::Done)?;
+  expect_text(&job_files.nth_image_output_path(0), oliana_lib::png_text::SEED_KEYWORD, "5")?;
+  expect_text(&job_files.nth_image_output_path(1), oliana_lib::png_text::SEED_KEYWORD, "6")?;
+  expect_text(&job_files.nth_image_output_path(0), oliana_lib::png_text::MODEL_KEYWORD, crate::IMAGE_MODEL)?;
+  // Step 4 is the final image, so only step 2 is previewed
+  let preview_steps: Vec<u32> = job_files.list_previews().map_err(|e| format!("{:?}", e))?.iter().map(|(step, _)| *step).collect();
+  if preview_steps != vec![2] {
+    return Err(format!("expected a preview of step 2 only, found steps {:?}", preview_steps));
+  }
+  match harness.last_call_arg("width")? {
+    (pipeline_name, Some(width)) if pipeline_name == "text" && width == "256" => Ok(()),
+    last_call => Err(format!("expected the text pipeline with width 256, the last call was {:?}", last_call)),
+  }
+}
+
+fn img2img(harness: &Harness<'_>) -> CaseResult {
+  let mut image_job = test_image_job("The red barn in the snow");
+  image_job.mode = oliana_lib::jobs::ImageMode::Img2Img;
+  image_job.strength = 0.5;
+  image_job.init_png = std::fs::read(oliana_lib::jobs::JobFiles::new(&harness.workdir, "text").image_output_path()).map_err(|e| format!("{:?}", e))?;
+  let job_files = harness.run_job("img2img", image_job)?;
+  expect_state(&job_files, oliana_lib::jobs::JobState::Done)?;
+  expect_text(&job_files.image_output_path(), oliana_lib::png_text::MODE_KEYWORD, "img2img")?;
+  match harness.last_call_arg("strength")? {
+    (pipeline_name, Some(strength)) if pipeline_name == "img2img" && strength == "0.5" => Ok(()),
+    last_call => Err(format!("expected the img2img pipeline with strength 0.5, the last call was {:?}", last_call)),
+  }
+}
+
+fn inpaint(harness: &Harness<'_>) -> CaseResult {
+  let mut image_job = test_image_job("A scarecrow by the red barn");
+  image_job.mode = oliana_lib::jobs::ImageMode::Inpaint;
+  image_job.strength = 1.0;
+  image_job.init_png = std::fs::read(oliana_lib::jobs::JobFiles::new(&harness.workdir, "text").image_output_path()).map_err(|e| format!("{:?}", e))?;
+  image_job.mask_png = std::fs::read(oliana_lib::jobs::JobFiles::new(&harness.workdir, "text").nth_image_output_path(1)).map_err(|e| format!("{:?}", e))?;
+  let job_files = harness.run_job("inpaint", image_job)?;
+  expect_state(&job_files, oliana_lib::jobs::JobState::Done)?;
+  match harness.last_call_arg("mask_image")? {
+    (pipeline_name, Some(_)) if pipeline_name == "inpaint" => Ok(()),
+    last_call => Err(format!("expected the inpainting pipeline with a mask_image, the last call was {:?}", last_call)),
+  }
+}
+
+fn cancel(harness: &Harness<'_>) -> CaseResult {
+  let job_files = oliana_lib::jobs::JobFiles::new(&harness.workdir, "cancel");
+  let cancel_at_step = (2, job_files.cancel_request_path().to_string_lossy().to_string());
+  harness.pipe.setattr(harness.py, "cancel_at_step", cancel_at_step).map_err(|e| format!("{:?}", e))?;
+  let job_files = harness.run_job("cancel", test_image_job("A barn which is never finished"));
+  harness.pipe.setattr(harness.py, "cancel_at_step", harness.py.None()).map_err(|e| format!("{:?}", e))?;
+  let job_files = job_files?;
+  expect_state(&job_files, oliana_lib::jobs::JobState::Cancelled)?;
+  if job_files.image_output_path().exists() {
+    return Err(format!("{} was written by a cancelled job", job_files.image_output_path().display()));
+  }
+  Ok(())
+}
+
+fn python_exception(harness: &Harness<'_>) -> CaseResult {
+  let job_files = harness.run_job("exception", test_image_job("A barn [[fail]]"))?;
+  expect_state(&job_files, oliana_lib::jobs::JobState::Failed)?;
+  let job_error = job_files.read_error().map_err(|e| format!("{:?}", e))?;
+  let is_expected = job_error.kind == oliana_lib::jobs::JobErrorKind::Exception && !job_error.retryable
+                    && job_error.message.contains("FakePipeline was asked to fail") && job_error.traceback.contains("oliana_images_pipeline.py");
+  if !is_expected {
+    return Err(format!("expected the fake pipeline's exception and a traceback through oliana_images_pipeline.py, got {:?}", job_error));
+  }
+  Ok(())
+}
+
+fn out_of_memory(harness: &Harness<'_>) -> CaseResult {
+  let job_files = harness.run_job("out-of-memory", test_image_job("A barn [[fail-oom]]"))?;
+  expect_state(&job_files, oliana_lib::jobs::JobState::Failed)?;
+  let job_error = job_files.read_error().map_err(|e| format!("{:?}", e))?;
+  if job_error.kind != oliana_lib::jobs::JobErrorKind::OutOfMemory || !job_error.retryable {
+    return Err(format!("expected a retryable OutOfMemory error, got {:?}", job_error));
+  }
+  Ok(())
+}
+
+fn unwritable_output(harness: &Harness<'_>) -> CaseResult {
+  let job_files = oliana_lib::jobs::JobFiles::new(&harness.workdir, "unwritable-output");
+  job_files.submit(&oliana_lib::jobs::JobInput::Image(test_image_job("A red barn")), "oliana_images").map_err(|e| format!("{:?}", e))?;
+  // A non-empty directory where NAME.png goes makes renaming the finished image into place fail
+  let out_png_file = job_files.image_output_path();
+  std::fs::create_dir_all(out_png_file.join("in-the-way")).map_err(|e| format!("{:?}", e))?;
+  let result = crate::process_image_job(harness.py, &harness.pipe, &harness.generate_image_fn, &job_files);
+  if let Err(e) = std::fs::remove_dir_all(&out_png_file) {
+    println!("(cannot remove {}: {:?})", out_png_file.display(), e);
+  }
+  if result.is_ok() {
+    return Err("expected process_image_job() to return the rename error".to_string());
+  }
+  expect_state(&job_files, oliana_lib::jobs::JobState::Failed)?;
+  let job_error = job_files.read_error().map_err(|e| format!("{:?}", e))?;
+  if job_error.kind != oliana_lib::jobs::JobErrorKind::Io {
+    return Err(format!("expected an Io error, got {:?}", job_error));
+  }
+  Ok(())
+}
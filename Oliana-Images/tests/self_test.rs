@@ -0,0 +1,19 @@
+// Runs `oliana_images --self-test` (see src/self_test.rs) as part of `cargo test`, so the fake-pipeline cases guard
+// python/oliana_images_pipeline.py without anyone having to remember to run them. Needs a python with pip; Pillow is installed
+// into the site-packages cache if it is missing.
+
+#[test]
+fn self_test_passes() {
+  let work_dir = std::env::temp_dir().join(format!("oliana_images-cargo-test-{}", std::process::id()));
+  let output = std::process::Command::new(env!("CARGO_BIN_EXE_oliana_images"))
+    .arg("--self-test")
+    .arg("--work-dir").arg(&work_dir)
+    .env_remove("WORK_DIR")
+    .output()
+    .expect("could not run oliana_images");
+  let stdout = String::from_utf8_lossy(&output.stdout);
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  assert!(output.status.success(), "oliana_images --self-test exited with {}\nstdout:\n{}\nstderr:\n{}", output.status, stdout, stderr);
+  assert!(!stdout.contains("FAIL"), "a self-test case failed but oliana_images exited 0\nstdout:\n{}", stdout);
+  let _ = std::fs::remove_dir_all(&work_dir);
+}
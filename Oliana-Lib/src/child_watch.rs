@@ -0,0 +1,151 @@
+// Watches a process spawned by launchers::TrackedProcs for its exit, without scanning the process table or trusting a PID file.
+//
+// A WatchedChild owns the std::process::Child, and as only try_exit() reaps it, the PID cannot be handed to another process until then;
+// signalling by PID is safe for as long as the WatchedChild is kept, so drop it as soon as try_exit() returns the exit status.
+// On Linux a thread per child blocks in waitid() on a pidfd (pidfd_open(), Linux 5.3+, older kernels wait on the PID itself) with WNOWAIT,
+// which returns the moment the process exits without reaping it, and then wakes exit_notify so the owner calls try_exit() right away
+// instead of at its next poll. Elsewhere nothing wakes exit_notify and exits are only noticed by polling try_exit(), a non-blocking waitpid().
+//
+// On Linux each child is also started (see prepare_command()) as the leader of a process group of its own, so signals reach whatever it
+// started in turn (eg pip), and with PR_SET_PDEATHSIG so the kernel sends it SIGTERM if the server dies without stopping it.
+// The group's ID is the child's PID, which stays ours until the child is reaped, so try_exit() kills whatever is left of the group first.
+
+// Has to be applied before spawning a process given to WatchedChild::new().
+pub fn prepare_command(command: &mut std::process::Command) -> &mut std::process::Command {
+  #[cfg(target_os = "linux")]
+  {
+    use std::os::unix::process::CommandExt;
+    let server_pid = std::process::id();
+    command.process_group(0);
+    // Only async-signal-safe calls are allowed between fork() and exec(). PR_SET_PDEATHSIG fires when the thread which spawned the process
+    // exits rather than the whole server, which is fine for tokio's worker threads and the main thread, but not for a short-lived thread.
+    unsafe {
+      command.pre_exec(move || {
+        if nix::libc::prctl(nix::libc::PR_SET_PDEATHSIG, nix::libc::SIGTERM) != 0 {
+          return Err(std::io::Error::last_os_error());
+        }
+        // The server died before prctl() took effect, so nothing would ever stop this process
+        if nix::libc::getppid() as u32 != server_pid {
+          return Err(std::io::Error::from_raw_os_error(nix::libc::ESRCH));
+        }
+        Ok(())
+      });
+    }
+  }
+  command
+}
+
+pub struct WatchedChild {
+  pub pid: u32,
+  child: std::process::Child,
+}
+
+impl WatchedChild {
+  pub fn new(child: std::process::Child, exit_notify: std::sync::Arc<tokio::sync::Notify>) -> Self {
+    let pid = child.id();
+    #[cfg(target_os = "linux")]
+    {
+      // Opened before anyone can reap the process, so the pidfd cannot refer to another process given the same PID
+      let pidfd = pidfd_open(pid);
+      let spawn_result = std::thread::Builder::new().name(format!("exit of PID {}", pid)).spawn(move || {
+        use std::os::fd::AsRawFd;
+        use nix::sys::wait::{Id, WaitPidFlag};
+        let id = match pidfd {
+          Some(ref pidfd) => Id::PIDFd(pidfd.as_raw_fd()),
+          None => Id::Pid(nix::unistd::Pid::from_raw(pid as i32)),
+        };
+        loop {
+          match nix::sys::wait::waitid(id, WaitPidFlag::WEXITED | WaitPidFlag::WNOWAIT) {
+            Err(nix::errno::Errno::EINTR) => continue,
+            Ok(_) => break,
+            Err(nix::errno::Errno::ECHILD) => break, // try_exit() reaped it first
+            Err(e) => {
+              // Leaves noticing the exit to polling
+              eprintln!("{}:{} waitid on PID {}: {:?}", file!(), line!(), pid, e);
+              return;
+            }
+          }
+        }
+        exit_notify.notify_one();
+      });
+      if let Err(e) = spawn_result {
+        eprintln!("{}:{} {:?}", file!(), line!(), e);
+      }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = exit_notify;
+    Self {
+      pid: pid,
+      child: child,
+    }
+  }
+
+  // Some(status) once the process has exited, which reaps it.
+  pub fn try_exit(&mut self) -> std::io::Result<Option<std::process::ExitStatus>> {
+    #[cfg(target_os = "linux")]
+    {
+      use nix::sys::wait::{Id, WaitPidFlag, WaitStatus};
+      match nix::sys::wait::waitid(Id::Pid(nix::unistd::Pid::from_raw(self.pid as i32)), WaitPidFlag::WEXITED | WaitPidFlag::WNOHANG | WaitPidFlag::WNOWAIT) {
+        Ok(WaitStatus::StillAlive) => return Ok(None),
+        Ok(_) => self.kill_rest_of_group(),
+        Err(e) => eprintln!("{}:{} {:?}", file!(), line!(), e),
+      }
+    }
+    self.child.try_wait()
+  }
+
+  // Blocks until the process has exited and reaps it, eg after kill().
+  pub fn wait(&mut self) -> std::io::Result<std::process::ExitStatus> {
+    self.child.wait()
+  }
+
+  // SIGKILL to the process and its group on Linux; try_exit() reports the exit like any other.
+  pub fn kill(&mut self) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+      if self.signal(nix::sys::signal::Signal::SIGKILL).is_ok() {
+        return Ok(());
+      }
+    }
+    self.child.kill()
+  }
+
+  // SIGTERM to the process and its group on Linux, which lets it exit by itself; elsewhere the same as kill().
+  #[cfg(target_os = "linux")]
+  pub fn terminate(&mut self) -> std::io::Result<()> {
+    self.signal(nix::sys::signal::Signal::SIGTERM).map_err(std::io::Error::from)
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  pub fn terminate(&mut self) -> std::io::Result<()> {
+    self.child.kill()
+  }
+
+  // Signals the process's whole group, see prepare_command()
+  #[cfg(target_os = "linux")]
+  pub fn signal(&self, signal: nix::sys::signal::Signal) -> nix::Result<()> {
+    nix::sys::signal::killpg(nix::unistd::Pid::from_raw(self.pid as i32), signal)
+  }
+
+  // Only while the exited process is not reaped yet, so the group ID cannot belong to anyone else
+  #[cfg(target_os = "linux")]
+  fn kill_rest_of_group(&self) {
+    match self.signal(nix::sys::signal::Signal::SIGKILL) {
+      Ok(()) | Err(nix::errno::Errno::ESRCH) => { }
+      Err(e) => eprintln!("{}:{} {:?}", file!(), line!(), e),
+    }
+  }
+}
+
+// None if the kernel has no pidfd_open() (before Linux 5.3), or the process is gone already.
+#[cfg(target_os = "linux")]
+fn pidfd_open(pid: u32) -> Option<std::os::fd::OwnedFd> {
+  use std::os::fd::FromRawFd;
+  let fd = unsafe { nix::libc::syscall(nix::libc::SYS_pidfd_open, pid as nix::libc::pid_t, 0) };
+  if fd < 0 {
+    eprintln!("{}:{} pidfd_open({}) failed with {:?}, waiting on the PID instead", file!(), line!(), pid, nix::errno::Errno::last());
+    return None;
+  }
+  // The syscall returned a new file descriptor which nothing else owns
+  Some(unsafe { std::os::fd::OwnedFd::from_raw_fd(fd as std::os::fd::RawFd) })
+}
@@ -98,21 +98,21 @@ pub fn get_cache_file(file_name: &str) -> Result<std::path::PathBuf, Box<dyn std
     Ok(pb)
 }
 
-// This is designed as a way for the server + GUI processes to communicate; the server will update numbers in here when
-// processes die, and if they grow too fast the GUI can assume no CUDA is available.
-pub fn get_cache_file_server_proc_restart_json() -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+// This is designed as a way for the server + GUI processes to communicate; the server writes every sub-process's launchers::ProcStatus
+// in here, so the GUI can tell that a backend failed to start (eg because there is no CUDA) without having to guess from restarts.
+pub fn get_cache_file_server_proc_statuses_json() -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
     let mut pb = get_cache_dir()?;
-    pb.push("proc_restart_counts.json");
+    pb.push("proc_statuses.json");
     Ok(pb)
 }
-pub fn set_cache_file_server_proc_restart_data(data: &std::collections::HashMap::<String, u32>) -> Result<(), Box<dyn std::error::Error>> {
+pub fn set_cache_file_server_proc_statuses_data(data: &std::collections::HashMap::<String, crate::launchers::ProcStatus>) -> Result<(), Box<dyn std::error::Error>> {
     let json_txt = serde_json::to_string(data)?;
-    let json_path = get_cache_file_server_proc_restart_json()?;
+    let json_path = get_cache_file_server_proc_statuses_json()?;
     std::fs::write(&json_path, &json_txt.as_bytes())?;
     Ok(())
 }
-pub fn get_cache_file_server_proc_restart_data() -> Result<std::collections::HashMap::<String, u32>, Box<dyn std::error::Error>> {
-    let json_path = get_cache_file_server_proc_restart_json()?;
+pub fn get_cache_file_server_proc_statuses_data() -> Result<std::collections::HashMap::<String, crate::launchers::ProcStatus>, Box<dyn std::error::Error>> {
+    let json_path = get_cache_file_server_proc_statuses_json()?;
     let json_txt = std::fs::read_to_string(&json_path)?;
     let hm = serde_json::from_str(&json_txt)?;
     Ok(hm)
@@ -0,0 +1,137 @@
+// How a backend tells the process supervising it (launchers::TrackedProcs, inside oliana_server) that it is alive and ready for jobs.
+//
+// TrackedProcs names two files in each backend's environment when spawning it:
+//   OLIANA_READY_FILE      written once, when the backend can take jobs (eg after its model has loaded)
+//   OLIANA_HEARTBEAT_FILE  re-written at least every HEARTBEAT_INTERVAL_MS with what the backend is doing, eg "loading model" or "idle"
+// A backend counts as Starting until its ready file appears, and one whose heartbeat file stops changing for RestartPolicy::hang_timeout_s
+// is assumed to be hung and killed, so it gets restarted like one which crashed. Both files hold a HeartbeatInfo; the heartbeat file's
+// modification time is when the backend last beat, which lets code without a Heartbeat (eg python) beat by touching the file.
+//
+// Backends started by hand have neither variable set, and every Heartbeat method does nothing.
+
+pub const HEARTBEAT_FILE_ENV: &str = "OLIANA_HEARTBEAT_FILE";
+pub const READY_FILE_ENV: &str = "OLIANA_READY_FILE";
+
+// Beats closer together than this only re-write the heartbeat file if the activity changed.
+pub const HEARTBEAT_INTERVAL_MS: u64 = 5000;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct HeartbeatInfo {
+  pub pid: u32,
+  pub activity: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Heartbeat {
+  pub heartbeat_file: Option<std::path::PathBuf>,
+  pub ready_file: Option<std::path::PathBuf>,
+  last_beat: std::sync::Mutex<Option<(std::time::Instant, String)>>,
+}
+
+impl Heartbeat {
+  pub fn new(heartbeat_file: Option<std::path::PathBuf>, ready_file: Option<std::path::PathBuf>) -> Self {
+    Self {
+      heartbeat_file: heartbeat_file,
+      ready_file: ready_file,
+      last_beat: std::sync::Mutex::new(None),
+    }
+  }
+
+  pub fn from_env() -> Self {
+    let env_path = |name: &str| std::env::var(name).ok().filter(|val| val.len() > 0).map(std::path::PathBuf::from);
+    Self::new(env_path(HEARTBEAT_FILE_ENV), env_path(READY_FILE_ENV))
+  }
+
+  // Cheap enough to call for every token or diffusion step; the file is only written when activity changes or HEARTBEAT_INTERVAL_MS has passed.
+  pub fn beat(&self, activity: &str) {
+    let heartbeat_file = match self.heartbeat_file {
+      Some(ref heartbeat_file) => heartbeat_file,
+      None => return,
+    };
+    if let Ok(mut last_beat) = self.last_beat.lock() {
+      let now = std::time::Instant::now();
+      if let Some((last_beat_at, ref last_activity)) = *last_beat {
+        if last_activity == activity && now.duration_since(last_beat_at).as_millis() < (HEARTBEAT_INTERVAL_MS / 2) as u128 {
+          return;
+        }
+      }
+      if let Err(e) = write_info(heartbeat_file, activity) {
+        eprintln!("{}:{} {:?}", file!(), line!(), e);
+      }
+      *last_beat = Some((now, activity.to_string()));
+    }
+  }
+
+  // Marks the backend ready for jobs; activity is beaten as well, usually "idle".
+  pub fn ready(&self, activity: &str) {
+    if let Some(ref ready_file) = self.ready_file {
+      if let Err(e) = write_info(ready_file, "ready") {
+        eprintln!("{}:{} {:?}", file!(), line!(), e);
+      }
+    }
+    self.beat(activity);
+  }
+
+  // Beats activity from a background thread until the returned guard is dropped, for long blocking calls like loading a model
+  // which cannot beat themselves. A hang inside such a call goes unnoticed, so keep these around setup code only.
+  pub fn keep_beating(&self, activity: &str) -> KeepBeating {
+    self.beat(activity);
+    let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let thread = match self.heartbeat_file {
+      Some(ref heartbeat_file) => {
+        let heartbeat_file = heartbeat_file.clone();
+        let activity = activity.to_string();
+        let thread_stop = stop.clone();
+        Some(std::thread::spawn(move || {
+          let mut ms_since_last_beat: u64 = 0;
+          while !thread_stop.load(std::sync::atomic::Ordering::Relaxed) {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            ms_since_last_beat += 100;
+            if ms_since_last_beat >= HEARTBEAT_INTERVAL_MS / 2 {
+              if let Err(e) = write_info(&heartbeat_file, &activity) {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+              }
+              ms_since_last_beat = 0;
+            }
+          }
+        }))
+      }
+      None => None,
+    };
+    KeepBeating {
+      stop: stop,
+      thread: thread,
+    }
+  }
+}
+
+pub struct KeepBeating {
+  stop: std::sync::Arc<std::sync::atomic::AtomicBool>,
+  thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for KeepBeating {
+  fn drop(&mut self) {
+    self.stop.store(true, std::sync::atomic::Ordering::Relaxed);
+    if let Some(thread) = self.thread.take() {
+      if let Err(e) = thread.join() {
+        eprintln!("{}:{} {:?}", file!(), line!(), e);
+      }
+    }
+  }
+}
+
+fn write_info(file_path: &std::path::Path, activity: &str) -> std::io::Result<()> {
+  let info = HeartbeatInfo {
+    pid: std::process::id(),
+    activity: activity.to_string(),
+  };
+  crate::jobs::write_atomically(file_path, &serde_json::to_vec(&info)?)
+}
+
+// The HeartbeatInfo in file_path and when the file was last written, or None if there is no such file (yet).
+pub fn read_info(file_path: &std::path::Path) -> Option<(HeartbeatInfo, std::time::SystemTime)> {
+  let modified = std::fs::metadata(file_path).and_then(|metadata| metadata.modified()).ok()?;
+  let info = serde_json::from_slice(&std::fs::read(file_path).ok()?).ok()?;
+  Some((info, modified))
+}
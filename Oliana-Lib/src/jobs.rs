@@ -0,0 +1,824 @@
+// The contract between oliana_server and the backends (oliana_text, oliana_images) which share a work directory.
+//
+// For a job named NAME (the server uses the job's nonce) the work directory holds:
+//   NAME.json    the TextJob, SummaryJob or ImageJob to run; written by the server
+//   NAME.state   a JobStatus; the server writes Queued, the backend moves it to Running and then Done, Failed or Cancelled
+//   NAME.backend the binary name of the backend which should run the job, for work directories shared by several backends (eg oliana_images
+//                and oliana_images_procedural); jobs without one are run by whichever backend finds them first
+//   NAME.cancel  created by the server to ask the backend to stop early
+//   NAME.txt     the reply text for text jobs; streamed, so it grows while the job is Running
+//   NAME.png     the image for image jobs; only present once the job is Done. Jobs asking for several images write the rest as NAME.1.png, NAME.2.png...
+//   NAME.step-N.png  a preview of an image job as it looked after diffusion step N; written while the job is Running, for ImageJobs with
+//                a preview_interval
+//   NAME.init.png  the image an img2img or inpaint ImageJob starts from; written by the server with NAME.json
+//   NAME.mask.png  for inpaint ImageJobs, which parts of NAME.init.png to repaint: white is repainted, black is kept
+//   NAME.error.json  a JobError saying why the job failed; written just before NAME.state becomes Failed
+//
+// Everything except NAME.txt is written with write_atomically(), so readers never see half a file.
+// Backends pick up work by looking for Queued states addressed to them, which means restarting a backend never re-runs finished jobs.
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct TextJob {
+  pub messages: Vec<TextMessage>, // The conversation so far, oldest first; the backend replies to it as the assistant
+  pub sampling: TextSampling,
+}
+
+impl TextJob {
+  // Err describes the first problem with the messages or sampling settings.
+  pub fn validate(&self) -> Result<(), String> {
+    match self.messages.last() {
+      None => return Err("messages must not be empty".to_string()),
+      Some(last_message) if last_message.role != TextRole::User => {
+        return Err(format!("the last message must be from the user, got {:?}", last_message.role));
+      }
+      Some(last_message) if last_message.content.trim().len() < 1 => {
+        return Err("the last user message must not be empty".to_string());
+      }
+      Some(_) => { }
+    }
+    self.sampling.validate()
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextRole {
+  System,
+  User,
+  Assistant,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TextMessage {
+  pub role: TextRole,
+  pub content: String,
+}
+
+impl TextMessage {
+  pub fn system(content: impl Into<String>) -> Self {
+    Self { role: TextRole::System, content: content.into() }
+  }
+  pub fn user(content: impl Into<String>) -> Self {
+    Self { role: TextRole::User, content: content.into() }
+  }
+  pub fn assistant(content: impl Into<String>) -> Self {
+    Self { role: TextRole::Assistant, content: content.into() }
+  }
+}
+
+// Asks oliana_text to fold the oldest turns of a conversation into a short "story so far", so long conversations still fit in the
+// model's context window. The summary is streamed to NAME.txt exactly like a reply.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SummaryJob {
+  pub story_so_far: String, // The previous summary, which the new one replaces; empty the first time
+  pub summarize: Vec<TextMessage>, // The turns to fold in, oldest first
+  pub max_tokens: usize,
+}
+
+impl SummaryJob {
+  // The request oliana_text actually runs; keeping the prompt here means every text backend summarizes the same way.
+  pub fn to_text_job(&self) -> TextJob {
+    let mut transcript = String::new();
+    if self.story_so_far.len() > 0 {
+      transcript.push_str(&format!("The story so far:\n{}\n\n", self.story_so_far));
+    }
+    transcript.push_str("What happened next:\n");
+    for message in self.summarize.iter() {
+      let speaker = match message.role {
+        TextRole::System => "Narrator",
+        TextRole::User => "Player",
+        TextRole::Assistant => "Storyteller",
+      };
+      transcript.push_str(&format!("{}: {}\n", speaker, message.content.trim()));
+    }
+    transcript.push_str("\nWrite the new story so far.");
+    TextJob {
+      messages: vec![
+        TextMessage::system("You keep notes for a storyteller. Summarize the story you are given in a few short paragraphs, keeping every name, place, \
+                             promise and unanswered question the player may come back to. Reply with the summary only."),
+        TextMessage::user(transcript),
+      ],
+      sampling: TextSampling {
+        max_tokens: Some(self.max_tokens),
+        ..TextSampling::default()
+      },
+    }
+  }
+}
+
+// NAME.json in oliana_text's work directory holds either kind of text job. SummaryJob's fields have no defaults, so a TextJob never parses as one.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(untagged)]
+pub enum TextBackendJob {
+  Summary(SummaryJob),
+  Reply(TextJob),
+}
+
+impl TextBackendJob {
+  pub fn into_text_job(self) -> TextJob {
+    match self {
+      TextBackendJob::Summary(summary_job) => summary_job.to_text_job(),
+      TextBackendJob::Reply(text_job) => text_job,
+    }
+  }
+}
+
+// How the backend picks each next token; every None leaves that setting at the backend's default.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct TextSampling {
+  pub temperature: Option<f64>, // 0 or more; lower values give more predictable replies
+  pub top_p: Option<f64>, // In (0, 1]; only sample from the most likely tokens whose probabilities add up to top_p
+  pub top_k: Option<usize>, // 1 or more; only sample from the top_k most likely tokens
+  pub max_tokens: Option<usize>, // 1 or more; the reply stops after this many new tokens
+  // Always take the most likely token, so the same messages always get the same reply. mistralrs cannot seed a single request,
+  // so this is the only reproducible mode; validate() rejects it together with temperature, top_p or top_k.
+  pub deterministic: bool,
+  pub stop: Vec<String>, // The reply ends before the first of these it would contain
+}
+
+impl TextSampling {
+  // Err describes the first setting which is out of range.
+  pub fn validate(&self) -> Result<(), String> {
+    if let Some(temperature) = self.temperature {
+      if !temperature.is_finite() || temperature < 0.0 {
+        return Err(format!("temperature must be 0 or more, got {}", temperature));
+      }
+    }
+    if let Some(top_p) = self.top_p {
+      if !top_p.is_finite() || top_p <= 0.0 || top_p > 1.0 {
+        return Err(format!("top_p must be more than 0 and at most 1, got {}", top_p));
+      }
+    }
+    if self.top_k == Some(0) {
+      return Err("top_k must be at least 1".to_string());
+    }
+    if self.max_tokens == Some(0) {
+      return Err("max_tokens must be at least 1".to_string());
+    }
+    if self.stop.iter().any(|stop_string| stop_string.len() < 1) {
+      return Err("stop strings must not be empty".to_string());
+    }
+    if self.deterministic && (self.temperature.is_some() || self.top_p.is_some() || self.top_k.is_some()) {
+      return Err("deterministic cannot be combined with temperature, top_p or top_k; deterministic replies always take the most likely token".to_string());
+    }
+    Ok(())
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ImageJob {
+  pub prompt: String,
+  pub negative_prompt: String,
+  pub guidance_scale: f32,
+  pub num_inference_steps: u32,
+  pub transparent_background: bool, // Write an RGBA NAME.png whose background has alpha 0, leaving only the subject (see oliana_lib::segment)
+  pub preview_interval: u32, // Write NAME.step-N.png (of the first image) after every preview_interval-th step (but not the last, which is NAME.png); 0 writes no previews
+  #[serde(flatten)]
+  pub options: ImageOptions,
+  pub mode: ImageMode,
+  pub strength: f32, // For Img2Img and Inpaint jobs, how much of the init image to repaint: near 0 keeps it almost as it is, 1 replaces it entirely
+  // The PNGs Img2Img and Inpaint jobs start from. They are not part of NAME.json: JobFiles::submit() writes them to NAME.init.png and
+  // NAME.mask.png, and backends read them from there.
+  #[serde(skip)]
+  pub init_png: Vec<u8>,
+  #[serde(skip)]
+  pub mask_png: Vec<u8>,
+}
+
+impl Default for ImageJob {
+  fn default() -> Self {
+    Self {
+      prompt: String::new(),
+      negative_prompt: String::new(),
+      guidance_scale: 3.5,
+      num_inference_steps: 10,
+      transparent_background: false,
+      preview_interval: 0,
+      options: ImageOptions::default(),
+      mode: ImageMode::Text,
+      strength: DEFAULT_IMAGE_STRENGTH,
+      init_png: vec![],
+      mask_png: vec![],
+    }
+  }
+}
+
+impl ImageJob {
+  // Err describes the first problem with the job's mode, strength or init and mask images.
+  pub fn validate_mode(&self) -> Result<(), String> {
+    let (needs_init, needs_mask) = match self.mode {
+      ImageMode::Text => (false, false),
+      ImageMode::Img2Img => (true, false),
+      ImageMode::Inpaint => (true, true),
+    };
+    for (name, png, is_needed) in [("init_png", &self.init_png, needs_init), ("mask_png", &self.mask_png, needs_mask)] {
+      if png.len() > 0 && !is_needed {
+        return Err(format!("{} jobs do not take {}", self.mode, name));
+      }
+      if png.len() < 1 && is_needed {
+        return Err(format!("{} jobs need {}", self.mode, name));
+      }
+      if png.len() > 0 {
+        image::ImageReader::with_format(std::io::Cursor::new(png), image::ImageFormat::Png).into_dimensions()
+          .map_err(|e| format!("{} is not a PNG: {}", name, e))?;
+      }
+    }
+    if self.mode != ImageMode::Text && !(self.strength > 0.0 && self.strength <= 1.0) {
+      return Err(format!("strength must be above 0 and at most 1, got {}", self.strength));
+    }
+    Ok(())
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageMode {
+  Text, // Draw the prompt from noise
+  Img2Img, // Repaint NAME.init.png towards the prompt, keeping its layout and colors in proportion to 1 - strength
+  Inpaint, // Repaint only the white parts of NAME.mask.png within NAME.init.png
+}
+
+// The same names NAME.json uses, eg "img2img"
+impl std::fmt::Display for ImageMode {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ImageMode::Text => write!(f, "text"),
+      ImageMode::Img2Img => write!(f, "img2img"),
+      ImageMode::Inpaint => write!(f, "inpaint"),
+    }
+  }
+}
+
+// Enough change for a scene to follow the story while staying recognisably the same place.
+pub const DEFAULT_IMAGE_STRENGTH: f32 = 0.6;
+
+// The most images one job may ask for; more would not fit in VRAM next to the pipeline.
+pub const MAX_NUM_IMAGES: u32 = 4;
+
+// Named sizes from the resolutions SDXL was trained on, for clients which would rather pick an aspect ratio than a width and height.
+pub const IMAGE_SIZE_PRESETS: [(&str, u32, u32); 4] = [
+  ("square", 1024, 1024),
+  ("portrait", 832, 1216),
+  ("landscape", 1216, 832),
+  ("wide", 1344, 768),
+];
+
+// Parses a preset name from IMAGE_SIZE_PRESETS or a size like "768x512" into (width, height).
+pub fn parse_image_size(size: &str) -> Result<(u32, u32), String> {
+  if let Some((_name, width, height)) = IMAGE_SIZE_PRESETS.iter().find(|(name, _, _)| name.eq_ignore_ascii_case(size.trim())) {
+    return Ok((*width, *height));
+  }
+  let (width_s, height_s) = size.trim().split_once(['x', 'X']).ok_or_else(|| {
+    format!("Expected one of {} or a size like 768x512, got {:?}", IMAGE_SIZE_PRESETS.map(|(name, _, _)| name).join(", "), size)
+  })?;
+  let width = width_s.parse::<u32>().map_err(|e| format!("{:?} in {:?}", e, size))?;
+  let height = height_s.parse::<u32>().map_err(|e| format!("{:?} in {:?}", e, size))?;
+  Ok((width, height))
+}
+
+// Everything about an ImageJob's output besides what it depicts. Flattened into ImageJob, so backends read these as top-level fields.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ImageOptions {
+  pub width: u32,
+  pub height: u32,
+  // Image i of a job is made from seed + i, so any one of them can be made again on its own; None lets the backend pick a random seed.
+  // oliana_server always picks one itself, so it can report the seeds with the images.
+  pub seed: Option<u64>,
+  pub num_images: u32,
+}
+
+impl Default for ImageOptions {
+  fn default() -> Self {
+    Self {
+      width: 1024, // The koala SDXL pipeline's own default
+      height: 1024,
+      seed: None,
+      num_images: 1,
+    }
+  }
+}
+
+impl ImageOptions {
+  // Err describes the first setting the pipeline cannot use.
+  pub fn validate(&self) -> Result<(), String> {
+    for (name, value) in [("width", self.width), ("height", self.height)] {
+      if value < 256 || value > 2048 || value % 8 != 0 {
+        return Err(format!("{} must be a multiple of 8 from 256 to 2048, got {}", name, value));
+      }
+    }
+    if self.num_images < 1 || self.num_images > MAX_NUM_IMAGES {
+      return Err(format!("num_images must be from 1 to {}, got {}", MAX_NUM_IMAGES, self.num_images));
+    }
+    if let Some(seed) = self.seed {
+      if seed.checked_add(self.num_images as u64).is_none() {
+        return Err(format!("seed must leave room for one seed per image, got {}", seed));
+      }
+    }
+    Ok(())
+  }
+}
+
+// For image jobs whose caller did not pick a seed. std's RandomState is keyed randomly for every instance, which is all the randomness this needs;
+// 48 bits leave room for a seed per image and stay exact in JSON readers which hold numbers as f64.
+pub fn random_image_seed() -> u64 {
+  use std::hash::{BuildHasher, Hasher};
+  std::collections::hash_map::RandomState::new().build_hasher().finish() & 0xFFFF_FFFF_FFFF
+}
+
+// Any kind of job, for code like the server's queue which holds them all until they are submitted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobInput {
+  Text(TextJob),
+  Summary(SummaryJob),
+  Image(ImageJob),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+  Queued,
+  Running,
+  Done,
+  Failed,
+  Cancelled,
+}
+
+impl JobState {
+  // Once a job reaches one of these the backend has moved on and nothing about the job changes again.
+  pub fn is_finished(&self) -> bool {
+    match self {
+      JobState::Queued | JobState::Running => false,
+      JobState::Done | JobState::Failed | JobState::Cancelled => true,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct JobStatus {
+  pub state: JobState,
+  #[serde(default)]
+  pub error: Option<String>, // Set with JobState::Failed; the JobError's message, NAME.error.json holds the rest
+}
+
+// Why a job failed, kept out of NAME.txt so an error can never be mistaken for the model's reply.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct JobError {
+  pub kind: JobErrorKind,
+  pub message: String, // One line, eg "torch.OutOfMemoryError: CUDA out of memory. Tried to allocate 20.00 MiB"
+  #[serde(default)]
+  pub traceback: String, // Everything else the backend knows about the failure, eg a python traceback; may be empty
+  pub retryable: bool, // Whether submitting the same job again might succeed
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobErrorKind {
+  InvalidInput, // The job cannot be run as written, eg NAME.json or NAME.init.png cannot be read, or the model refused the request
+  OutOfMemory, // The backend ran out of (GPU) memory; smaller or fewer jobs at once may succeed
+  BackendExited, // The backend process exited while working on the job
+  BackendDown, // The server could not hand the job to its backend, eg because the backend's binary is missing
+  Exception, // The model or pipeline raised an error
+  Io, // The backend could not read or write the work directory
+}
+
+impl JobErrorKind {
+  // Whether a job which failed like this might succeed if submitted again unchanged
+  pub fn is_retryable(&self) -> bool {
+    match self {
+      JobErrorKind::OutOfMemory | JobErrorKind::BackendExited | JobErrorKind::BackendDown | JobErrorKind::Io => true,
+      JobErrorKind::InvalidInput | JobErrorKind::Exception => false,
+    }
+  }
+}
+
+impl JobError {
+  pub fn new(kind: JobErrorKind, message: impl Into<String>) -> Self {
+    Self {
+      kind: kind,
+      message: message.into(),
+      traceback: String::new(),
+      retryable: kind.is_retryable(),
+    }
+  }
+
+  pub fn with_traceback(mut self, traceback: impl Into<String>) -> Self {
+    self.traceback = traceback.into();
+    self
+  }
+
+  // For failures which only come as text, eg a python traceback or a debug-printed error: the last non-empty line (eg "ValueError: ...")
+  // becomes the message, and the text is recognized as OutOfMemory (else default_kind) by the phrases torch, CUDA and candle use.
+  pub fn from_traceback(default_kind: JobErrorKind, traceback: &str) -> Self {
+    let message = traceback.lines().rev().map(|l| l.trim()).find(|l| l.len() > 0).unwrap_or("Unknown error");
+    let lowercase_traceback = traceback.to_lowercase();
+    let kind = if lowercase_traceback.contains("out of memory") || lowercase_traceback.contains("out_of_memory") || lowercase_traceback.contains("outofmemoryerror") {
+      JobErrorKind::OutOfMemory
+    }
+    else {
+      default_kind
+    };
+    JobError::new(kind, message).with_traceback(traceback)
+  }
+}
+
+impl std::fmt::Display for JobError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{:?}: {}", self.kind, self.message)
+  }
+}
+
+// Names every file belonging to one job; see the top of this file for what each one holds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobFiles {
+  pub workdir: std::path::PathBuf,
+  pub name: String,
+}
+
+impl JobFiles {
+  pub fn new(workdir: impl Into<std::path::PathBuf>, name: &str) -> Self {
+    Self {
+      workdir: workdir.into(),
+      name: name.to_string(),
+    }
+  }
+
+  pub fn path(&self, extension: &str) -> std::path::PathBuf {
+    self.workdir.join(format!("{}.{}", self.name, extension))
+  }
+  pub fn input_path(&self) -> std::path::PathBuf {
+    self.path("json")
+  }
+  pub fn state_path(&self) -> std::path::PathBuf {
+    self.path("state")
+  }
+  pub fn backend_path(&self) -> std::path::PathBuf {
+    self.path("backend")
+  }
+  pub fn cancel_request_path(&self) -> std::path::PathBuf {
+    self.path("cancel")
+  }
+  pub fn text_output_path(&self) -> std::path::PathBuf {
+    self.path("txt")
+  }
+  pub fn image_output_path(&self) -> std::path::PathBuf {
+    self.path("png")
+  }
+  // NAME.png for the first image of a job, then NAME.1.png, NAME.2.png...
+  pub fn nth_image_output_path(&self, image_i: u32) -> std::path::PathBuf {
+    match image_i {
+      0 => self.image_output_path(),
+      _ => self.path(&format!("{}.png", image_i)),
+    }
+  }
+  pub fn preview_path(&self, step: u32) -> std::path::PathBuf {
+    self.path(&format!("step-{}.png", step))
+  }
+  pub fn init_image_path(&self) -> std::path::PathBuf {
+    self.path("init.png")
+  }
+  pub fn mask_image_path(&self) -> std::path::PathBuf {
+    self.path("mask.png")
+  }
+  pub fn error_path(&self) -> std::path::PathBuf {
+    self.path("error.json")
+  }
+
+  // Every NAME.step-N.png written so far, in step order.
+  pub fn list_previews(&self) -> std::io::Result<Vec<(u32, std::path::PathBuf)>> {
+    let preview_prefix = format!("{}.step-", self.name);
+    let mut previews = vec![];
+    for entry in std::fs::read_dir(&self.workdir)? {
+      let entry_path = entry?.path();
+      let step = entry_path.file_name().and_then(std::ffi::OsStr::to_str)
+                  .and_then(|file_name| file_name.strip_prefix(&preview_prefix))
+                  .and_then(|file_name| file_name.strip_suffix(".png"))
+                  .and_then(|step_s| step_s.parse::<u32>().ok());
+      if let Some(step) = step {
+        previews.push((step, entry_path));
+      }
+    }
+    previews.sort_by_key(|(step, _)| *step);
+    Ok(previews)
+  }
+
+  // Used by the server; clears anything left over from an earlier job with the same name, then hands the job to the backend named backend_bin_name.
+  pub fn submit(&self, input: &JobInput, backend_bin_name: &str) -> std::io::Result<()> {
+    let stale_image_paths = (0..MAX_NUM_IMAGES).map(|image_i| self.nth_image_output_path(image_i));
+    let stale_paths = [self.cancel_request_path(), self.text_output_path(), self.init_image_path(), self.mask_image_path(), self.error_path()];
+    for stale_path in stale_paths.into_iter().chain(stale_image_paths) {
+      if stale_path.exists() {
+        std::fs::remove_file(&stale_path)?;
+      }
+    }
+    for (_step, stale_preview_path) in self.list_previews()? {
+      std::fs::remove_file(&stale_preview_path)?;
+    }
+    let input_json = match input {
+      JobInput::Text(text_job) => serde_json::to_vec(text_job)?,
+      JobInput::Summary(summary_job) => serde_json::to_vec(summary_job)?,
+      JobInput::Image(image_job) => serde_json::to_vec(image_job)?,
+    };
+    if let JobInput::Image(image_job) = input {
+      for (png, png_path) in [(&image_job.init_png, self.init_image_path()), (&image_job.mask_png, self.mask_image_path())] {
+        if png.len() > 0 {
+          write_atomically(&png_path, png)?;
+        }
+      }
+    }
+    write_atomically(&self.input_path(), &input_json)?;
+    write_atomically(&self.backend_path(), backend_bin_name.as_bytes())?;
+    self.write_state(JobState::Queued)
+  }
+
+  // None if the job may be run by any backend
+  pub fn read_backend(&self) -> std::io::Result<Option<String>> {
+    match std::fs::read_to_string(self.backend_path()) {
+      Ok(backend_bin_name) => Ok(Some(backend_bin_name.trim().to_string())),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e),
+    }
+  }
+
+  pub fn read_input<T: serde::de::DeserializeOwned>(&self) -> std::io::Result<T> {
+    let input_json = std::fs::read(self.input_path())?;
+    Ok(serde_json::from_slice(&input_json)?)
+  }
+
+  // Ok(None) if the job has never been submitted
+  pub fn read_status(&self) -> std::io::Result<Option<JobStatus>> {
+    match std::fs::read(self.state_path()) {
+      Ok(state_json) => Ok(Some(serde_json::from_slice(&state_json)?)),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e),
+    }
+  }
+
+  // Shorthand for callers which only care whether a job is finished; unreadable states count as not finished yet.
+  pub fn read_state(&self) -> Option<JobState> {
+    match self.read_status() {
+      Ok(maybe_status) => maybe_status.map(|status| status.state),
+      Err(e) => {
+        eprintln!("{}:{} {:?}", file!(), line!(), e);
+        None
+      }
+    }
+  }
+
+  pub fn write_state(&self, state: JobState) -> std::io::Result<()> {
+    self.write_status(&JobStatus { state: state, error: None })
+  }
+
+  // NAME.error.json is written first, so anyone who sees the Failed state can read why.
+  pub fn write_failed(&self, error: &JobError) -> std::io::Result<()> {
+    write_atomically(&self.error_path(), &serde_json::to_vec(error)?)?;
+    self.write_status(&JobStatus { state: JobState::Failed, error: Some(error.message.clone()) })
+  }
+
+  // Why a Failed job failed. Jobs failed by a backend which writes no NAME.error.json are described from NAME.state's error text instead.
+  pub fn read_error(&self) -> std::io::Result<JobError> {
+    match std::fs::read(self.error_path()) {
+      Ok(error_json) => Ok(serde_json::from_slice(&error_json)?),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+        let error_text = self.read_status()?.and_then(|status| status.error).unwrap_or_default();
+        Ok(JobError::from_traceback(JobErrorKind::Exception, &error_text))
+      }
+      Err(e) => Err(e),
+    }
+  }
+
+  pub fn write_status(&self, status: &JobStatus) -> std::io::Result<()> {
+    write_atomically(&self.state_path(), &serde_json::to_vec(status)?)
+  }
+
+  pub fn request_cancel(&self) -> std::io::Result<()> {
+    write_atomically(&self.cancel_request_path(), " ".as_bytes())
+  }
+
+  pub fn is_cancel_requested(&self) -> bool {
+    self.cancel_request_path().exists()
+  }
+}
+
+// Writes a job's final state when dropped. Backends create one before marking a job Running, so that an error? return part-way through
+// a job leaves it Failed instead of Running forever, which would stop the server from dispatching anything else to the backend.
+#[clippy::has_significant_drop]
+pub struct WriteStatusOnDropped {
+  pub job_files: JobFiles,
+  pub outcome: Result<JobState, JobError>,
+}
+
+impl WriteStatusOnDropped {
+  pub fn new(job_files: JobFiles, backend_bin_name: &str) -> Self {
+    Self {
+      job_files: job_files,
+      // Only an error? return can leave this in place, and those are all failures to read or write the work directory
+      outcome: Err(JobError::new(JobErrorKind::Io, format!("{} stopped working on this job before finishing it", backend_bin_name))),
+    }
+  }
+  // Changes what is written on drop, eg to report a finished or cancelled job instead of a failed one.
+  pub fn set_outcome(&mut self, outcome: Result<JobState, JobError>) {
+    self.outcome = outcome;
+  }
+}
+
+impl Drop for WriteStatusOnDropped {
+  fn drop(&mut self) {
+    let written = match &self.outcome {
+      Ok(state) => self.job_files.write_state(*state),
+      Err(job_error) => self.job_files.write_failed(job_error),
+    };
+    if let Err(e) = written {
+      eprintln!("{:?} when writing {}", e, self.job_files.state_path().display());
+    }
+  }
+}
+
+// Writes to a hidden temporary file next to file_path and renames it into place, so file_path is either missing, the old contents or the new contents.
+pub fn write_atomically(file_path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+  let tmp_path = temp_path_for(file_path);
+  std::fs::write(&tmp_path, contents)?;
+  std::fs::rename(&tmp_path, file_path)
+}
+
+// The temporary file write_atomically() uses; also useful for programs (eg python) which need to write the file themselves before it is renamed into place.
+pub fn temp_path_for(file_path: &std::path::Path) -> std::path::PathBuf {
+  let file_name = file_path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+  file_path.with_file_name(format!(".{}.tmp", file_name))
+}
+
+// Returns every job in workdir which is waiting for the backend named backend_bin_name (or for any backend), oldest first.
+pub fn find_queued_jobs(workdir: impl Into<std::path::PathBuf>, backend_bin_name: &str) -> std::io::Result<Vec<JobFiles>> {
+  let workdir = workdir.into();
+  let mut queued_jobs: Vec<(std::time::SystemTime, JobFiles)> = vec![];
+  for entry in std::fs::read_dir(&workdir)? {
+    let entry_path = entry?.path();
+    if entry_path.extension().and_then(std::ffi::OsStr::to_str) != Some("state") {
+      continue;
+    }
+    let name = match entry_path.file_stem().and_then(std::ffi::OsStr::to_str) {
+      Some(name) => name,
+      None => continue,
+    };
+    let job_files = JobFiles::new(workdir.clone(), name);
+    if job_files.read_state() == Some(JobState::Queued) {
+      match job_files.read_backend() {
+        Ok(Some(job_backend)) if job_backend != backend_bin_name => continue, // Another backend sharing workdir will run this one
+        Ok(_) => { }
+        Err(e) => {
+          eprintln!("{}:{} {:?}", file!(), line!(), e);
+          continue;
+        }
+      }
+      let queued_at = std::fs::metadata(&entry_path).and_then(|m| m.modified()).unwrap_or(std::time::SystemTime::UNIX_EPOCH);
+      queued_jobs.push((queued_at, job_files));
+    }
+  }
+  queued_jobs.sort_by(|a, b| a.0.cmp(&b.0));
+  Ok(queued_jobs.into_iter().map(|(_queued_at, job_files)| job_files).collect())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A fresh, empty work directory per test, so tests running in parallel never see each other's jobs
+  fn temp_workdir(test_name: &str) -> std::path::PathBuf {
+    let workdir = std::env::temp_dir().join(format!("oliana_lib-jobs-{}-{}", test_name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&workdir);
+    std::fs::create_dir_all(&workdir).expect("cannot create the test work directory");
+    workdir
+  }
+
+  fn queued_names(workdir: &std::path::Path, backend_bin_name: &str) -> Vec<String> {
+    find_queued_jobs(workdir, backend_bin_name).expect("find_queued_jobs").into_iter().map(|job_files| job_files.name).collect()
+  }
+
+  #[test]
+  fn submitted_jobs_are_found_by_their_backend_only() {
+    let workdir = temp_workdir("find");
+    let text_job = JobInput::Text(TextJob { messages: vec![TextMessage::user("Hello")], ..TextJob::default() });
+    let image_job = JobInput::Image(ImageJob::default());
+    JobFiles::new(&workdir, "1").submit(&text_job, "oliana_text").unwrap();
+    JobFiles::new(&workdir, "2").submit(&image_job, "oliana_images").unwrap();
+    JobFiles::new(&workdir, "3").submit(&image_job, "oliana_images_procedural").unwrap();
+    // Jobs without NAME.backend go to whichever backend finds them
+    let unaddressed = JobFiles::new(&workdir, "4");
+    write_atomically(&unaddressed.input_path(), b"{}").unwrap();
+    unaddressed.write_state(JobState::Queued).unwrap();
+
+    assert_eq!(queued_names(&workdir, "oliana_text").len(), 2);
+    assert!(queued_names(&workdir, "oliana_text").contains(&"1".to_string()));
+    assert!(queued_names(&workdir, "oliana_images").contains(&"2".to_string()));
+    assert!(!queued_names(&workdir, "oliana_images").contains(&"3".to_string()));
+    assert!(queued_names(&workdir, "oliana_images_procedural").contains(&"3".to_string()));
+    assert!(queued_names(&workdir, "oliana_images_procedural").contains(&"4".to_string()));
+
+    // Only Queued jobs are picked up
+    JobFiles::new(&workdir, "2").write_state(JobState::Running).unwrap();
+    assert_eq!(queued_names(&workdir, "oliana_images"), vec!["4".to_string()]);
+    let _ = std::fs::remove_dir_all(&workdir);
+  }
+
+  #[test]
+  fn states_move_from_queued_to_finished() {
+    let workdir = temp_workdir("states");
+    let job_files = JobFiles::new(&workdir, "1");
+    assert_eq!(job_files.read_state(), None);
+    job_files.submit(&JobInput::Image(ImageJob::default()), "oliana_images").unwrap();
+    assert_eq!(job_files.read_state(), Some(JobState::Queued));
+    assert_eq!(job_files.read_backend().unwrap(), Some("oliana_images".to_string()));
+
+    job_files.write_state(JobState::Running).unwrap();
+    assert_eq!(job_files.read_state(), Some(JobState::Running));
+    assert!(!JobState::Running.is_finished());
+
+    job_files.write_failed(&JobError::new(JobErrorKind::OutOfMemory, "CUDA out of memory")).unwrap();
+    let status = job_files.read_status().unwrap().unwrap();
+    assert_eq!(status.state, JobState::Failed);
+    assert_eq!(status.error, Some("CUDA out of memory".to_string()));
+    let job_error = job_files.read_error().unwrap();
+    assert_eq!(job_error.kind, JobErrorKind::OutOfMemory);
+    assert!(job_error.retryable);
+
+    // Resubmitting clears the previous run's error
+    job_files.submit(&JobInput::Image(ImageJob::default()), "oliana_images").unwrap();
+    assert!(!job_files.error_path().exists());
+    job_files.write_state(JobState::Done).unwrap();
+    assert_eq!(job_files.read_status().unwrap().unwrap(), JobStatus { state: JobState::Done, error: None });
+    assert!(JobState::Done.is_finished() && JobState::Cancelled.is_finished() && JobState::Failed.is_finished());
+    let _ = std::fs::remove_dir_all(&workdir);
+  }
+
+  #[test]
+  fn dropped_status_writer_fails_the_job_unless_told_otherwise() {
+    let workdir = temp_workdir("dropped");
+    let job_files = JobFiles::new(&workdir, "1");
+    job_files.submit(&JobInput::Image(ImageJob::default()), "test_backend").unwrap();
+    {
+      let _final_state_writer = WriteStatusOnDropped::new(job_files.clone(), "test_backend");
+      job_files.write_state(JobState::Running).unwrap();
+      // Returning early, eg on an error?, drops it here without set_outcome()
+    }
+    assert_eq!(job_files.read_state(), Some(JobState::Failed));
+    let job_error = job_files.read_error().unwrap();
+    assert_eq!(job_error.kind, JobErrorKind::Io);
+    assert!(job_error.message.contains("test_backend"));
+
+    {
+      let mut final_state_writer = WriteStatusOnDropped::new(job_files.clone(), "test_backend");
+      job_files.write_state(JobState::Running).unwrap();
+      final_state_writer.set_outcome(Ok(JobState::Done));
+    }
+    assert_eq!(job_files.read_state(), Some(JobState::Done));
+    let _ = std::fs::remove_dir_all(&workdir);
+  }
+
+  #[test]
+  fn write_atomically_never_exposes_a_partial_file() {
+    let workdir = temp_workdir("atomic");
+    let file_path = workdir.join("1.json");
+    const FILE_LEN: usize = 256 * 1024;
+    write_atomically(&file_path, &vec![b'a'; FILE_LEN]).unwrap();
+
+    let writer_path = file_path.clone();
+    let writer = std::thread::spawn(move || {
+      for i in 0..200 {
+        let fill = if i % 2 == 0 { b'b' } else { b'a' };
+        write_atomically(&writer_path, &vec![fill; FILE_LEN]).unwrap();
+      }
+    });
+    let mut num_reads = 0;
+    while !writer.is_finished() {
+      let contents = std::fs::read(&file_path).unwrap();
+      assert_eq!(contents.len(), FILE_LEN);
+      assert!(contents.iter().all(|b| *b == contents[0]), "read a mix of two writes");
+      num_reads += 1;
+    }
+    writer.join().unwrap();
+    assert!(num_reads > 0);
+    assert!(!temp_path_for(&file_path).exists());
+    let _ = std::fs::remove_dir_all(&workdir);
+  }
+
+  #[test]
+  fn text_backend_jobs_parse_as_either_kind() {
+    let text_job = TextJob { messages: vec![TextMessage::user("Hello")], ..TextJob::default() };
+    let parsed: TextBackendJob = serde_json::from_slice(&serde_json::to_vec(&text_job).unwrap()).unwrap();
+    assert_eq!(parsed, TextBackendJob::Reply(text_job));
+
+    let summary_job = SummaryJob { story_so_far: String::new(), summarize: vec![TextMessage::user("I open the door")], max_tokens: 200 };
+    let parsed: TextBackendJob = serde_json::from_slice(&serde_json::to_vec(&summary_job).unwrap()).unwrap();
+    assert_eq!(parsed, TextBackendJob::Summary(summary_job.clone()));
+    let summary_text_job = parsed.into_text_job();
+    assert_eq!(summary_text_job.sampling.max_tokens, Some(200));
+    assert!(summary_text_job.messages.last().unwrap().content.contains("I open the door"));
+
+    // TextJob's fields all have defaults, so even an empty object is a reply
+    let parsed: TextBackendJob = serde_json::from_str("{}").unwrap();
+    assert_eq!(parsed, TextBackendJob::Reply(TextJob::default()));
+  }
+}
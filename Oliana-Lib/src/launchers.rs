@@ -1,6 +1,12 @@
 
 use crate as oliana_lib;
 
+// How many of a process's most recent lines get_proc_outputs() returns
+pub const PROC_OUTPUT_LINES: usize = 400;
+
+// How long TrackedProcs::shutdown() waits for the output of the processes it stopped to be captured, see OneTrackedProc::join_log_tasks()
+pub const LOG_TASK_GRACE_MS: u64 = 500;
+
 // This structure is responsible for watching over the
 // Oliana-Text and Oliana-Text subprocesses and providing accessors to their
 // outputs over a directory structure.
@@ -11,9 +17,141 @@ pub struct TrackedProcs {
   pub expected_bin_directory: std::path::PathBuf,
   pub procs: Vec<OneTrackedProc>,
   pub tracked_proc_args: Vec<(String, Vec<String>)>,
-  pub sinfo: sysinfo::System,
-  pub spawned_children: Vec<std::process::Child>,
   pub procs_should_be_stopped: bool,
+  pub restart_policy: RestartPolicy,
+  pub child_exited: std::sync::Arc<tokio::sync::Notify>, // Woken (on Linux) as soon as a tracked process exits; call ensure_registered_procs_running() to record it
+  pub exit_tx: tokio::sync::broadcast::Sender<ProcExitEvent>,
+  pub shutting_down: bool, // Set by shutdown(), after which nothing is spawned again
+}
+
+// How a tracked process which keeps exiting is restarted: after each unexpected exit we wait initial_backoff_ms, doubling up to max_backoff_ms
+// while it keeps exiting before it reports ready, and once it has been started max_starts_in_window times within window_s seconds it is
+// left stopped (ProcState::Failed) until the server restarts. A process whose heartbeat (see oliana_lib::heartbeat) is older than
+// hang_timeout_s is killed, and then restarted like any other which exited. TrackedProcs::shutdown() gives processes shutdown_grace_ms
+// to exit after SIGTERM before killing them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestartPolicy {
+  pub initial_backoff_ms: u64,
+  pub max_backoff_ms: u64,
+  pub hang_timeout_s: u64,
+  pub max_starts_in_window: usize,
+  pub window_s: u64,
+  pub log_lines: usize, // How many lines of each of stdout and stderr ProcLog keeps
+  pub shutdown_grace_ms: u64,
+}
+
+impl Default for RestartPolicy {
+  fn default() -> Self {
+    Self {
+      initial_backoff_ms: 1000,
+      max_backoff_ms: 60_000,
+      hang_timeout_s: 300,
+      max_starts_in_window: 5,
+      window_s: 120,
+      log_lines: crate::proc_logs::DEFAULT_RING_CAPACITY_LINES,
+      shutdown_grace_ms: 5000,
+    }
+  }
+}
+
+impl RestartPolicy {
+  // The defaults, with each value overridden by its environment variable when that is set to a number:
+  // OLIANA_RESTART_BACKOFF_MS, OLIANA_RESTART_MAX_BACKOFF_MS, OLIANA_HANG_TIMEOUT_S, OLIANA_RESTART_MAX_STARTS, OLIANA_RESTART_WINDOW_S,
+  // OLIANA_PROC_LOG_LINES and OLIANA_SHUTDOWN_GRACE_MS.
+  pub fn from_env() -> Self {
+    fn env_num<T: std::str::FromStr>(name: &str, default: T) -> T {
+      match std::env::var(name).ok().and_then(|val| val.parse::<T>().ok()) {
+        Some(val) => val,
+        None => default,
+      }
+    }
+    let defaults = Self::default();
+    Self {
+      initial_backoff_ms: env_num("OLIANA_RESTART_BACKOFF_MS", defaults.initial_backoff_ms),
+      max_backoff_ms: env_num("OLIANA_RESTART_MAX_BACKOFF_MS", defaults.max_backoff_ms),
+      hang_timeout_s: env_num("OLIANA_HANG_TIMEOUT_S", defaults.hang_timeout_s),
+      max_starts_in_window: env_num("OLIANA_RESTART_MAX_STARTS", defaults.max_starts_in_window),
+      window_s: env_num("OLIANA_RESTART_WINDOW_S", defaults.window_s),
+      log_lines: env_num("OLIANA_PROC_LOG_LINES", defaults.log_lines),
+      shutdown_grace_ms: env_num("OLIANA_SHUTDOWN_GRACE_MS", defaults.shutdown_grace_ms),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcState {
+  /// Spawned, but has not written its ready file yet (eg it is still loading a model)
+  Starting,
+  /// Wrote its ready file and is taking jobs
+  Healthy,
+  /// Exited and will be spawned again once its backoff has passed
+  BackingOff,
+  /// Was started RestartPolicy::max_starts_in_window times within RestartPolicy::window_s and will not be spawned again
+  Failed,
+  /// Stopped by TrackedProcs::shutdown()
+  Stopped,
+}
+
+// How a tracked process last exited; code is None if a signal ended it.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ProcExit {
+  pub code: Option<i32>,
+  pub signal: Option<i32>,
+  pub unix_s: u64,
+}
+
+impl ProcExit {
+  pub fn new(status: std::process::ExitStatus) -> Self {
+    #[cfg(unix)]
+    let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+    #[cfg(not(unix))]
+    let signal = None;
+    Self {
+      code: status.code(),
+      signal: signal,
+      unix_s: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+    }
+  }
+}
+
+impl std::fmt::Display for ProcExit {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match (self.code, self.signal) {
+      (Some(code), _) => write!(f, "exit code {}", code),
+      (None, Some(signal)) => write!(f, "signal {}", signal),
+      (None, None) => write!(f, "unknown exit status"),
+    }
+  }
+}
+
+// How many exits a slow TrackedProcs::subscribe_exits() subscriber may fall behind before it misses some
+pub const EXIT_EVENT_CAPACITY: usize = 64;
+
+// Sent to TrackedProcs::subscribe_exits() subscribers each time a tracked process is reaped
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcExitEvent {
+  pub bin_name: String,
+  pub pid: u32,
+  pub exit: ProcExit,
+}
+
+// A snapshot of one tracked process, see TrackedProcs::get_proc_statuses()
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ProcStatus {
+  pub state: ProcState,
+  pub pid: Option<u32>,
+  /// How many times the process has been spawned since the server started
+  pub restart_count: u32,
+  /// How many of those spawns were within the last RestartPolicy::window_s seconds
+  pub recent_starts: usize,
+  pub last_exit: Option<ProcExit>,
+  /// With ProcState::BackingOff, how long until the process is spawned again
+  pub next_start_in_ms: Option<u64>,
+  /// What the running process last said it was doing in its heartbeat, eg "loading model" or "idle"
+  pub activity: Option<String>,
+  /// How long ago the running process last beat
+  pub heartbeat_age_ms: Option<u64>,
 }
 
 impl TrackedProcs {
@@ -23,9 +161,11 @@ impl TrackedProcs {
       expected_bin_directory: expected_bin_directory.into(),
       procs: Vec::with_capacity(8),
       tracked_proc_args: Vec::with_capacity(8),
-      sinfo: sysinfo::System::new(),
-      spawned_children: Vec::with_capacity(32),
       procs_should_be_stopped: false,
+      restart_policy: RestartPolicy::from_env(),
+      child_exited: std::sync::Arc::new(tokio::sync::Notify::new()),
+      exit_tx: tokio::sync::broadcast::channel(EXIT_EVENT_CAPACITY).0,
+      shutting_down: false,
     }
   }
 
@@ -35,12 +175,19 @@ impl TrackedProcs {
       expected_bin_directory: std::env::var("OLIANA_BIN_DIR")?.into(),
       procs: Vec::with_capacity(8),
       tracked_proc_args: Vec::with_capacity(8),
-      sinfo: sysinfo::System::new(),
-      spawned_children: Vec::with_capacity(32),
       procs_should_be_stopped: false,
+      restart_policy: RestartPolicy::from_env(),
+      child_exited: std::sync::Arc::new(tokio::sync::Notify::new()),
+      exit_tx: tokio::sync::broadcast::channel(EXIT_EVENT_CAPACITY).0,
+      shutting_down: false,
     })
   }
 
+  // Every exit of a tracked process from now on, with its real exit status
+  pub fn subscribe_exits(&self) -> tokio::sync::broadcast::Receiver<ProcExitEvent> {
+    self.exit_tx.subscribe()
+  }
+
   pub fn register_tracked_proc(&mut self, process_bin_name: &str, process_args: &[&str]) {
     let mut owned_p_args = Vec::with_capacity(process_args.len());
     for arg in process_args {
@@ -51,11 +198,26 @@ impl TrackedProcs {
     );
   }
 
+  // Every registered process gets a chance to start even if an earlier one fails; the first error is returned once all have been tried.
   pub fn ensure_registered_procs_running(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    if self.shutting_down {
+      return Ok(());
+    }
+    let mut first_error: Option<Box<dyn std::error::Error>> = None;
     for i in 0..self.tracked_proc_args.len() {
-      self.ensure_named_proc_running(self.tracked_proc_args[i].0.clone(), self.tracked_proc_args[i].1.clone())?; // TODO engineer those .clone()s out of here!
+      if let Err(e) = self.ensure_named_proc_running(self.tracked_proc_args[i].0.clone(), self.tracked_proc_args[i].1.clone()) { // TODO engineer those .clone()s out of here!
+        if first_error.is_none() {
+          first_error = Some(e);
+        }
+        else {
+          eprintln!("{}:{} {:?}", file!(), line!(), e);
+        }
+      }
+    }
+    match first_error {
+      Some(e) => Err(e),
+      None => Ok(()),
     }
-    Ok(())
   }
 
   pub fn resume_sigstop_procs(&self, resume_for_duration: std::time::Duration) -> Result<(), Box<dyn std::error::Error>> {
@@ -92,38 +254,54 @@ impl TrackedProcs {
   pub fn send_signal_to_children(&self, signal: impl Into<nix::sys::signal::Signal>) -> Result<(), Box<dyn std::error::Error>> {
     let signal = signal.into();
     for i in 0..self.procs.len() {
-      if let Some(pid) = self.procs[i].get_last_expected_pid_fast() {
-        if let Err(e) = nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), signal) {
+      // Processes which exited have no child; their PID may belong to someone else by now
+      if let Some(ref child) = self.procs[i].child {
+        if let Err(e) = child.signal(signal) {
           eprintln!("{}:{} {:?}", file!(), line!(), e);
         }
       }
-      else {
-        // Could not get a FAST pid, so for correctness we'll go ALL THE WAY to the filesystem for it -_-
-        if let Ok(Some(pid)) = self.procs[i].get_expected_pid() {
-          if let Err(e) = nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), signal) {
-            eprintln!("{}:{} {:?}", file!(), line!(), e);
-          }
-        }
-      }
     }
     Ok(())
   }
 
-  // This is called periodically & is responsible for calling .update_proc_output_txt_from_files() on running processes; it has the mutable access to the data to do that.
+  // This is called periodically & is responsible for restarting processes which exited, see RestartPolicy.
   pub fn ensure_named_proc_running(&mut self, process_bin_name: String, process_args: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
     let mut existing_proc_i: Option<usize> = None;
     for i in 0..self.procs.len() {
       if self.procs[i].bin_name == process_bin_name {
         existing_proc_i = Some(i);
-        if let Err(e) = self.procs[i].update_proc_output_txt_from_files() {
-          eprintln!("{}:{} {}", file!(), line!(), e);
-        }
         // Found the process, is it running?
       }
     }
     if let Some(i) = existing_proc_i {
-      if !(self.procs[i].is_running(&mut self.sinfo, &mut self.spawned_children)?) {
-        self.procs[i].spawn_proc(&process_args, &mut self.spawned_children)?;
+      let now = std::time::Instant::now();
+      let proc = &mut self.procs[i];
+      if proc.is_running()? {
+        proc.read_heartbeat_files();
+        if proc.state == ProcState::Starting && proc.is_ready {
+          eprintln!("{} is ready", proc.bin_name);
+          proc.state = ProcState::Healthy;
+          proc.backoff_ms = 0;
+        }
+        if let Some(heartbeat_age) = proc.heartbeat_age() {
+          if heartbeat_age.as_secs() > self.restart_policy.hang_timeout_s {
+            eprintln!("{} has not beaten for {} seconds (last activity {:?}), killing it", proc.bin_name, heartbeat_age.as_secs(), proc.last_heartbeat.as_ref().map(|(info, _)| &info.activity));
+            proc.kill();
+          }
+        }
+      }
+      else {
+        match proc.state {
+          ProcState::Starting | ProcState::Healthy => {
+            proc.begin_backoff(&self.restart_policy);
+          }
+          ProcState::BackingOff => {
+            if proc.next_start_at.map(|t| now >= t).unwrap_or(true) {
+              proc.spawn_proc(&process_args)?;
+            }
+          }
+          ProcState::Failed | ProcState::Stopped => { }
+        }
       }
     }
     else {
@@ -134,20 +312,84 @@ impl TrackedProcs {
         filesystem_bin_path: crate::files::find_newest_mtime_bin_under_folder(&self.expected_bin_directory, &process_bin_name)?,
         filesystem_pid_filepath: self.proc_track_dir.join(format!("{}-pid.txt", process_bin_name)),
         filesystem_stdout_filepath: self.proc_track_dir.join(format!("{}-stdout.txt", process_bin_name)),
-        filesystem_stdout_read_bytes: 0,
         filesystem_stderr_filepath: self.proc_track_dir.join(format!("{}-stderr.txt", process_bin_name)),
-        filesystem_stderr_read_bytes: 0,
+        filesystem_heartbeat_filepath: self.proc_track_dir.join(format!("{}-heartbeat.json", process_bin_name)),
+        filesystem_ready_filepath: self.proc_track_dir.join(format!("{}-ready.json", process_bin_name)),
         proc_restart_count: 0,
-        proc_output_txt: String::new(),
-        last_expected_pid: std::sync::RwLock::new(None)
+        log: std::sync::Arc::new(crate::proc_logs::ProcLog::new(self.restart_policy.log_lines)),
+        log_tasks: vec![],
+        child: None,
+        child_exited: self.child_exited.clone(),
+        exit_tx: self.exit_tx.clone(),
+        state: ProcState::Starting,
+        start_times: std::collections::VecDeque::new(),
+        next_start_at: None,
+        backoff_ms: 0,
+        last_exit: None,
+        last_heartbeat: None,
+        is_ready: false,
       };
-      otp.spawn_proc(&process_args, &mut self.spawned_children)?;
+      stop_stale_proc(&otp.filesystem_pid_filepath, &otp.bin_name, std::time::Duration::from_millis(self.restart_policy.shutdown_grace_ms));
+      otp.spawn_proc(&process_args)?;
       self.procs.push(otp);
     }
 
     Ok(())
   }
 
+  // Returns false if the process has exited OR was never spawned (eg the binary could not be found under expected_bin_directory)
+  pub fn is_named_proc_running(&mut self, process_bin_name: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    for i in 0..self.procs.len() {
+      if self.procs[i].bin_name == process_bin_name {
+        return self.procs[i].is_running();
+      }
+    }
+    Ok(false)
+  }
+
+  // Stops every tracked process: SIGTERM to each one (and its process group, see oliana_lib::child_watch), up to RestartPolicy::shutdown_grace_ms
+  // for them to exit by themselves, then SIGKILL for any which have not. Nothing is spawned again afterwards. Also run on Drop.
+  pub fn shutdown(&mut self) {
+    self.shutting_down = true;
+    for proc in self.procs.iter_mut() {
+      if let Some(ref mut child) = proc.child {
+        eprintln!("Stopping {} (PID {})", proc.bin_name, child.pid);
+        if let Err(e) = child.terminate() {
+          eprintln!("{}:{} {:?}", file!(), line!(), e);
+        }
+      }
+    }
+    let deadline = std::time::Instant::now() + std::time::Duration::from_millis(self.restart_policy.shutdown_grace_ms);
+    loop {
+      let mut any_running = false;
+      for proc in self.procs.iter_mut() {
+        match proc.is_running() {
+          Ok(is_running) => any_running |= is_running,
+          Err(e) => eprintln!("{}:{} {:?}", file!(), line!(), e),
+        }
+      }
+      if !any_running || std::time::Instant::now() >= deadline {
+        break;
+      }
+      std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    for proc in self.procs.iter_mut() {
+      if proc.child.is_some() {
+        eprintln!("{} did not exit within {} ms of SIGTERM, killing it", proc.bin_name, self.restart_policy.shutdown_grace_ms);
+        proc.kill();
+        if let Err(e) = proc.wait_for_exit() {
+          eprintln!("{}:{} {:?}", file!(), line!(), e);
+        }
+      }
+      proc.state = ProcState::Stopped;
+      proc.next_start_at = None;
+    }
+    // So the last lines each process wrote make it into its log and its stdout/stderr files
+    for proc in self.procs.iter_mut() {
+      proc.join_log_tasks(std::time::Duration::from_millis(LOG_TASK_GRACE_MS));
+    }
+  }
+
   pub fn get_proc_restart_counts(&self) -> std::collections::HashMap::<String, u32> {
     let mut hm = std::collections::HashMap::new();
     for i in 0..self.procs.len() {
@@ -156,150 +398,200 @@ impl TrackedProcs {
     hm
   }
 
+  pub fn get_proc_statuses(&self) -> std::collections::HashMap::<String, ProcStatus> {
+    let now = std::time::Instant::now();
+    let mut hm = std::collections::HashMap::new();
+    for i in 0..self.procs.len() {
+      let proc = &self.procs[i];
+      let status = ProcStatus {
+        state: proc.state,
+        pid: if proc.state == ProcState::Starting || proc.state == ProcState::Healthy { proc.pid() } else { None },
+        restart_count: proc.proc_restart_count,
+        recent_starts: proc.start_times.iter().filter(|t| now.duration_since(**t).as_secs() <= self.restart_policy.window_s).count(),
+        last_exit: proc.last_exit.clone(),
+        next_start_in_ms: match (proc.state, proc.next_start_at) {
+          (ProcState::BackingOff, Some(t)) => Some(t.saturating_duration_since(now).as_millis() as u64),
+          _ => None,
+        },
+        activity: proc.last_heartbeat.as_ref().map(|(info, _)| info.activity.clone()),
+        heartbeat_age_ms: proc.heartbeat_age().map(|age| age.as_millis() as u64),
+      };
+      hm.insert(proc.bin_name.clone(), status);
+    }
+    hm
+  }
+
+  // The log of a process, which can be read and subscribed to without holding on to the TrackedProcs
+  pub fn get_proc_log(&self, process_bin_name: &str) -> Option<std::sync::Arc<crate::proc_logs::ProcLog>> {
+    self.procs.iter().find(|proc| proc.bin_name == process_bin_name).map(|proc| proc.log.clone())
+  }
+
+  // The last PROC_OUTPUT_LINES lines of each process, see ProcLog::to_text()
   pub fn get_proc_outputs(&self) -> std::collections::HashMap::<String, String> {
     let mut hm = std::collections::HashMap::new();
     for i in 0..self.procs.len() {
-      hm.insert(self.procs[i].bin_name.clone(), self.procs[i].proc_output_txt.clone());
+      hm.insert(self.procs[i].bin_name.clone(), self.procs[i].log.to_text(PROC_OUTPUT_LINES));
     }
     hm
   }
 
 }
 
+impl Drop for TrackedProcs {
+  fn drop(&mut self) {
+    self.shutdown();
+  }
+}
+
 // This structure exists to store potentially-expensive-to-lookup items once (eg filesystem_bin_path looked up from bin_name)
 pub struct OneTrackedProc {
   pub proc_track_dir: std::path::PathBuf,
   pub bin_name: String,
   pub filesystem_bin_path: std::path::PathBuf,
   pub filesystem_pid_filepath: std::path::PathBuf,
-  pub filesystem_stdout_filepath: std::path::PathBuf,
-  pub filesystem_stdout_read_bytes: usize,
+  pub filesystem_stdout_filepath: std::path::PathBuf, // Mirrors of the current process's output; see oliana_lib::proc_logs
   pub filesystem_stderr_filepath: std::path::PathBuf,
-  pub filesystem_stderr_read_bytes: usize,
+  pub filesystem_heartbeat_filepath: std::path::PathBuf,
+  pub filesystem_ready_filepath: std::path::PathBuf,
   pub proc_restart_count: u32,
-  pub proc_output_txt: String,
-  pub last_expected_pid: std::sync::RwLock::<Option<u32>>,
+  pub log: std::sync::Arc<crate::proc_logs::ProcLog>,
+  pub log_tasks: Vec<tokio::task::JoinHandle<()>>, // The tasks capturing the current (or last) process's stdout and stderr, see join_log_tasks()
+  pub child: Option<crate::child_watch::WatchedChild>, // None once the process has exited
+  pub child_exited: std::sync::Arc<tokio::sync::Notify>,
+  pub exit_tx: tokio::sync::broadcast::Sender<ProcExitEvent>,
+  pub state: ProcState,
+  pub start_times: std::collections::VecDeque<std::time::Instant>, // When the process was spawned within the last RestartPolicy::window_s, oldest first
+  pub next_start_at: Option<std::time::Instant>, // With ProcState::BackingOff
+  pub backoff_ms: u64, // The last backoff waited; doubled for each exit before the process counts as healthy
+  pub last_exit: Option<ProcExit>,
+  pub last_heartbeat: Option<(crate::heartbeat::HeartbeatInfo, std::time::SystemTime)>, // Only ever from the current process; cleared when it exits
+  pub is_ready: bool,
 }
 
 impl OneTrackedProc {
+  // The PID file is only a hint for cleaning up after a server which crashed; the running process is always self.child.
   pub fn get_expected_pid(&self) -> Result<Option<u32>, Box<dyn std::error::Error>> {
     if self.filesystem_pid_filepath.exists() {
       let file_content = std::fs::read_to_string(&self.filesystem_pid_filepath).map_err(crate::err::eloc!())?;
       let pid_num = file_content.parse::<u32>().map_err(crate::err::eloc!())?;
-      if let Ok(mut write_lock) = self.last_expected_pid.write() {
-        *write_lock = Some(pid_num);
-      }
       return Ok(Some(pid_num));
     }
     Ok(None)
   }
 
-  pub fn get_last_expected_pid_fast(&self) -> Option<u32> {
-    let mut result = None;
-    if let Ok(read_lock) = self.last_expected_pid.read() {
-      if let Some(val) = *read_lock {
-        result = Some(val);
-      }
-    }
-    result
-  }
-
-  pub fn is_running(&self, sinfo: &mut sysinfo::System, spawned_child_holder: &mut Vec<std::process::Child>) -> Result<bool, Box<dyn std::error::Error>> {
-    if let Some(pid) = self.get_expected_pid().map_err(crate::err::eloc!())? {
-      //sinfo.refresh_processes(sysinfo::ProcessesToUpdate::Some(&[sysinfo::Pid::from_u32(pid)]), true); // TODO potential future optimization where we don't scan _all_ processes?
-      sinfo.refresh_processes(sysinfo::ProcessesToUpdate::All, true);
-      // If <pid> is no longer in the process list, it exited!
-      // If <pid> is in the process list, return if it's alive or not (which ought to always be true)
-      if let Some(process) = sinfo.process(sysinfo::Pid::from_u32(pid)) {
-        // Process exists, should we reap it?
-        match process.status() {
-          sysinfo::ProcessStatus::Zombie | sysinfo::ProcessStatus::Dead => {
-            // Process _just_ exited, therefore it is _not_ running!
-
-            // Retain all children which are NOT this process.
-            spawned_child_holder.retain_mut(|c| {
-              if c.id() != pid {
-                true
-              }
-              else {
-                // Reap the child process
-                if let Err(e) = c.wait() {
-                  eprintln!("{:?}", e);
-                }
-                false
-              }
-            });
-
-            return Ok(false);
-          }
-          unused => {
-            return Ok(true); // <pid> is still running!
-          }
-        }
-      }
-      else {
-        // If we thought we had a PID and no longer have it, re-scan processes in spawned_child_holder and remove them
-        spawned_child_holder.retain_mut(|c| {
-          match c.try_wait() {
-              Ok(Some(status)) => false, /* remove because exited w/ a code */
-              Ok(None) => {
-                  true /* has yet to exit, keep reference */
-              }
-              Err(e) => {
-                /* misc OS error, keep reference */
-                eprintln!("Within spawned_child_holder.retain_mut: {:?}", e);
-                true
-              },
-          }
-        });
-      }
-    }
+  // None once the process has exited
+  pub fn pid(&self) -> Option<u32> {
+    self.child.as_ref().map(|child| child.pid)
+  }
+
+  // Once the process is seen to have exited it is reaped, its exit status recorded in last_exit and sent to TrackedProcs::subscribe_exits(),
+  // and its pid file removed, so the exit is only recorded once.
+  pub fn is_running(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+    let exit_status = match self.child {
+      Some(ref mut child) => match child.try_exit().map_err(crate::err::eloc!())? {
+        Some(exit_status) => exit_status,
+        None => return Ok(true),
+      },
+      None => return Ok(false),
+    };
+    self.record_exit(exit_status);
     Ok(false)
   }
 
-  pub fn update_proc_output_txt_from_files(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-    use std::io::Write;
+  // Blocks until the process has exited, so only call it after kill(); records the exit like is_running().
+  pub fn wait_for_exit(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(ref mut child) = self.child {
+      let exit_status = child.wait().map_err(crate::err::eloc!())?;
+      self.record_exit(exit_status);
+    }
+    Ok(())
+  }
 
-    // We simply append here, spawn_proc.() will place a "================ PID {} ================" banner across process re-starts.
-    // If length is ever > 32kb, we remove the first 8kb chunk from the beginning of the in-memory string
-    if let Ok(current_pid_stdout) = std::fs::read_to_string(&self.filesystem_stdout_filepath) {
-      if current_pid_stdout.len() > self.filesystem_stdout_read_bytes {
-        let new_stdout = &current_pid_stdout[self.filesystem_stdout_read_bytes..];
-        self.proc_output_txt.push_str(new_stdout);
-        self.filesystem_stdout_read_bytes += current_pid_stdout.len() - self.filesystem_stdout_read_bytes;
-        print!("{}", new_stdout);
-        if let Err(e) = std::io::stdout().flush() {
-            println!("{}:{} {}", file!(), line!(), e);
-        }
-      }
+  fn record_exit(&mut self, exit_status: std::process::ExitStatus) {
+    let pid = self.pid().unwrap_or(0);
+    self.child = None;
+    let last_exit = ProcExit::new(exit_status);
+    eprintln!("{} (PID {}) exited with {}", self.bin_name, pid, last_exit);
+    // Fails only when nobody is subscribed
+    let _ = self.exit_tx.send(ProcExitEvent {
+      bin_name: self.bin_name.clone(),
+      pid: pid,
+      exit: last_exit.clone(),
+    });
+    self.last_exit = Some(last_exit);
+    self.last_heartbeat = None;
+    self.is_ready = false;
+    if let Err(e) = std::fs::remove_file(&self.filesystem_pid_filepath) {
+      eprintln!("{}:{} {}", file!(), line!(), e);
     }
-    if let Ok(current_pid_stderr) = std::fs::read_to_string(&self.filesystem_stderr_filepath) {
-      if current_pid_stderr.len() > self.filesystem_stderr_read_bytes {
-        let new_stderr = &current_pid_stderr[self.filesystem_stderr_read_bytes..];
-        self.proc_output_txt.push_str(new_stderr);
-        self.filesystem_stderr_read_bytes += current_pid_stderr.len() - self.filesystem_stderr_read_bytes;
-        eprint!("{}", new_stderr);
-        if let Err(e) = std::io::stdout().flush() {
-            println!("{}:{} {}", file!(), line!(), e);
-        }
+  }
+
+  // Caches the process's heartbeat and whether it is ready; files written by an earlier process (another PID) are ignored.
+  pub fn read_heartbeat_files(&mut self) {
+    let pid = match self.pid() {
+      Some(pid) => pid,
+      None => return,
+    };
+    self.last_heartbeat = crate::heartbeat::read_info(&self.filesystem_heartbeat_filepath).filter(|(info, _)| info.pid == pid);
+    if !self.is_ready {
+      self.is_ready = crate::heartbeat::read_info(&self.filesystem_ready_filepath).map(|(info, _)| info.pid == pid).unwrap_or(false);
+    }
+  }
+
+  // None until the process has beaten at least once
+  pub fn heartbeat_age(&self) -> Option<std::time::Duration> {
+    let (_info, beat_at) = self.last_heartbeat.as_ref()?;
+    Some(std::time::SystemTime::now().duration_since(*beat_at).unwrap_or(std::time::Duration::ZERO))
+  }
+
+  // Kills the process if we spawned it; is_running() notices the exit and records it like any other.
+  pub fn kill(&mut self) {
+    if let Some(ref mut child) = self.child {
+      if let Err(e) = child.kill() {
+        eprintln!("{}:{} {:?}", file!(), line!(), e);
       }
     }
+    // Until it has exited, do not act on the stale heartbeat again
+    self.last_heartbeat = None;
+  }
 
-    if self.proc_output_txt.len() > 32 * 1024 {
-      let mut closest_char_idx = 8192;
-      for (char_idx, _c) in self.proc_output_txt.char_indices() {
-        if char_idx > closest_char_idx {
-          closest_char_idx = char_idx;
-          break;
-        }
+  // Waits up to grace for the log tasks to read what the process wrote before it exited, then stops any still running, which only happens
+  // if something the process started holds on to its stdout or stderr. Blocks the calling thread, so keep grace short on a runtime thread.
+  pub fn join_log_tasks(&mut self, grace: std::time::Duration) {
+    let deadline = std::time::Instant::now() + grace;
+    while self.log_tasks.iter().any(|task| !task.is_finished()) && std::time::Instant::now() < deadline {
+      std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    for task in self.log_tasks.drain(..) {
+      if !task.is_finished() {
+        eprintln!("Stopping the capture of {}'s output, which is still open after it exited", self.bin_name);
+        task.abort();
       }
-      self.proc_output_txt = self.proc_output_txt[closest_char_idx..].to_string();
     }
+  }
 
-    Ok(())
+  // Called once the process has been seen to exit; schedules its next start, or gives up on it if it has been started too often recently.
+  pub fn begin_backoff(&mut self, restart_policy: &RestartPolicy) {
+    let now = std::time::Instant::now();
+    while self.start_times.front().map(|t| now.duration_since(*t).as_secs() > restart_policy.window_s).unwrap_or(false) {
+      self.start_times.pop_front();
+    }
+    if self.start_times.len() >= restart_policy.max_starts_in_window {
+      eprintln!("{} was started {} times within {} seconds, not starting it again", self.bin_name, self.start_times.len(), restart_policy.window_s);
+      self.state = ProcState::Failed;
+      self.next_start_at = None;
+      return;
+    }
+    self.backoff_ms = match self.backoff_ms {
+      0 => restart_policy.initial_backoff_ms,
+      backoff_ms => std::cmp::min(backoff_ms.saturating_mul(2), restart_policy.max_backoff_ms),
+    };
+    eprintln!("Starting {} again in {} ms", self.bin_name, self.backoff_ms);
+    self.state = ProcState::BackingOff;
+    self.next_start_at = Some(now + std::time::Duration::from_millis(self.backoff_ms));
   }
 
-  pub fn spawn_proc(&mut self, args: &Vec<String>, spawned_child_holder: &mut Vec<std::process::Child>) -> Result<(), Box<dyn std::error::Error>> {
+  pub fn spawn_proc(&mut self, args: &Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
 
     let debug_process_line = format!("{} {}", self.filesystem_bin_path.display(), args.join(" "));
     eprintln!("Spawning the process: {debug_process_line}");
@@ -314,15 +606,25 @@ impl OneTrackedProc {
         eprintln!("{}:{} {}", file!(), line!(), e);
       }
     }
+    for heartbeat_filepath in [&self.filesystem_heartbeat_filepath, &self.filesystem_ready_filepath] {
+      if heartbeat_filepath.exists() {
+        if let Err(e) = std::fs::remove_file(heartbeat_filepath) {
+          eprintln!("{}:{} {}", file!(), line!(), e);
+        }
+      }
+    }
 
-    let child_stdout = std::fs::File::create(&self.filesystem_stdout_filepath)?; // We will have to remember to regularly read from these and eprintln!() + write to self.proc_output_txt
-    let child_stderr = std::fs::File::create(&self.filesystem_stderr_filepath)?;
+    let stdout_file = std::fs::File::create(&self.filesystem_stdout_filepath)?;
+    let stderr_file = std::fs::File::create(&self.filesystem_stderr_filepath)?;
 
-    let child = std::process::Command::new(&self.filesystem_bin_path)
+    let mut child = crate::child_watch::prepare_command(&mut std::process::Command::new(&self.filesystem_bin_path))
                   .args(args)
+                  .env(crate::heartbeat::HEARTBEAT_FILE_ENV, &self.filesystem_heartbeat_filepath)
+                  .env(crate::heartbeat::READY_FILE_ENV, &self.filesystem_ready_filepath)
+                  .env("PYTHONUNBUFFERED", "1") // Otherwise python holds on to its output until a pipe-sized block is full
                   .stdin(std::process::Stdio::null())
-                  .stdout(child_stdout)
-                  .stderr(child_stderr)
+                  .stdout(std::process::Stdio::piped())
+                  .stderr(std::process::Stdio::piped())
                   .spawn().map_err(crate::err::eloc!())?;
 
     if let Some(dirname) = self.filesystem_pid_filepath.parent() {
@@ -333,8 +635,15 @@ impl OneTrackedProc {
 
     let pid = child.id();
 
-    if let Ok(mut write_lock) = self.last_expected_pid.write() {
-      *write_lock = Some(pid);
+    // The tasks end once the process closes its end of the pipes; a task of the last process still running now is stopped first
+    self.join_log_tasks(std::time::Duration::ZERO);
+    if let Some(child_stdout) = child.stdout.take() {
+      let child_stdout = tokio::process::ChildStdout::from_std(child_stdout).map_err(crate::err::eloc!())?;
+      self.log_tasks.push(crate::proc_logs::ProcLog::capture(self.log.clone(), pid, crate::proc_logs::LogStream::Stdout, child_stdout, stdout_file));
+    }
+    if let Some(child_stderr) = child.stderr.take() {
+      let child_stderr = tokio::process::ChildStderr::from_std(child_stderr).map_err(crate::err::eloc!())?;
+      self.log_tasks.push(crate::proc_logs::ProcLog::capture(self.log.clone(), pid, crate::proc_logs::LogStream::Stderr, child_stderr, stderr_file));
     }
 
     let pid_file_content = format!("{pid}");
@@ -344,13 +653,126 @@ impl OneTrackedProc {
     std::fs::write(&self.filesystem_pid_filepath, pid_file_content).map_err(crate::err::eloc!())?;
 
     self.proc_restart_count += 1;
-    self.proc_output_txt.push_str(&format!("================ PID {pid} ================\n"));
-    self.filesystem_stdout_read_bytes = 0;
-    self.filesystem_stderr_read_bytes = 0;
+    let now = std::time::Instant::now();
+    self.start_times.push_back(now);
+    self.next_start_at = None;
+    self.state = ProcState::Starting;
+    self.last_heartbeat = None;
+    self.is_ready = false;
 
-    spawned_child_holder.push(child);
+    self.child = Some(crate::child_watch::WatchedChild::new(child, self.child_exited.clone()));
 
     Ok(())
   }
 }
 
+
+// Stops what a PID file left behind by a server which crashed (or was killed) says is still running, together with its process group, then
+// removes the PID file: SIGTERM, up to grace for it to exit, then SIGKILL. PIDs get reused, so a process which is not running bin_name is left alone.
+pub fn stop_stale_proc(pid_filepath: &std::path::Path, bin_name: &str, grace: std::time::Duration) {
+  let pid = match std::fs::read_to_string(pid_filepath) {
+    Ok(file_content) => file_content.trim().parse::<u32>().ok(),
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+    Err(e) => {
+      eprintln!("{}:{} {:?}", file!(), line!(), e);
+      return;
+    }
+  };
+  if let Some(pid) = pid {
+    let mut sinfo = sysinfo::System::new();
+    if is_running_bin(&mut sinfo, pid, bin_name) {
+      eprintln!("Stopping {} (PID {}) which an earlier server left running", bin_name, pid);
+      signal_stale_proc(&sinfo, pid, sysinfo::Signal::Term);
+      let deadline = std::time::Instant::now() + grace;
+      while std::time::Instant::now() < deadline && is_running_bin(&mut sinfo, pid, bin_name) {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+      }
+      if is_running_bin(&mut sinfo, pid, bin_name) {
+        eprintln!("{} (PID {}) did not exit within {} ms of SIGTERM, killing it", bin_name, pid, grace.as_millis());
+        signal_stale_proc(&sinfo, pid, sysinfo::Signal::Kill);
+      }
+    }
+    else {
+      #[cfg(target_os = "linux")]
+      kill_orphaned_group(&sinfo, pid, bin_name);
+    }
+  }
+  if let Err(e) = std::fs::remove_file(pid_filepath) {
+    eprintln!("{}:{} {}", file!(), line!(), e);
+  }
+}
+
+// stop_stale_proc() for every "{bin_name}-pid.txt" file in proc_track_dir, for when the server itself could not be stopped cleanly.
+pub fn stop_stale_procs(proc_track_dir: &std::path::Path, grace: std::time::Duration) {
+  let dirents = match std::fs::read_dir(proc_track_dir) {
+    Ok(dirents) => dirents,
+    Err(e) => {
+      eprintln!("{}:{} {:?}", file!(), line!(), e);
+      return;
+    }
+  };
+  for dirent in dirents.flatten() {
+    let file_name = dirent.file_name().to_string_lossy().to_string();
+    if let Some(bin_name) = file_name.strip_suffix("-pid.txt").or_else(|| file_name.strip_suffix("-pid.TXT")) {
+      stop_stale_proc(&dirent.path(), bin_name, grace);
+    }
+  }
+}
+
+// Zombies count as gone; they only wait for init to reap them.
+fn is_running_bin(sinfo: &mut sysinfo::System, pid: u32, bin_name: &str) -> bool {
+  let sys_pid = sysinfo::Pid::from_u32(pid);
+  sinfo.refresh_processes(sysinfo::ProcessesToUpdate::Some(&[sys_pid]), true);
+  let process = match sinfo.process(sys_pid) {
+    Some(process) => process,
+    None => return false,
+  };
+  if process.status() == sysinfo::ProcessStatus::Zombie {
+    return false;
+  }
+  // The name may be truncated (15 bytes on Linux) and the exe of a since-rebuilt binary ends in " (deleted)", so compare file stems of each
+  let is_bin_path = |path: &std::path::Path| path.file_stem().map(|stem| stem.to_string_lossy().trim_end_matches(" (deleted)") == bin_name).unwrap_or(false);
+  process.exe().map(is_bin_path).unwrap_or(false)
+    || process.cmd().first().map(|arg0| is_bin_path(std::path::Path::new(arg0))).unwrap_or(false)
+    || process.name().to_string_lossy() == bin_name
+}
+
+// PR_SET_PDEATHSIG only reaches the backend itself, so whatever it started (eg pip) can outlive it in its process group. The kernel does not
+// hand out a PID while it is still a process group's ID, so if nothing runs as pid but the group exists, the group is what is left of bin_name.
+#[cfg(target_os = "linux")]
+fn kill_orphaned_group(sinfo: &sysinfo::System, pid: u32, bin_name: &str) {
+  let nix_pid = nix::unistd::Pid::from_raw(pid as i32);
+  let pid_is_free = match sinfo.process(sysinfo::Pid::from_u32(pid)) {
+    Some(process) => process.status() == sysinfo::ProcessStatus::Zombie,
+    None => true,
+  };
+  if pid_is_free && nix::sys::signal::killpg(nix_pid, None).is_ok() {
+    eprintln!("Killing what {} (PID {}) left running in its process group", bin_name, pid);
+    if let Err(e) = nix::sys::signal::killpg(nix_pid, nix::sys::signal::Signal::SIGKILL) {
+      eprintln!("{}:{} {:?}", file!(), line!(), e);
+    }
+  }
+}
+
+fn signal_stale_proc(sinfo: &sysinfo::System, pid: u32, signal: sysinfo::Signal) {
+  // Backends are the leaders of their own process groups, so the whole group goes (see oliana_lib::child_watch)
+  #[cfg(target_os = "linux")]
+  {
+    let nix_pid = nix::unistd::Pid::from_raw(pid as i32);
+    let nix_signal = match signal {
+      sysinfo::Signal::Term => nix::sys::signal::Signal::SIGTERM,
+      _ => nix::sys::signal::Signal::SIGKILL,
+    };
+    if nix::unistd::getpgid(Some(nix_pid)) == Ok(nix_pid) {
+      if let Err(e) = nix::sys::signal::killpg(nix_pid, nix_signal) {
+        eprintln!("{}:{} {:?}", file!(), line!(), e);
+      }
+      return;
+    }
+  }
+  if let Some(process) = sinfo.process(sysinfo::Pid::from_u32(pid)) {
+    if process.kill_with(signal).is_none() {
+      eprintln!("{}:{} cannot send {:?} to PID {} on this platform", file!(), line!(), signal, pid);
+    }
+  }
+}
@@ -6,6 +6,13 @@ pub mod files;
 pub mod misc;
 pub mod launchers;
 pub mod build_meta;
+pub mod jobs;
+pub mod watch;
+pub mod heartbeat;
+pub mod proc_logs;
+pub mod child_watch;
+pub mod segment;
+pub mod png_text;
 
 #[cfg(target_os = "linux")]
 pub use nix;
@@ -0,0 +1,105 @@
+// Keeps a generated image's provenance inside the image itself, as PNG text chunks, so it survives being copied out of a work directory.
+//
+// Backends add generation_text() to each NAME.png before renaming it into place (see oliana_lib::jobs). Values which are plain ASCII are
+// written as tEXt chunks and everything else (eg a prompt with accents or emoji) as UTF-8 iTXt chunks. read_text() returns both kinds,
+// plus the compressed zTXt chunks other tools may write: all tEXt chunks first, then zTXt, then iTXt.
+
+// Keywords written by generation_text(); "Software" and "Creation Time" are keywords the PNG spec defines, so image viewers show them too.
+pub const PROMPT_KEYWORD: &str = "prompt";
+pub const NEGATIVE_PROMPT_KEYWORD: &str = "negative_prompt";
+pub const SEED_KEYWORD: &str = "seed";
+pub const NUM_INFERENCE_STEPS_KEYWORD: &str = "num_inference_steps";
+pub const GUIDANCE_SCALE_KEYWORD: &str = "guidance_scale";
+pub const MODEL_KEYWORD: &str = "model";
+pub const MODE_KEYWORD: &str = "mode";
+pub const STRENGTH_KEYWORD: &str = "strength";
+pub const SOFTWARE_KEYWORD: &str = "Software";
+pub const CREATION_TIME_KEYWORD: &str = "Creation Time";
+
+// What made image image_i of image_job; model is the checkpoint (or algorithm) the backend drew it with.
+// Img2Img and Inpaint jobs also record their mode and strength, though not the init image itself.
+// The seed is left out when the job has none, as the backend then picked one we cannot know.
+pub fn generation_text(image_job: &crate::jobs::ImageJob, image_i: u32, model: &str) -> Vec<(String, String)> {
+  let mut text = vec![
+    (PROMPT_KEYWORD.to_string(), image_job.prompt.clone()),
+    (NEGATIVE_PROMPT_KEYWORD.to_string(), image_job.negative_prompt.clone()),
+  ];
+  if let Some(seed) = image_job.options.seed {
+    text.push((SEED_KEYWORD.to_string(), seed.wrapping_add(image_i as u64).to_string()));
+  }
+  text.push((NUM_INFERENCE_STEPS_KEYWORD.to_string(), image_job.num_inference_steps.to_string()));
+  text.push((GUIDANCE_SCALE_KEYWORD.to_string(), image_job.guidance_scale.to_string()));
+  if image_job.mode != crate::jobs::ImageMode::Text {
+    text.push((MODE_KEYWORD.to_string(), image_job.mode.to_string()));
+    text.push((STRENGTH_KEYWORD.to_string(), image_job.strength.to_string()));
+  }
+  text.push((MODEL_KEYWORD.to_string(), model.to_string()));
+  text.push((SOFTWARE_KEYWORD.to_string(), format!("Oliana {}", env!("CARGO_PKG_VERSION"))));
+  let now_unix_s = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+  text.push((CREATION_TIME_KEYWORD.to_string(), rfc3339_utc(now_unix_s)));
+  text
+}
+
+// Every text chunk in png_file as (keyword, text), see the order above.
+pub fn read_text(png_file: &std::path::Path) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+  let (info, _pixels) = decode(png_file)?;
+  let mut text = vec![];
+  for chunk in info.uncompressed_latin1_text.iter() {
+    text.push((chunk.keyword.clone(), chunk.text.clone()));
+  }
+  for chunk in info.compressed_latin1_text.iter() {
+    text.push((chunk.keyword.clone(), chunk.get_text()?));
+  }
+  for chunk in info.utf8_text.iter() {
+    text.push((chunk.keyword.clone(), chunk.get_text()?));
+  }
+  Ok(text)
+}
+
+// Re-writes png_file with text added after any chunks it already has. Keywords must be 1 to 79 Latin-1 characters.
+// Backends call this on their temporary file, so readers of NAME.png never see it half-written.
+pub fn add_text(png_file: &std::path::Path, text: &[(String, String)]) -> Result<(), Box<dyn std::error::Error>> {
+  let (mut info, pixels) = decode(png_file)?;
+  for (keyword, value) in text.iter() {
+    if keyword.len() < 1 || keyword.len() > 79 || !keyword.chars().all(|c| (c as u32) >= 32 && (c as u32) < 256) {
+      return Err(format!("PNG text keywords must be 1 to 79 Latin-1 characters, got {:?}", keyword).into());
+    }
+    if value.is_ascii() {
+      info.uncompressed_latin1_text.push(png::text_metadata::TEXtChunk::new(keyword.clone(), value.clone()));
+    }
+    else {
+      info.utf8_text.push(png::text_metadata::ITXtChunk::new(keyword.clone(), value.clone()));
+    }
+  }
+  let png_fd = std::io::BufWriter::new(std::fs::File::create(png_file)?);
+  let mut png_writer = png::Encoder::with_info(png_fd, info)?.write_header()?;
+  png_writer.write_image_data(&pixels)?;
+  png_writer.finish()?;
+  Ok(())
+}
+
+// Reads all of png_file, as text chunks may come after the image data; the pixels are left exactly as stored so add_text() can write them back.
+fn decode(png_file: &std::path::Path) -> Result<(png::Info<'static>, Vec<u8>), Box<dyn std::error::Error>> {
+  let png_fd = std::io::BufReader::new(std::fs::File::open(png_file)?);
+  let mut png_reader = png::Decoder::new(png_fd).read_info()?;
+  let mut pixels = vec![0; png_reader.output_buffer_size().ok_or("PNG is too large to decode")?];
+  png_reader.next_frame(&mut pixels)?;
+  png_reader.finish()?;
+  Ok((png_reader.info().clone(), pixels))
+}
+
+// eg "2024-11-30T18:04:05Z"; days are converted to a date with Howard Hinnant's civil_from_days() so we need no date-time crate.
+fn rfc3339_utc(unix_s: u64) -> String {
+  let days = (unix_s / 86400) as i64;
+  let secs_of_day = unix_s % 86400;
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let day_of_era = z.rem_euclid(146097);
+  let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let shifted_month = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+  let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+  let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+  format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs_of_day / 3600, (secs_of_day / 60) % 60, secs_of_day % 60)
+}
@@ -0,0 +1,182 @@
+// Captures what a tracked process (see launchers::TrackedProcs) writes to stdout and stderr.
+//
+// Each stream is a pipe read by its own tokio task, so nothing is lost or re-read while the server is busy: bytes are mirrored
+// unchanged to the stream's file under the tracked-proc dir and echoed to our own stdout/stderr, and every line becomes a LogLine
+// in the process's ProcLog. A ProcLog keeps the last ring_capacity_lines lines of each stream across restarts of the process,
+// numbered by seq so a reader can ask for only the lines after the last one it saw, and publishes every new line to subscribers.
+//
+// Lines end at '\n' or '\r' (progress bars re-draw themselves with '\r'), and lines longer than MAX_LINE_BYTES are split at a
+// character boundary, so a line is always valid UTF-8 (anything which is not becomes U+FFFD).
+
+pub const DEFAULT_RING_CAPACITY_LINES: usize = 2000;
+pub const MAX_LINE_BYTES: usize = 16 * 1024;
+// How many lines a slow subscriber may fall behind before it misses some; it can catch up from ProcLog::lines_after().
+pub const SUBSCRIBER_CAPACITY_LINES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogStream {
+  Stdout,
+  Stderr,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LogLine {
+  /// Counts up from 1 for every line of the process, across both streams and restarts
+  pub seq: u64,
+  pub pid: u32,
+  pub stream: LogStream,
+  pub unix_ms: u64,
+  pub text: String,
+}
+
+pub struct ProcLog {
+  pub ring_capacity_lines: usize,
+  lines: std::sync::Mutex<ProcLogLines>,
+  line_tx: tokio::sync::broadcast::Sender<LogLine>,
+}
+
+#[derive(Default)]
+struct ProcLogLines {
+  last_seq: u64,
+  stdout: std::collections::VecDeque<LogLine>,
+  stderr: std::collections::VecDeque<LogLine>,
+}
+
+impl ProcLog {
+  pub fn new(ring_capacity_lines: usize) -> Self {
+    let (line_tx, _line_rx) = tokio::sync::broadcast::channel(SUBSCRIBER_CAPACITY_LINES);
+    Self {
+      ring_capacity_lines: ring_capacity_lines,
+      lines: std::sync::Mutex::new(ProcLogLines::default()),
+      line_tx: line_tx,
+    }
+  }
+
+  pub fn push(&self, pid: u32, stream: LogStream, text: String) {
+    let line = match self.lines.lock() {
+      Ok(mut lines) => {
+        lines.last_seq += 1;
+        let line = LogLine {
+          seq: lines.last_seq,
+          pid: pid,
+          stream: stream,
+          unix_ms: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0),
+          text: text,
+        };
+        let ring = match stream {
+          LogStream::Stdout => &mut lines.stdout,
+          LogStream::Stderr => &mut lines.stderr,
+        };
+        ring.push_back(line.clone());
+        while ring.len() > self.ring_capacity_lines {
+          ring.pop_front();
+        }
+        line
+      }
+      Err(e) => {
+        eprintln!("{}:{} {:?}", file!(), line!(), e);
+        return;
+      }
+    };
+    // Fails only when nobody is subscribed, which is fine
+    let _ = self.line_tx.send(line);
+  }
+
+  // Lines still in the ring buffers with a seq greater than after_seq, oldest first, at most max_lines of the newest ones.
+  pub fn lines_after(&self, after_seq: u64, max_lines: usize) -> Vec<LogLine> {
+    let mut result: Vec<LogLine> = match self.lines.lock() {
+      Ok(lines) => lines.stdout.iter().chain(lines.stderr.iter()).filter(|line| line.seq > after_seq).cloned().collect(),
+      Err(e) => {
+        eprintln!("{}:{} {:?}", file!(), line!(), e);
+        vec![]
+      }
+    };
+    result.sort_by_key(|line| line.seq);
+    if result.len() > max_lines {
+      result.drain(..result.len() - max_lines);
+    }
+    result
+  }
+
+  // Every line pushed from now on; subscribe before calling lines_after() to be sure no line falls between the two.
+  pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<LogLine> {
+    self.line_tx.subscribe()
+  }
+
+  // The last max_lines lines as one string, with a "================ PID {} ================" banner wherever the process was restarted.
+  pub fn to_text(&self, max_lines: usize) -> String {
+    let mut text = String::new();
+    let mut last_pid: Option<u32> = None;
+    for line in self.lines_after(0, max_lines) {
+      if last_pid != Some(line.pid) {
+        text.push_str(&format!("================ PID {} ================\n", line.pid));
+        last_pid = Some(line.pid);
+      }
+      text.push_str(&line.text);
+      text.push('\n');
+    }
+    text
+  }
+
+  // Spawns a tokio task, so call it from within the runtime, which reads reader until the process closes it (see the top of this file).
+  // The task ends by itself once the process and whatever it started have exited; see OneTrackedProc::log_tasks.
+  pub fn capture(proc_log: std::sync::Arc<ProcLog>, pid: u32, stream: LogStream, reader: impl tokio::io::AsyncRead + Send + Unpin + 'static, disk_file: std::fs::File) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+      use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+      let mut reader = tokio::io::BufReader::new(reader);
+      let mut disk_file = tokio::fs::File::from_std(disk_file);
+      let mut line: Vec<u8> = Vec::with_capacity(256);
+      let mut last_byte: u8 = 0;
+      let emit = |line: &mut Vec<u8>| {
+        let text = String::from_utf8_lossy(line).to_string();
+        match stream {
+          LogStream::Stdout => println!("{}", text),
+          LogStream::Stderr => eprintln!("{}", text),
+        }
+        proc_log.push(pid, stream, text);
+        line.clear();
+      };
+      loop {
+        let buf = match reader.fill_buf().await {
+          Ok(buf) => buf,
+          Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+          Err(e) => {
+            eprintln!("{}:{} {:?}", file!(), line!(), e);
+            break;
+          }
+        };
+        if buf.len() < 1 {
+          break; // The process closed the stream, usually because it exited
+        }
+        for &byte in buf.iter() {
+          if byte == b'\n' || byte == b'\r' {
+            // "\r\n" ends one line, not two
+            if !(byte == b'\n' && last_byte == b'\r' && line.len() < 1) {
+              emit(&mut line);
+            }
+          }
+          else {
+            // Only split before the first byte of a character
+            if line.len() >= MAX_LINE_BYTES && (byte & 0xC0) != 0x80 {
+              emit(&mut line);
+            }
+            line.push(byte);
+          }
+          last_byte = byte;
+        }
+        if let Err(e) = disk_file.write_all(buf).await {
+          eprintln!("{}:{} {:?}", file!(), line!(), e);
+        }
+        let num_bytes = buf.len();
+        reader.consume(num_bytes);
+      }
+      if line.len() > 0 {
+        emit(&mut line);
+      }
+      if let Err(e) = disk_file.flush().await {
+        eprintln!("{}:{} {:?}", file!(), line!(), e);
+      }
+    })
+  }
+}
@@ -0,0 +1,105 @@
+// Separates an image's subject from its background without a model, for ImageJobs with transparent_background set.
+//
+// The background is taken to be the most common color along the image's border; every pixel reachable from the border through
+// pixels of roughly that color becomes fully transparent, and pixels touching it which are only a little further from that color
+// are made partly transparent so the subject's outline is not left with a halo. This works well for subjects on a plain backdrop,
+// which is what backends ask their models for when transparent_background is set, and is what oliana_images falls back to when
+// its segmentation model cannot be loaded.
+
+// How far (in the largest difference of any one channel) a pixel may be from the background color and still be background.
+pub const DEFAULT_TOLERANCE: u8 = 32;
+
+// The average of the border pixels in the most common color bucket (16 levels per channel) among all border pixels.
+pub fn border_color(image: &image::RgbaImage) -> image::Rgba<u8> {
+  let mut bucket_counts: std::collections::HashMap<[u8; 3], (u64, [u64; 3])> = std::collections::HashMap::new();
+  for (x, y) in border_pixels(image.width(), image.height()) {
+    let pixel = image.get_pixel(x, y);
+    let bucket = bucket_counts.entry([pixel.0[0] >> 4, pixel.0[1] >> 4, pixel.0[2] >> 4]).or_insert((0, [0; 3]));
+    bucket.0 += 1;
+    for c in 0..3 {
+      bucket.1[c] += pixel.0[c] as u64;
+    }
+  }
+  match bucket_counts.values().max_by_key(|(count, _)| *count) {
+    Some((count, sums)) => image::Rgba([(sums[0] / count) as u8, (sums[1] / count) as u8, (sums[2] / count) as u8, 255]),
+    None => image::Rgba([255, 255, 255, 255]), // A 0x0 image
+  }
+}
+
+// Makes the background of image transparent in place, returning how many pixels became fully transparent.
+pub fn flood_fill_background(image: &mut image::RgbaImage, tolerance: u8) -> usize {
+  let (width, height) = image.dimensions();
+  let background = border_color(image);
+  let distance = |pixel: &image::Rgba<u8>| (0..3).map(|c| (pixel.0[c] as i32 - background.0[c] as i32).unsigned_abs()).max().unwrap_or(0);
+
+  let mut is_background = vec![false; (width as usize) * (height as usize)];
+  let mut to_visit: std::collections::VecDeque<(u32, u32)> = std::collections::VecDeque::new();
+  for (x, y) in border_pixels(width, height) {
+    let i = (y * width + x) as usize;
+    if !is_background[i] && distance(image.get_pixel(x, y)) <= tolerance as u32 {
+      is_background[i] = true;
+      to_visit.push_back((x, y));
+    }
+  }
+  while let Some((x, y)) = to_visit.pop_front() {
+    for (nx, ny) in neighbors(x, y, width, height) {
+      let i = (ny * width + nx) as usize;
+      if !is_background[i] && distance(image.get_pixel(nx, ny)) <= tolerance as u32 {
+        is_background[i] = true;
+        to_visit.push_back((nx, ny));
+      }
+    }
+  }
+
+  let mut num_transparent = 0;
+  for y in 0..height {
+    for x in 0..width {
+      let i = (y * width + x) as usize;
+      if is_background[i] {
+        image.get_pixel_mut(x, y).0[3] = 0;
+        num_transparent += 1;
+      }
+      else if neighbors(x, y, width, height).any(|(nx, ny)| is_background[(ny * width + nx) as usize]) {
+        // Edge pixels within twice the tolerance are a blend of subject and background; fade them in proportion
+        let pixel = image.get_pixel_mut(x, y);
+        let edge_distance = distance(pixel).saturating_sub(tolerance as u32);
+        if edge_distance < (tolerance as u32).max(1) {
+          pixel.0[3] = ((pixel.0[3] as u32 * edge_distance) / (tolerance as u32).max(1)) as u8;
+        }
+      }
+    }
+  }
+  num_transparent
+}
+
+// Used by backends which save an RGB PNG first: re-writes png_file as RGBA with its background removed, unless it already has
+// transparent pixels (eg because a segmentation model got to it first). Returns how many pixels were made transparent.
+// Re-encoding drops every text chunk, so those already in png_file (eg from png_text::add_text()) are written back afterwards;
+// zTXt chunks come back uncompressed, as tEXt or iTXt.
+pub fn make_background_transparent(png_file: &std::path::Path, tolerance: u8) -> Result<usize, Box<dyn std::error::Error>> {
+  let decoded = image::ImageReader::open(png_file)?.with_guessed_format()?.decode()?;
+  let mut image = decoded.to_rgba8();
+  if decoded.color().has_alpha() && image.pixels().any(|pixel| pixel.0[3] < 255) {
+    return Ok(0);
+  }
+  let text = crate::png_text::read_text(png_file)?;
+  let num_transparent = flood_fill_background(&mut image, tolerance);
+  image.save_with_format(png_file, image::ImageFormat::Png)?;
+  if text.len() > 0 {
+    crate::png_text::add_text(png_file, &text)?;
+  }
+  Ok(num_transparent)
+}
+
+// Every pixel on the edge of a width x height image, each once.
+fn border_pixels(width: u32, height: u32) -> impl Iterator<Item = (u32, u32)> {
+  (0..height).flat_map(move |y| {
+    let is_edge_row = y == 0 || y + 1 == height;
+    (0..width).filter(move |x| is_edge_row || *x == 0 || *x + 1 == width).map(move |x| (x, y))
+  })
+}
+
+// The up to 4 pixels sharing an edge with x,y
+fn neighbors(x: u32, y: u32, width: u32, height: u32) -> impl Iterator<Item = (u32, u32)> {
+  [(x.wrapping_sub(1), y), (x + 1, y), (x, y.wrapping_sub(1)), (x, y + 1)].into_iter().filter(move |(nx, ny)| *nx < width && *ny < height)
+}
@@ -0,0 +1,147 @@
+// Lets oliana_server and the backends sleep until something changes in a work directory instead of polling it.
+//
+// A WorkdirWatcher asks the OS for change events (inotify on linux) and falls back to notify's PollWatcher
+// when that is not possible, eg when the inotify watch limit is exhausted or OLIANA_WATCH_POLL=1 is set for
+// network filesystems which never deliver events. Every event bumps a generation counter; each waiter holds a
+// WorkdirSubscription which remembers the last generation it saw, so a change that lands between "check the files"
+// and "wait" still wakes the waiter straight away.
+//
+// Events only say "look again", callers always re-read the job files (see oliana_lib::jobs) after waking.
+
+// How often the polling fallback re-scans watched directories.
+pub const POLL_FALLBACK_INTERVAL_MS: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchBackend {
+  // The OS delivers change events; inotify on linux
+  Native,
+  // notify's PollWatcher re-scans the directories every POLL_FALLBACK_INTERVAL_MS
+  Polling,
+  // Nothing is watched; waiters only wake for poke() or their timeout
+  Unwatched,
+}
+
+struct WatchGeneration {
+  generation: std::sync::Mutex<u64>,
+  changed: std::sync::Condvar,
+  generation_tx: tokio::sync::watch::Sender<u64>,
+}
+
+impl WatchGeneration {
+  fn bump(&self) {
+    let mut generation = self.generation.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    *generation = generation.wrapping_add(1);
+    self.generation_tx.send_replace(*generation);
+    self.changed.notify_all();
+  }
+  fn current(&self) -> u64 {
+    *self.generation.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+}
+
+pub struct WorkdirWatcher {
+  pub backend: WatchBackend,
+  shared: std::sync::Arc<WatchGeneration>,
+  // Kept alive for as long as we want events; the Mutex is only there to make WorkdirWatcher Sync.
+  _os_watcher: std::sync::Mutex<Option<Box<dyn notify::Watcher + Send>>>,
+}
+
+impl WorkdirWatcher {
+  // Watches the top level of every directory in dirs, creating any that do not exist yet.
+  pub fn new<P: AsRef<std::path::Path>>(dirs: &[P]) -> Result<Self, Box<dyn std::error::Error>> {
+    let (generation_tx, _generation_rx) = tokio::sync::watch::channel(0);
+    let shared = std::sync::Arc::new(WatchGeneration {
+      generation: std::sync::Mutex::new(0),
+      changed: std::sync::Condvar::new(),
+      generation_tx: generation_tx,
+    });
+    for dir in dirs.iter() {
+      std::fs::create_dir_all(dir.as_ref())?;
+    }
+
+    let force_polling = std::env::var("OLIANA_WATCH_POLL").map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(false);
+    if !force_polling {
+      match Self::watch_dirs::<notify::RecommendedWatcher, P>(dirs, &shared, notify::Config::default()) {
+        Ok(os_watcher) => {
+          return Ok(Self { backend: WatchBackend::Native, shared: shared, _os_watcher: std::sync::Mutex::new(Some(os_watcher)) });
+        }
+        Err(e) => {
+          eprintln!("{}:{} {:?}, falling back to polling every {}ms", file!(), line!(), e, POLL_FALLBACK_INTERVAL_MS);
+        }
+      }
+    }
+
+    let poll_config = notify::Config::default().with_poll_interval(std::time::Duration::from_millis(POLL_FALLBACK_INTERVAL_MS));
+    let os_watcher = Self::watch_dirs::<notify::PollWatcher, P>(dirs, &shared, poll_config)?;
+    Ok(Self { backend: WatchBackend::Polling, shared: shared, _os_watcher: std::sync::Mutex::new(Some(os_watcher)) })
+  }
+
+  fn watch_dirs<W: notify::Watcher + Send + 'static, P: AsRef<std::path::Path>>(dirs: &[P], shared: &std::sync::Arc<WatchGeneration>, config: notify::Config) -> notify::Result<Box<dyn notify::Watcher + Send>> {
+    let event_shared = shared.clone();
+    // Errors wake waiters too, so they re-read the directory rather than trusting a watch which may have missed something
+    let mut os_watcher = W::new(move |_event: notify::Result<notify::Event>| event_shared.bump(), config)?;
+    for dir in dirs.iter() {
+      os_watcher.watch(dir.as_ref(), notify::RecursiveMode::NonRecursive)?;
+    }
+    Ok(Box::new(os_watcher))
+  }
+
+  // Wakes every waiter as if a watched file had changed; used for in-memory changes such as the server's job queue.
+  pub fn poke(&self) {
+    self.shared.bump();
+  }
+
+  // Changes made after this call wake the returned subscription, including ones made before its first wait.
+  pub fn subscribe(&self) -> WorkdirSubscription {
+    WorkdirSubscription {
+      shared: self.shared.clone(),
+      last_seen_generation: self.shared.current(),
+    }
+  }
+}
+
+impl Default for WorkdirWatcher {
+  // A watcher with no directories; only useful as a placeholder, as subscribers wake on poke() and timeouts alone.
+  fn default() -> Self {
+    let (generation_tx, _generation_rx) = tokio::sync::watch::channel(0);
+    Self {
+      backend: WatchBackend::Unwatched,
+      shared: std::sync::Arc::new(WatchGeneration {
+        generation: std::sync::Mutex::new(0),
+        changed: std::sync::Condvar::new(),
+        generation_tx: generation_tx,
+      }),
+      _os_watcher: std::sync::Mutex::new(None),
+    }
+  }
+}
+
+pub struct WorkdirSubscription {
+  shared: std::sync::Arc<WatchGeneration>,
+  last_seen_generation: u64,
+}
+
+impl WorkdirSubscription {
+  // Blocks until something changed since the previous wait (or since subscribe()), or until timeout passes.
+  // Returns true if there was a change.
+  pub fn wait_blocking(&mut self, timeout: std::time::Duration) -> bool {
+    let generation = self.shared.generation.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let (generation, _timeout_result) = self.shared.changed.wait_timeout_while(generation, timeout, |generation| *generation == self.last_seen_generation)
+      .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let changed = *generation != self.last_seen_generation;
+    self.last_seen_generation = *generation;
+    changed
+  }
+
+  // The async version of wait_blocking().
+  pub async fn wait(&mut self, timeout: std::time::Duration) -> bool {
+    let last_seen_generation = self.last_seen_generation;
+    let mut generation_rx = self.shared.generation_tx.subscribe();
+    let seen_generation = match tokio::time::timeout(timeout, generation_rx.wait_for(|generation| *generation != last_seen_generation)).await {
+      Ok(Ok(generation)) => *generation,
+      _ => last_seen_generation,
+    };
+    self.last_seen_generation = seen_generation;
+    seen_generation != last_seen_generation
+  }
+}
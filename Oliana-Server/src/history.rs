@@ -0,0 +1,74 @@
+// Keeps a session's conversation inside the text model's context window.
+//
+// Every message is counted with estimate_tokens(). Once a session's prompt goes over its budget the server asks oliana_text for a
+// SummaryJob which folds the oldest turns into the session's "story so far", sent as part of the system message from then on.
+// Until that summary is ready, turns are sent with just the newest messages which fit (see trim_to_budget()).
+
+use oliana_lib::jobs::{TextMessage, TextRole};
+
+// Tokens a session's prompt may use before its oldest turns are summarized. Phi-3.5 can read far more, but every token of context
+// costs VRAM and time on each reply; the server binary overrides this with the OLIANA_CONTEXT_TOKEN_BUDGET environment variable.
+pub const DEFAULT_CONTEXT_TOKEN_BUDGET: usize = 3072;
+
+// Longest summary we ask for.
+pub const SUMMARY_MAX_TOKENS: usize = 384;
+
+// The newest messages (two exchanges) are never summarized, so the model always sees the latest turns word for word.
+pub const KEEP_RECENT_MESSAGES: usize = 4;
+
+// The chat template wraps every message in role markers, eg "<|user|>" and "<|end|>".
+pub const TOKENS_PER_MESSAGE_OVERHEAD: usize = 4;
+
+// We do not load a tokenizer in the server; Phi-3.5's averages about 4 bytes per token on English prose, and counting 3 keeps the
+// estimate on the safe side for names, punctuation and other languages.
+pub fn estimate_tokens(text: &str) -> usize {
+    (text.len() + 2) / 3
+}
+
+pub fn message_tokens(message: &TextMessage) -> usize {
+    estimate_tokens(&message.content) + TOKENS_PER_MESSAGE_OVERHEAD
+}
+
+pub fn conversation_tokens(messages: &[TextMessage]) -> usize {
+    messages.iter().map(message_tokens).sum()
+}
+
+// How many of the oldest messages to fold into the story so far, or None while fixed_tokens (the system message) plus messages fit in budget.
+// Folds enough to bring the prompt down to half the budget, so a summary is not needed again on the very next turn, but always whole
+// user + assistant exchanges and never the newest KEEP_RECENT_MESSAGES.
+pub fn messages_to_summarize(fixed_tokens: usize, messages: &[TextMessage], budget: usize) -> Option<usize> {
+    let mut remaining_tokens = fixed_tokens + conversation_tokens(messages);
+    if remaining_tokens <= budget {
+        return None;
+    }
+    let max_summarized = messages.len().saturating_sub(KEEP_RECENT_MESSAGES);
+    let mut num_summarized = 0;
+    while num_summarized < max_summarized && remaining_tokens > budget / 2 {
+        remaining_tokens -= message_tokens(&messages[num_summarized]);
+        num_summarized += 1;
+    }
+    num_summarized -= num_summarized % 2;
+    if num_summarized < 1 {
+        return None;
+    }
+    Some(num_summarized)
+}
+
+// The newest messages which fit in budget alongside fixed_tokens (the system message and the message being replied to), starting with a user message.
+pub fn trim_to_budget(fixed_tokens: usize, messages: &[TextMessage], budget: usize) -> &[TextMessage] {
+    let mut used_tokens = fixed_tokens;
+    let mut first_kept = messages.len();
+    while first_kept > 0 {
+        let message_tokens = message_tokens(&messages[first_kept - 1]);
+        if used_tokens + message_tokens > budget {
+            break;
+        }
+        used_tokens += message_tokens;
+        first_kept -= 1;
+    }
+    // A reply without the message it answers only confuses the model
+    while first_kept < messages.len() && messages[first_kept].role != TextRole::User {
+        first_kept += 1;
+    }
+    &messages[first_kept..]
+}
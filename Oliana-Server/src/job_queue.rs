@@ -0,0 +1,397 @@
+// The job queue sits between the RPC handlers and the backends' work directories. Backends only ever see one .json file at a time;
+// everything else waits here until the dispatcher decides whose turn it is.
+
+use crate::{JobId, JobKind, OlianaError};
+
+// How many jobs may wait (across all clients + both backends) before *_begin() calls are refused with OlianaError::QueueFull.
+// The server binary overrides this with the OLIANA_MAX_QUEUE_DEPTH environment variable.
+pub const DEFAULT_MAX_QUEUE_DEPTH: usize = 32;
+
+// Runs image jobs without python or a GPU; see Oliana-Images-Procedural.
+pub const PROCEDURAL_IMAGE_BACKEND: &str = "oliana_images_procedural";
+
+// oliana_images counts as crash-looping once it has been started this many times within the server's RestartPolicy::window_s (2 minutes by default).
+pub const CRASH_LOOP_STARTS: usize = 3;
+
+// How long jobs wait for a backend the server is not tracking at all (eg its binary could not be found) before they fail with OlianaError::BackendDown.
+pub const BACKEND_MISSING_TIMEOUT_S: u64 = 30;
+
+// Which backend runs image jobs; the server binary reads this from the OLIANA_IMAGE_BACKEND environment variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImageBackendPolicy {
+    /// oliana_images, unless it is missing or crash-looping, in which case jobs go to oliana_images_procedural until it recovers
+    #[default]
+    Auto,
+    /// Always oliana_images
+    Diffusion,
+    /// Always oliana_images_procedural; oliana_images is never started
+    Procedural,
+}
+
+impl std::str::FromStr for ImageBackendPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ImageBackendPolicy::Auto),
+            "diffusion" => Ok(ImageBackendPolicy::Diffusion),
+            "procedural" => Ok(ImageBackendPolicy::Procedural),
+            unk => Err(format!("Unknown image backend {:?}, expected auto, diffusion or procedural", unk)),
+        }
+    }
+}
+
+impl ImageBackendPolicy {
+    // The policy a server started with our environment uses; the GUI reads it too, to know where the server it starts sends image jobs.
+    pub fn from_env() -> Self {
+        match std::env::var("OLIANA_IMAGE_BACKEND") {
+            Ok(env_val) => match env_val.parse::<ImageBackendPolicy>() {
+                Ok(parsed_policy) => parsed_policy,
+                Err(e) => {
+                    eprintln!("Ignoring OLIANA_IMAGE_BACKEND: {}", e);
+                    ImageBackendPolicy::default()
+                }
+            },
+            Err(_) => ImageBackendPolicy::default(),
+        }
+    }
+}
+
+// Interactive jobs (eg the GUI waiting on a reply) are always dispatched before Batch jobs (eg scripted oliana_client runs).
+// Variant order matters; lower variants are dispatched first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize)]
+pub enum JobPriority {
+    Interactive,
+    Batch,
+}
+
+impl Default for JobPriority {
+    fn default() -> Self { JobPriority::Batch }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum QueuePosition {
+    /// The job has not been handed to its backend yet; jobs_ahead are dispatched to the same backend before it.
+    Waiting { jobs_ahead: usize },
+    /// The backend is working on the job right now.
+    Running,
+    /// The job is finished, was cancelled, or never existed.
+    NotQueued,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueuedJob {
+    pub job: JobId,
+    pub client: std::net::SocketAddr,
+    pub priority: JobPriority,
+    pub input: oliana_lib::jobs::JobInput, // Submitted to the backend's work directory when the job is dispatched
+}
+
+#[derive(Debug, Clone)]
+pub struct RunningJob {
+    pub job: JobId,
+    pub backend: &'static str, // Binary name of the backend the job was submitted to
+    pub backend_restart_count: u32, // If the backend's restart count changes while this job runs, the backend died and the job is lost
+    pub backend_pid: Option<u32>, // The process the job was handed to; its exit (see TrackedProcs::subscribe_exits()) fails the job
+}
+
+#[derive(Debug)]
+pub struct JobQueue {
+    pub max_depth: usize,
+    waiting: Vec<QueuedJob>, // Kept in arrival order, which breaks ties between otherwise-equal jobs
+    running: std::collections::HashMap<JobKind, RunningJob>,
+    // Value of dispatch_counter when each client last had a job of each kind dispatched; clients which waited longest go first.
+    last_dispatched: std::collections::HashMap<(std::net::SocketAddr, JobKind), u64>,
+    dispatch_counter: u64,
+    // Clients whose connection closed while jobs of theirs were still waiting; their last_dispatched entries go once those jobs have left the queue.
+    departed_clients: std::collections::HashSet<std::net::SocketAddr>,
+}
+
+impl Default for JobQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_QUEUE_DEPTH)
+    }
+}
+
+impl JobQueue {
+    pub fn new(max_depth: usize) -> Self {
+        Self {
+            max_depth: max_depth,
+            waiting: vec![],
+            running: std::collections::HashMap::new(),
+            last_dispatched: std::collections::HashMap::new(),
+            dispatch_counter: 0,
+            departed_clients: std::collections::HashSet::new(),
+        }
+    }
+
+    pub fn push(&mut self, queued_job: QueuedJob) -> Result<(), OlianaError> {
+        if self.waiting.len() >= self.max_depth {
+            return Err(OlianaError::QueueFull { max_depth: self.max_depth });
+        }
+        self.waiting.push(queued_job);
+        Ok(())
+    }
+
+    pub fn is_waiting(&self, job: JobId) -> bool {
+        self.waiting.iter().any(|q| q.job == job)
+    }
+
+    pub fn is_running(&self, job: JobId) -> bool {
+        self.running.get(&job.kind).map(|r| r.job == job).unwrap_or(false)
+    }
+
+    pub fn get_running(&self, kind: JobKind) -> Option<&RunningJob> {
+        self.running.get(&kind)
+    }
+
+    // Removes a job which has not been dispatched yet; returns false if it was not waiting (ie it is running or already gone).
+    pub fn remove_waiting(&mut self, job: JobId) -> bool {
+        let client = match self.waiting.iter().find(|q| q.job == job) {
+            Some(queued_job) => queued_job.client,
+            None => return false,
+        };
+        self.waiting.retain(|q| q.job != job);
+        self.forget_client_if_departed(client);
+        true
+    }
+
+    // Removes every job of kind which has not been dispatched yet, eg because its backend will never run again.
+    pub fn remove_all_waiting(&mut self, kind: JobKind) -> Vec<QueuedJob> {
+        let (removed, kept): (Vec<QueuedJob>, Vec<QueuedJob>) = std::mem::take(&mut self.waiting).into_iter().partition(|q| q.job.kind == kind);
+        self.waiting = kept;
+        for queued_job in removed.iter() {
+            self.forget_client_if_departed(queued_job.client);
+        }
+        removed
+    }
+
+    // Called once a client's connection closes. Its address is never seen again (a client which reconnects comes from a new port), so
+    // nothing needs to remember when it last had a job dispatched once the jobs it left waiting are gone.
+    pub fn forget_client(&mut self, client: std::net::SocketAddr) {
+        self.departed_clients.insert(client);
+        self.forget_client_if_departed(client);
+    }
+
+    fn forget_client_if_departed(&mut self, client: std::net::SocketAddr) {
+        if self.departed_clients.contains(&client) && !self.waiting.iter().any(|q| q.client == client) {
+            self.departed_clients.remove(&client);
+            self.last_dispatched.retain(|(dispatched_client, _kind), _| *dispatched_client != client);
+        }
+    }
+
+    // Frees the backend for the next job.
+    pub fn finish_running(&mut self, kind: JobKind) -> Option<RunningJob> {
+        self.running.remove(&kind)
+    }
+
+    // If the backend for kind is idle, takes the job which should run next off the queue and records it as running on backend.
+    pub fn dispatch_next(&mut self, kind: JobKind, backend: &'static str, backend_restart_count: u32, backend_pid: Option<u32>) -> Option<QueuedJob> {
+        if self.running.contains_key(&kind) {
+            return None;
+        }
+        let i = pick_next_index(&self.waiting, kind, &self.last_dispatched)?;
+        let queued_job = self.waiting.remove(i);
+        self.dispatch_counter += 1;
+        self.last_dispatched.insert((queued_job.client, kind), self.dispatch_counter);
+        self.running.insert(kind, RunningJob { job: queued_job.job, backend: backend, backend_restart_count: backend_restart_count, backend_pid: backend_pid });
+        self.forget_client_if_departed(queued_job.client);
+        Some(queued_job)
+    }
+
+    // Replays dispatch_next() against a copy of the waiting jobs to count how many will be dispatched before job.
+    pub fn position(&self, job: JobId) -> QueuePosition {
+        if self.is_running(job) {
+            return QueuePosition::Running;
+        }
+        if !self.is_waiting(job) {
+            return QueuePosition::NotQueued;
+        }
+        let mut waiting: Vec<QueuedJob> = self.waiting.iter().filter(|q| q.job.kind == job.kind).cloned().collect();
+        let mut last_dispatched = self.last_dispatched.clone();
+        let mut dispatch_counter = self.dispatch_counter;
+        let mut jobs_ahead: usize = 0;
+        while let Some(i) = pick_next_index(&waiting, job.kind, &last_dispatched) {
+            let queued_job = waiting.remove(i);
+            if queued_job.job == job {
+                break;
+            }
+            dispatch_counter += 1;
+            last_dispatched.insert((queued_job.client, job.kind), dispatch_counter);
+            jobs_ahead += 1;
+        }
+        QueuePosition::Waiting { jobs_ahead }
+    }
+}
+
+// Highest priority first, then the client who has gone longest without a dispatch (never = 0), then whoever arrived first.
+fn pick_next_index(waiting: &[QueuedJob], kind: JobKind, last_dispatched: &std::collections::HashMap<(std::net::SocketAddr, JobKind), u64>) -> Option<usize> {
+    waiting.iter()
+        .enumerate()
+        .filter(|(_i, q)| q.job.kind == kind)
+        .min_by_key(|(i, q)| (q.priority, *last_dispatched.get(&(q.client, kind)).unwrap_or(&0), *i))
+        .map(|(i, _q)| i)
+}
+
+// Picks the binary name of the backend which should run the next job of kind.
+pub fn pick_backend(kind: JobKind, image_backend_policy: ImageBackendPolicy, proc_statuses: &std::collections::HashMap<String, oliana_lib::launchers::ProcStatus>) -> &'static str {
+    match (kind, image_backend_policy) {
+        (JobKind::Text, _) => kind.backend_bin_name(),
+        (JobKind::Image, ImageBackendPolicy::Diffusion) => kind.backend_bin_name(),
+        (JobKind::Image, ImageBackendPolicy::Procedural) => PROCEDURAL_IMAGE_BACKEND,
+        (JobKind::Image, ImageBackendPolicy::Auto) => {
+            // No status means oliana_images could not be started at all, eg it was never built on this machine
+            match proc_statuses.get(kind.backend_bin_name()) {
+                Some(status) if status.state != oliana_lib::launchers::ProcState::Failed && status.recent_starts < CRASH_LOOP_STARTS => kind.backend_bin_name(),
+                _ => PROCEDURAL_IMAGE_BACKEND,
+            }
+        }
+    }
+}
+
+// Runs for the life of the server; whenever a work directory or the queue changes each idle backend is handed its next job, and running jobs are checked for completion.
+pub async fn dispatch_jobs_forever(
+        shared_jobs: std::sync::Arc<std::sync::RwLock<crate::SharedJobState>>,
+        shareable_procs: std::sync::Arc<std::sync::RwLock<oliana_lib::launchers::TrackedProcs>>,
+        workdir_watcher: std::sync::Arc<oliana_lib::watch::WorkdirWatcher>,
+        ai_workdir_images: String,
+        ai_workdir_text: String
+    ) {
+    let mut workdir_changes = workdir_watcher.subscribe();
+    let mut proc_exits = match shareable_procs.read() {
+        Ok(procs_rg) => procs_rg.subscribe_exits(),
+        Err(e) => {
+            eprintln!("{}:{} {:?}", file!(), line!(), e);
+            return;
+        }
+    };
+    let mut last_image_backend: Option<&'static str> = None;
+    // When each backend which has no status was first seen without one
+    let mut backend_missing_since: std::collections::HashMap<&'static str, std::time::Instant> = std::collections::HashMap::new();
+    loop {
+        // Backends writing job files, OlianaServer::enqueue_job() and backends exiting all wake us; backend restarts do not, so we also look once a second.
+        let mut exited: Vec<oliana_lib::launchers::ProcExitEvent> = vec![];
+        tokio::select! {
+            _ = workdir_changes.wait( tokio::time::Duration::from_millis(1000) ) => { }
+            proc_exit = proc_exits.recv() => {
+                if let Ok(proc_exit) = proc_exit {
+                    exited.push(proc_exit);
+                }
+            }
+        }
+        // A lagged receiver only loses events; restart counts below still catch those backends
+        while let Ok(proc_exit) = proc_exits.try_recv() {
+            exited.push(proc_exit);
+        }
+
+        let proc_statuses = match shareable_procs.try_read() {
+            Ok(procs_rg) => procs_rg.get_proc_statuses(),
+            Err(_) => continue, // Probably busy re-spawning a backend; try again after the next change or second
+        };
+        let restart_count = |backend: &str| proc_statuses.get(backend).map(|status| status.restart_count).unwrap_or(0);
+
+        let image_backend_policy = match shared_jobs.read() {
+            Ok(shared_jobs_rg) => shared_jobs_rg.image_backend_policy,
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+                ImageBackendPolicy::default()
+            }
+        };
+        let image_backend = pick_backend(JobKind::Image, image_backend_policy, &proc_statuses);
+        if last_image_backend != Some(image_backend) {
+            eprintln!("Image jobs now go to {} (OLIANA_IMAGE_BACKEND is {:?})", image_backend, image_backend_policy);
+            last_image_backend = Some(image_backend);
+        }
+
+        for kind in [JobKind::Text, JobKind::Image] {
+            let workdir = match kind {
+                JobKind::Text => &ai_workdir_text,
+                JobKind::Image => &ai_workdir_images,
+            };
+            let backend = match kind {
+                JobKind::Text => kind.backend_bin_name(),
+                JobKind::Image => image_backend,
+            };
+            let backend_restart_count = restart_count(backend);
+            let backend_pid = proc_statuses.get(backend).and_then(|status| status.pid);
+            // Jobs wait here until their backend is Healthy, eg while it is starting (loading its model) or about to be restarted, rather than in
+            // the work directory, so they can still be cancelled or re-prioritized. A Failed backend is never started again, so its jobs fail instead,
+            // as do those of a backend which has had no status (was never spawned) for BACKEND_MISSING_TIMEOUT_S.
+            let backend_state = proc_statuses.get(backend).map(|status| status.state);
+            let backend_failed = backend_state == Some(oliana_lib::launchers::ProcState::Failed);
+            let backend_is_ready = backend_state == Some(oliana_lib::launchers::ProcState::Healthy);
+            let backend_missing = match backend_state {
+                Some(_) => {
+                    backend_missing_since.remove(backend);
+                    false
+                }
+                None => backend_missing_since.entry(backend).or_insert_with(std::time::Instant::now).elapsed().as_secs() >= BACKEND_MISSING_TIMEOUT_S,
+            };
+
+            // The job files are small, so they are written while holding the lock; that way RPCs never see a Running job without its files.
+            match shared_jobs.write() {
+                Ok(mut shared_jobs_wg) => {
+                    if let Some(running) = shared_jobs_wg.queue.get_running(kind).cloned() {
+                        let job_files = crate::job_files(workdir, running.job);
+                        if crate::job_is_finished(&job_files) {
+                            shared_jobs_wg.queue.finish_running(kind);
+                        }
+                        else {
+                            // The replacement backend only picks up Queued jobs, so nothing will ever finish this one
+                            let backend_exit = match exited.iter().find(|proc_exit| proc_exit.bin_name == running.backend && Some(proc_exit.pid) == running.backend_pid) {
+                                Some(proc_exit) => Some(format!("exited with {}", proc_exit.exit)),
+                                None if restart_count(running.backend) != running.backend_restart_count => Some("exited".to_string()),
+                                None if running.backend == backend && backend_failed => Some("was started too often and will not be restarted".to_string()),
+                                None => None,
+                            };
+                            if let Some(backend_exit) = backend_exit {
+                                eprintln!("{} {} while working on {}, failing the job", running.backend, backend_exit, running.job);
+                                shared_jobs_wg.queue.finish_running(kind);
+                                if let Err(e) = job_files.write_failed(&oliana_lib::jobs::JobError::new(oliana_lib::jobs::JobErrorKind::BackendExited, format!("{} {} while working on job {}", running.backend, backend_exit, running.job))) {
+                                    eprintln!("{}:{} {:?}", file!(), line!(), e);
+                                }
+                            }
+                        }
+                    }
+                    if backend_failed {
+                        let job_error = oliana_lib::jobs::JobError::new(oliana_lib::jobs::JobErrorKind::BackendExited, format!("{} was started too often and will not be restarted until the server restarts", backend));
+                        fail_all_waiting(&mut shared_jobs_wg.queue, kind, workdir, backend, &job_error);
+                    }
+                    else if backend_missing {
+                        let job_error = oliana_lib::jobs::JobError::new(oliana_lib::jobs::JobErrorKind::BackendDown, format!("{} has not been running for {} seconds; is its binary missing?", backend, BACKEND_MISSING_TIMEOUT_S));
+                        fail_all_waiting(&mut shared_jobs_wg.queue, kind, workdir, backend, &job_error);
+                    }
+                    if backend_is_ready {
+                        if let Some(queued_job) = shared_jobs_wg.queue.dispatch_next(kind, backend, backend_restart_count, backend_pid) {
+                            let job_files = crate::job_files(workdir, queued_job.job);
+                            if let Err(e) = job_files.submit(&queued_job.input, backend) {
+                                eprintln!("{}:{} {:?}", file!(), line!(), e);
+                                shared_jobs_wg.queue.finish_running(kind);
+                                // Otherwise RPCs waiting on the job would find it neither queued nor running, with no state saying why
+                                if let Err(e) = job_files.write_failed(&oliana_lib::jobs::JobError::new(oliana_lib::jobs::JobErrorKind::Io, format!("Cannot hand job {} to {}: {:?}", queued_job.job, backend, e))) {
+                                    eprintln!("{}:{} {:?}", file!(), line!(), e);
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}:{} {:?}", file!(), line!(), e);
+                }
+            }
+        }
+    }
+}
+
+// Fails every job of kind still waiting for backend, for when the backend will not take them.
+fn fail_all_waiting(queue: &mut JobQueue, kind: JobKind, workdir: &str, backend: &str, job_error: &oliana_lib::jobs::JobError) {
+    for queued_job in queue.remove_all_waiting(kind) {
+        eprintln!("Failing {}: {}", queued_job.job, job_error);
+        // Written like submit() would, so the job's error names the backend it was meant for
+        let job_files = crate::job_files(workdir, queued_job.job);
+        let write_result = oliana_lib::jobs::write_atomically(&job_files.backend_path(), backend.as_bytes())
+            .and_then(|_| job_files.write_failed(job_error));
+        if let Err(e) = write_result {
+            eprintln!("{}:{} {:?}", file!(), line!(), e);
+        }
+    }
+}
@@ -29,6 +29,16 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
     help_cmd.print_long_help()?;
     return Ok(());
   }
+  if args.command == Command::ImageInfo {
+    // Reads the file alone, so needs no server
+    if args.file.len() < 1 {
+      return Err("The image-info command requires a file, eg: image-info out.png".into());
+    }
+    for (keyword, text) in oliana_lib::png_text::read_text(std::path::Path::new(&args.file))?.iter() {
+      println!("{}: {}", keyword, text);
+    }
+    return Ok(());
+  }
 
   println!("Connecting to {:?}", args.server_url);
 
@@ -40,14 +50,35 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
   let client = oliana_server_lib::OlianaClient::new(tarpc::client::Config::default(), transport.await?).spawn();
 
   if args.command == Command::Text {
-    let text_begin_diagnostic = client.generate_text_begin(
-      tarpc::context::current(),
-      args.system_prompt.clone(),
-      args.prompt.clone()
-    ).await?;
-    eprintln!("From Server: {:?}", &text_begin_diagnostic);
+    let history = args.read_history()?;
+    let job = match (args.job, args.session) {
+      (Some(job), _) => job, // Continue reading a job begun by an earlier connection
+      (None, Some(session)) => {
+        // The server adds the session's system prompt and earlier turns, and keeps the reply for the next turn
+        client.session_say(tarpc::context::current(), session, args.prompt.clone()).await??
+      }
+      (None, None) => {
+        let mut messages = Vec::with_capacity(history.len() + 2);
+        messages.push(oliana_server_lib::TextMessage::system(args.system_prompt.clone()));
+        messages.extend(history.iter().cloned());
+        messages.push(oliana_server_lib::TextMessage::user(args.prompt.clone()));
+        let job = client.generate_text_begin(
+          tarpc::context::current(),
+          messages,
+          args.text_sampling()
+        ).await??;
+        job
+      }
+    };
+    eprintln!("From Server: job {}", &job);
     let mut generated_text = String::with_capacity(4096);
-    while let Some(next_token) = client.generate_text_next_token(tarpc::context::current()).await? {
+    let mut last_position: Option<oliana_server_lib::QueuePosition> = None;
+    while let Some(next_token) = client.generate_text_next_token(tarpc::context::current(), job).await?? {
+      if next_token.len() < 1 { // Still queued
+        let position = client.job_queue_position(tarpc::context::current(), job).await??;
+        print_queue_position_if_changed(position, &mut last_position);
+        continue;
+      }
       eprint!("{}", &next_token);
       //eprint!(" ");
       generated_text.push_str(&next_token);
@@ -58,27 +89,166 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
       eprintln!("Writing {} chars to {}", generated_text.len(), &args.output);
       tokio::fs::write(&args.output, &generated_text).await?;
     }
+    if args.history.len() > 0 && args.job.is_none() && args.session.is_none() {
+      let mut history = history;
+      history.push(oliana_server_lib::TextMessage::user(args.prompt.clone()));
+      history.push(oliana_server_lib::TextMessage::assistant(generated_text.clone()));
+      eprintln!("Writing {} messages to {}", history.len(), &args.history);
+      tokio::fs::write(&args.history, serde_json::to_string_pretty(&history)?).await?;
+    }
   }
   else if args.command == Command::Image {
-    let text_begin_diagnostic = client.generate_image_begin(
-      tarpc::context::current(),
-      args.prompt.clone(),
-      args.negative_prompt.clone(),
-      args.guidance_scale,
-      args.num_inference_steps
-    ).await?;
-    eprintln!("From Server: {:?}", &text_begin_diagnostic);
+    let job = match args.job {
+      Some(job) => job, // Continue waiting on a job begun by an earlier connection
+      None if args.init_image.len() > 0 => {
+        let job = client.edit_image_begin(
+          tarpc::context::current(),
+          args.prompt.clone(),
+          args.negative_prompt.clone(),
+          args.guidance_scale,
+          args.num_inference_steps,
+          args.transparent_background,
+          args.image_options()?,
+          args.init_image()?
+        ).await??;
+        job
+      }
+      None => {
+        let job = client.generate_image_begin(
+          tarpc::context::current(),
+          args.prompt.clone(),
+          args.negative_prompt.clone(),
+          args.guidance_scale,
+          args.num_inference_steps,
+          args.transparent_background,
+          args.image_options()?
+        ).await??;
+        job
+      }
+    };
+    eprintln!("From Server: job {}", &job);
+
+    // Wait out the queue and the backend before asking for the result; generate_image_get_result() waits as long as the backend is alive, which can outlast our RPC deadline.
+    let mut last_position: Option<oliana_server_lib::QueuePosition> = None;
+    while args.previews {
+      match client.generate_image_next_preview(tarpc::context::current(), job).await?? {
+        Some(preview) if preview.png.len() > 0 => {
+          let preview_path = args.preview_path(preview.step);
+          eprintln!("Writing step {} preview ({} bytes) to {}", preview.step, preview.png.len(), preview_path.display());
+          tokio::fs::write(&preview_path, &preview.png).await?;
+        }
+        Some(_) => { // Nothing new yet
+          let position = client.job_queue_position(tarpc::context::current(), job).await??;
+          print_queue_position_if_changed(position, &mut last_position);
+        }
+        None => break,
+      }
+    }
+    loop {
+      let position = client.job_queue_position(tarpc::context::current(), job).await??;
+      print_queue_position_if_changed(position, &mut last_position);
+      if let oliana_server_lib::QueuePosition::Waiting { .. } = position {
+        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        continue;
+      }
+      if client.generate_image_result_exists(tarpc::context::current(), job).await?? {
+        break;
+      }
+      tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+    }
 
-    let png_bytes = client.generate_image_get_result(tarpc::context::current()).await?;
+    let images = client.generate_image_get_result(tarpc::context::current(), job).await??;
 
     if args.output.len() > 0 {
-      eprintln!("Writing {} bytes to {}", png_bytes.len(), &args.output);
-      tokio::fs::write(&args.output, &png_bytes).await?;
+      for (image_i, image) in images.iter().enumerate() {
+        let image_path = args.nth_image_path(image_i);
+        eprintln!("Writing {} bytes to {} (--random-seed {} makes it again)", image.png.len(), image_path.display(), image.seed);
+        tokio::fs::write(&image_path, &image.png).await?;
+      }
     }
 
   }
+  else if args.command == Command::Cancel {
+    let job = args.job.ok_or_else(|| "The cancel command requires --job, eg --job text-3")?;
+    client.cancel(tarpc::context::current(), job).await??;
+    eprintln!("Asked the server to cancel {}", &job);
+  }
+  else if args.command == Command::QueuePosition {
+    let job = args.job.ok_or_else(|| "The queue-position command requires --job, eg --job image-3")?;
+    let position = client.job_queue_position(tarpc::context::current(), job).await??;
+    println!("{:?}", position);
+  }
+  else if args.command == Command::BackendStatus {
+    let mut statuses: Vec<(String, oliana_server_lib::ProcStatus)> = client.backend_status(tarpc::context::current()).await??.into_iter().collect();
+    statuses.sort_by(|a, b| a.0.cmp(&b.0));
+    for (bin_name, status) in statuses.iter() {
+      let mut line = format!("{} {:?}, started {} times ({} recently)", bin_name, status.state, status.restart_count, status.recent_starts);
+      if let Some(pid) = status.pid {
+        line += &format!(", PID {}", pid);
+      }
+      if let (Some(activity), Some(heartbeat_age_ms)) = (&status.activity, status.heartbeat_age_ms) {
+        line += &format!(", {} ({} ms ago)", activity, heartbeat_age_ms);
+      }
+      if let Some(ref last_exit) = status.last_exit {
+        line += &format!(", last exited with {}", last_exit);
+      }
+      if let Some(next_start_in_ms) = status.next_start_in_ms {
+        line += &format!(", restarting in {} ms", next_start_in_ms);
+      }
+      println!("{}", line);
+    }
+  }
+  else if args.command == Command::BackendLog {
+    let mut after_seq: u64 = 0;
+    loop {
+      let lines = client.backend_log(tarpc::context::current(), args.backend.clone(), after_seq).await??;
+      for line in lines.iter() {
+        println!("[{} PID {} {:?}] {}", args.backend, line.pid, line.stream, line.text);
+        after_seq = line.seq;
+      }
+      if !args.follow && lines.len() < oliana_server_lib::BACKEND_LOG_MAX_LINES {
+        break;
+      }
+    }
+  }
+  else if args.command == Command::SessionCreate {
+    let settings = oliana_server_lib::SessionSettings {
+      title: args.title.clone(),
+      system_prompt: args.system_prompt.clone(),
+      sampling: args.text_sampling(),
+    };
+    let session = client.session_create(tarpc::context::current(), settings).await??;
+    println!("{}", session);
+  }
+  else if args.command == Command::SessionList {
+    for summary in client.session_list(tarpc::context::current()).await??.iter() {
+      let pending = summary.pending_job.map(|job| format!(", replying in {}", job)).unwrap_or_default();
+      println!("{} {:?} ({} messages{})", summary.id, summary.title, summary.num_messages, pending);
+    }
+  }
+  else if args.command == Command::SessionShow {
+    let session_id = args.session.ok_or_else(|| "The session-show command requires --session, eg --session session-3")?;
+    let session = client.session_resume(tarpc::context::current(), session_id).await??;
+    println!("{} {:?}", session.id, session.settings.title);
+    println!("[system] {}", session.settings.system_prompt);
+    if session.story_so_far.len() > 0 {
+      println!("[story so far] {}", session.story_so_far);
+    }
+    for message in session.messages.iter() {
+      println!("[{:?}] {}", message.role, message.content);
+    }
+    if let Some(pending_turn) = session.pending_turn {
+      println!("[User] {}", pending_turn.user_message);
+      println!("(still replying in {}; read it with: text --job {})", pending_turn.job, pending_turn.job);
+    }
+  }
+  else if args.command == Command::SessionDelete {
+    let session = args.session.ok_or_else(|| "The session-delete command requires --session, eg --session session-3")?;
+    client.session_delete(tarpc::context::current(), session).await??;
+    eprintln!("Deleted {}", &session);
+  }
   else if args.command == Command::ServerPCIHardwareNames {
-    let hardware_names = client.fetch_pci_hw_device_names(tarpc::context::current()).await?;
+    let hardware_names = client.fetch_pci_hw_device_names(tarpc::context::current()).await??;
     for name in hardware_names.iter() {
       println!("{name}");
     }
@@ -91,9 +261,23 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
   Ok(())
 }
 
+fn print_queue_position_if_changed(position: oliana_server_lib::QueuePosition, last_position: &mut Option<oliana_server_lib::QueuePosition>) {
+  if *last_position != Some(position) {
+    if let oliana_server_lib::QueuePosition::Waiting { jobs_ahead } = position {
+      eprintln!("Queued, {} jobs ahead", jobs_ahead);
+    }
+    *last_position = Some(position);
+  }
+}
+
 #[derive(clap::ValueEnum, Clone, Debug, PartialEq)]
 pub enum Command {
   Text, Image,
+  ImageInfo,
+  Cancel,
+  QueuePosition,
+  BackendStatus, BackendLog,
+  SessionCreate, SessionList, SessionShow, SessionDelete,
   ServerPCIHardwareNames,
   Help
 }
@@ -110,14 +294,22 @@ pub struct Args {
    #[clap(value_enum, default_value_t=Command::Help)]
     pub command: Command,
 
+    /// With command 'image-info' only - the .png file whose text (eg the prompt and seed an image was generated from) to print
+    #[arg(default_value="")]
+    pub file: String,
+
     /// Pass a prompt for the Command's Image or Text AI agent (required for Image and Text commands)
     #[arg(short, long, default_value="")]
     pub prompt: String,
 
-    /// With command 'text' only - pass in the system prompt to use
+    /// With commands 'text' and 'session-create' - pass in the system prompt to use
     #[arg(short, long, default_value="You are a helpful office assistant who eagerly answers questions with expert advice.")]
     pub system_prompt: String,
 
+    /// With command 'text' only - a .json file holding the earlier turns of a conversation as a list of {"role": "user" | "assistant", "content": "..."} messages; they are sent between the system prompt and --prompt, and the file is re-written with this turn added once the reply is complete. A missing file starts a new conversation.
+    #[arg(long, default_value="")]
+    pub history: String,
+
     /// With command 'image' only - pass in a negative prompt
     #[arg(short, long, default_value="")]
     pub negative_prompt: String,
@@ -130,6 +322,14 @@ pub struct Args {
     #[arg(short, long, default_value="12")]
     pub num_inference_steps: u32,
 
+    /// With command 'image' only - remove the background, writing an RGBA .png in which only the prompt's subject is opaque
+    #[arg(long)]
+    pub transparent_background: bool,
+
+    /// With command 'image' only - also write each preview the server sends while the image is diffusing, named after --output (eg out.step-4.png)
+    #[arg(long)]
+    pub previews: bool,
+
     /// File path to write Image or Text AI response back to (defaults to out.png when using Image command, writes to stdout if unspecified in Text command)
     #[arg(short, long, default_value="")]
     pub output: String,
@@ -142,9 +342,71 @@ pub struct Args {
     #[arg(short = 'v', long, action = clap::ArgAction::Count)]
     pub verbose: u8,
 
-    /// If set, every random-number generator will use this as their seed to allow completely deterministic AI runs.
+    /// With command 'text' or 'image' - instead of beginning a new job, continue reading an existing one (eg "text-3" or "image-0"), possibly begun by another connection. With command 'cancel' - the job to stop. With command 'queue-position' - the job to report on.
     #[arg(short, long)]
-    pub random_seed: Option<usize>,
+    pub job: Option<oliana_server_lib::JobId>,
+
+    /// With command 'text' - reply as the next turn of this server-side session (eg "session-3") instead of a one-off conversation; --system-prompt and --history are not used. With commands 'session-show' and 'session-delete' - the session to act on.
+    #[arg(long)]
+    pub session: Option<oliana_server_lib::SessionId>,
+
+    /// With command 'backend-log' only - the backend whose output to print, eg oliana_text, oliana_images or oliana_images_procedural
+    #[arg(long, default_value="oliana_text")]
+    pub backend: String,
+
+    /// With command 'backend-log' only - keep printing lines as the backend writes them, until interrupted
+    #[arg(long)]
+    pub follow: bool,
+
+    /// With command 'session-create' only - a title to tell sessions apart in session-list
+    #[arg(long, default_value="")]
+    pub title: String,
+
+    /// If set, every random-number generator will use this as their seed to allow completely deterministic AI runs. With command 'image', the Nth extra image uses this seed + N.
+    /// With command 'text' the text backend cannot seed its sampler, so any seed instead makes it always take the most likely token
+    /// (the same messages always get the same reply); this cannot be combined with --temperature, --top-p or --top-k.
+    #[arg(short, long)]
+    pub random_seed: Option<u64>,
+
+    /// With command 'image' only - the image size, either WIDTHxHEIGHT (multiples of 8) or one of square, portrait, landscape and wide
+    #[arg(long, default_value="square")]
+    pub size: String,
+
+    /// With command 'image' only - how many images to make from the prompt; the first is written to --output and the rest next to it (eg out.1.png)
+    #[arg(long, default_value="1")]
+    pub num_images: u32,
+
+    /// With command 'image' only - a .png to repaint towards the prompt (img2img) instead of starting from noise; it is stretched to --size
+    #[arg(long, default_value="")]
+    pub init_image: String,
+
+    /// With command 'image' and --init-image only - how much of the init image to repaint, above 0 and at most 1
+    #[arg(long, default_value_t=oliana_lib::jobs::DEFAULT_IMAGE_STRENGTH)]
+    pub strength: f32,
+
+    /// With command 'image' and --init-image only - a .png which is white where the init image should be repainted and black where it should be kept (inpainting)
+    #[arg(long, default_value="")]
+    pub mask: String,
+
+    /// With command 'text' only - sampling temperature, 0 or more; lower values give more predictable replies. Uses the backend's default if unspecified.
+    #[arg(long)]
+    pub temperature: Option<f64>,
+
+    /// With command 'text' only - only sample from the most likely tokens whose probabilities add up to this, from 0 to 1
+    #[arg(long)]
+    pub top_p: Option<f64>,
+
+    /// With command 'text' only - only sample from this many of the most likely tokens
+    #[arg(long)]
+    pub top_k: Option<usize>,
+
+    /// With command 'text' only - stop the reply after this many new tokens
+    #[arg(long)]
+    pub max_tokens: Option<usize>,
+
+    /// With command 'text' only - end the reply before this string; may be specified multiple times
+    #[arg(long)]
+    pub stop: Vec<String>,
 
 }
 
@@ -156,6 +418,62 @@ impl Args {
     }
     self
   }
+
+  // Empty if --history is unset or names a file which does not exist yet
+  pub fn read_history(&self) -> Result<Vec<oliana_server_lib::TextMessage>, Box<dyn std::error::Error>> {
+    if self.history.len() < 1 || !std::path::Path::new(&self.history).exists() {
+      return Ok(vec![]);
+    }
+    let history_json = std::fs::read_to_string(&self.history).map_err(oliana_lib::eloc!())?;
+    Ok(serde_json::from_str(&history_json).map_err(oliana_lib::eloc!())?)
+  }
+
+  pub fn text_sampling(&self) -> oliana_server_lib::TextSampling {
+    oliana_server_lib::TextSampling {
+      temperature: self.temperature,
+      top_p: self.top_p,
+      top_k: self.top_k,
+      max_tokens: self.max_tokens,
+      deterministic: self.random_seed.is_some(),
+      stop: self.stop.clone(),
+    }
+  }
+
+  pub fn image_options(&self) -> Result<oliana_server_lib::ImageOptions, Box<dyn std::error::Error>> {
+    let (width, height) = oliana_lib::jobs::parse_image_size(&self.size)?;
+    Ok(oliana_server_lib::ImageOptions {
+      width: width,
+      height: height,
+      seed: self.random_seed,
+      num_images: self.num_images,
+    })
+  }
+
+  pub fn init_image(&self) -> Result<oliana_server_lib::InitImage, Box<dyn std::error::Error>> {
+    let png = std::fs::read(&self.init_image).map_err(oliana_lib::eloc!())?;
+    let mut mask_png = None;
+    if self.mask.len() > 0 {
+      mask_png = Some(std::fs::read(&self.mask).map_err(oliana_lib::eloc!())?);
+    }
+    Ok(oliana_server_lib::InitImage {
+      png: png,
+      strength: self.strength,
+      mask_png: mask_png,
+    })
+  }
+
+  // --output for the first image, then --output with its extension replaced by .N.png
+  pub fn nth_image_path(&self, image_i: usize) -> std::path::PathBuf {
+    match image_i {
+      0 => std::path::PathBuf::from(&self.output),
+      _ => std::path::Path::new(&self.output).with_extension(format!("{}.png", image_i)),
+    }
+  }
+
+  // --output with its extension replaced by .step-N.png
+  pub fn preview_path(&self, step: u32) -> std::path::PathBuf {
+    std::path::Path::new(&self.output).with_extension(format!("step-{}.png", step))
+  }
 }
 
 
@@ -25,6 +25,29 @@ async fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
     tokio::spawn(fut);
 }
 
+// Resolves on SIGTERM (eg from Oliana-GUI) or Ctrl+C.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => { }
+                    _ = tokio::signal::ctrl_c() => { }
+                }
+            }
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
 
 // This is used for the TCP listeners to communicate liviliness to the background task which ensures sub-processes are running.
 // When system is idle on Linux systems, children get SIGSTOP/CONT-ed to limit CPU use when nobody has connected for >20s.
@@ -56,7 +79,7 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
     }
 
 
-    let mut procs = oliana_lib::launchers::TrackedProcs::new(expected_bin_directory.clone(), track_proc_dir.clone());
+    let mut procs = oliana_lib::launchers::TrackedProcs::new(track_proc_dir.clone(), expected_bin_directory.clone());
 
     // This is where we do some general config of how & where the child processes will live.
     // Once registered, the server will regularly poll .ensure_registered_procs_running() to re-spawn anything that dies.
@@ -96,23 +119,83 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
         );
     }
 
-    procs.register_tracked_proc("oliana_images", &[
-        "--workdir", &ai_workdir_images.to_string_lossy()
-    ]);
+    // OLIANA_IMAGE_BACKEND picks between oliana_images (diffusion) and oliana_images_procedural, see job_queue::ImageBackendPolicy.
+    // Both share ai_workdir_images; the dispatcher addresses each job to one of them.
+    let image_backend_policy = oliana_server_lib::ImageBackendPolicy::from_env();
+
+    if image_backend_policy != oliana_server_lib::ImageBackendPolicy::Procedural {
+        procs.register_tracked_proc("oliana_images", &[
+            "--workdir", &ai_workdir_images.to_string_lossy()
+        ]);
+    }
+
+    if image_backend_policy != oliana_server_lib::ImageBackendPolicy::Diffusion {
+        procs.register_tracked_proc(oliana_server_lib::job_queue::PROCEDURAL_IMAGE_BACKEND, &[
+            "--workdir", &ai_workdir_images.to_string_lossy()
+        ]);
+    }
 
     procs.register_tracked_proc("oliana_text", &[
         "--workdir", &ai_workdir_text.to_string_lossy()
     ]);
 
-    procs.ensure_registered_procs_running()?;
+    // A backend which cannot start (eg oliana_images was never built on this machine) should not take the whole server down;
+    // the dispatcher routes image jobs around a missing oliana_images.
+    if let Err(e) = procs.ensure_registered_procs_running() {
+        eprintln!("{}:{} {:?}", file!(), line!(), e);
+    }
 
+    let child_exited = procs.child_exited.clone();
     let shareable_procs = std::sync::Arc::new(std::sync::RwLock::new(procs));
+    // Job nonces + streaming offsets live here instead of in each OlianaServer so that jobs outlive the connection which began them.
+    let mut shared_job_state = oliana_server_lib::SharedJobState::default();
+    shared_job_state.image_backend_policy = image_backend_policy;
+    shared_job_state.image_preview_interval = oliana_server_lib::DEFAULT_IMAGE_PREVIEW_INTERVAL;
+    if let Ok(env_val) = std::env::var("OLIANA_IMAGE_PREVIEW_INTERVAL") {
+        if let Ok(parsed_interval) = env_val.parse::<u32>() {
+            eprintln!("Overriding default image preview interval of {} steps with environment OLIANA_IMAGE_PREVIEW_INTERVAL={}", shared_job_state.image_preview_interval, parsed_interval);
+            shared_job_state.image_preview_interval = parsed_interval;
+        }
+    }
+    if let Ok(env_val) = std::env::var("OLIANA_MAX_QUEUE_DEPTH") {
+        if let Ok(parsed_max_depth) = env_val.parse::<usize>() {
+            eprintln!("Overriding default max queue depth of {} with environment OLIANA_MAX_QUEUE_DEPTH={}", shared_job_state.queue.max_depth, parsed_max_depth);
+            shared_job_state.queue.max_depth = parsed_max_depth;
+        }
+    }
+    // Sessions are kept outside the work directories, which are emptied above, so games survive a server restart
+    let mut sessions_dir = track_proc_dir.join("sessions");
+    if let Ok(env_sessions_dir) = std::env::var("OLIANA_SESSIONS_DIR") {
+        sessions_dir = env_sessions_dir.into();
+    }
+    shared_job_state.sessions = oliana_server_lib::sessions::SessionStore::load(&sessions_dir)?;
+    println!("Loaded {} sessions from {}", shared_job_state.sessions.sessions.len(), sessions_dir.display());
+    if let Ok(env_val) = std::env::var("OLIANA_CONTEXT_TOKEN_BUDGET") {
+        if let Ok(parsed_budget) = env_val.parse::<usize>() {
+            eprintln!("Overriding default context token budget of {} with environment OLIANA_CONTEXT_TOKEN_BUDGET={}", shared_job_state.sessions.context_token_budget, parsed_budget);
+            shared_job_state.sessions.context_token_budget = parsed_budget;
+        }
+    }
+    let shared_jobs = std::sync::Arc::new(std::sync::RwLock::new(shared_job_state));
     let shareable_ipv6_ai_workdir_images = ai_workdir_images.to_string_lossy().to_string();
     let shareable_ipv6_ai_workdir_text = ai_workdir_text.to_string_lossy().to_string();
     let shareable_ipv4_ai_workdir_images = ai_workdir_images.to_string_lossy().to_string();
     let shareable_ipv4_ai_workdir_text = ai_workdir_text.to_string_lossy().to_string();
 
-    // Start an infinite tokio task to call ensure_registered_procs_running()? every 2 seconds or so.
+    // One watcher on both work directories wakes the dispatcher and every waiting RPC as soon as a backend writes something.
+    let workdir_watcher = std::sync::Arc::new(oliana_lib::watch::WorkdirWatcher::new(&[&ai_workdir_images, &ai_workdir_text]).map_err(oliana_lib::eloc!())?);
+    println!("Watching work directories for changes ({:?})", workdir_watcher.backend);
+
+    // Start an infinite tokio task which hands queued jobs to the image and text backends one at a time.
+    tokio::task::spawn(oliana_server_lib::job_queue::dispatch_jobs_forever(
+        shared_jobs.clone(),
+        shareable_procs.clone(),
+        workdir_watcher.clone(),
+        ai_workdir_images.to_string_lossy().to_string(),
+        ai_workdir_text.to_string_lossy().to_string()
+    ));
+
+    // Start an infinite tokio task to call ensure_registered_procs_running()? every 2 seconds or so, and straight away when a backend exits.
     let ensure_registered_procs_running_t_shareable_procs = shareable_procs.clone();
     tokio::task::spawn(async move {
         let mut ms_since_last_ensured_running: u64 = 0;
@@ -125,7 +208,13 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
         let mut last_tick_procs_should_be_stopped = false;
 
         loop {
-            tokio::time::sleep(tick_delay_duration).await;
+            let mut child_just_exited = false;
+            tokio::select! {
+                _ = tokio::time::sleep(tick_delay_duration) => { }
+                _ = child_exited.notified() => {
+                    child_just_exited = true;
+                }
+            }
             ms_since_last_ensured_running += MS_TO_TICK_FOR;
 
             if let Ok(read_lock_guard) = ensure_registered_procs_running_t_shareable_procs.try_read() {
@@ -134,7 +223,7 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
                 }
             }
 
-            if ms_since_last_ensured_running > 2600 {
+            if child_just_exited || ms_since_last_ensured_running > 2600 {
                 if let Ok(mut write_lock_guard) = ensure_registered_procs_running_t_shareable_procs.try_write() {
                     if let Err(e) = write_lock_guard.ensure_registered_procs_running() {
                         eprintln!("Error polling ensure_registered_procs_running: {:?}", e);
@@ -162,8 +251,8 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
                 if let Ok(read_lock_guard) = ensure_registered_procs_running_t_shareable_procs.try_read() {
                     // Now we summarize sub-process running state and write to 2 files that other programs can poll to report subprocess status.
                     // This is primarially used so things like Oliana-GUI can tell a user "You don't have CUDA/OneAPI/<tech-of-choice>" without needing to actually EMBED <tech-of-choice> to perform the measurement.
-                    let data = read_lock_guard.get_proc_restart_counts();
-                    if let Err(e) = oliana_lib::files::set_cache_file_server_proc_restart_data(&data) {
+                    let data = read_lock_guard.get_proc_statuses();
+                    if let Err(e) = oliana_lib::files::set_cache_file_server_proc_statuses_data(&data) {
                         eprintln!("{}:{} {}", file!(), line!(), e);
                     }
                     let data = read_lock_guard.get_proc_outputs();
@@ -176,6 +265,24 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
         }
     });
 
+    // Backends lead process groups of their own, so Ctrl+C in a terminal only reaches the server; it stops them (see TrackedProcs::shutdown())
+    // before exiting rather than leaving them running. If the server is killed outright, Linux sends each of them SIGTERM anyway.
+    let shutdown_shareable_procs = shareable_procs.clone();
+    tokio::task::spawn(async move {
+        shutdown_signal().await;
+        eprintln!("Stopping backends before exiting");
+        let shutdown_result = tokio::task::spawn_blocking(move || {
+            match shutdown_shareable_procs.write() {
+                Ok(mut procs_wg) => procs_wg.shutdown(),
+                Err(e) => eprintln!("{}:{} {:?}", file!(), line!(), e),
+            }
+        }).await;
+        if let Err(e) = shutdown_result {
+            eprintln!("{}:{} {:?}", file!(), line!(), e);
+        }
+        std::process::exit(0);
+    });
+
     let mut port: u16 = 9050;
     if let Ok(env_val) = std::env::var("PORT") {
         if let Ok(parsed_port_num) = env_val.parse::<u16>() {
@@ -217,6 +324,8 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut all_futures = vec![];
     let ipv6_movable_shareable_procs = shareable_procs.clone();
+    let ipv6_movable_shared_jobs = shared_jobs.clone();
+    let ipv6_movable_workdir_watcher = workdir_watcher.clone();
     let ipv6_futures = tokio::spawn(ipv6_listener
             // Ignore accept errors.
             .filter_map(|r| future::ready(r.ok()))
@@ -229,6 +338,8 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
                 let server = oliana_server_lib::OlianaServer::new(
                     channel.transport().peer_addr().expect("IPv6 Client had no peer_addr!"),
                     ipv6_movable_shareable_procs.clone(),
+                    ipv6_movable_shared_jobs.clone(),
+                    ipv6_movable_workdir_watcher.clone(),
                     &shareable_ipv6_ai_workdir_images[..],
                     &shareable_ipv6_ai_workdir_text[..]
                 );
@@ -238,7 +349,15 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
                         write_guard.set_procs_should_be_stopped(false);
                     }
                 }
-                channel.execute(server.serve()).for_each(spawn)
+                let client_socket = server.client_socket;
+                let closing_shared_jobs = ipv6_movable_shared_jobs.clone();
+                channel.execute(server.serve()).for_each(spawn).map(move |_| {
+                    // The stream of requests ends when the client disconnects
+                    match closing_shared_jobs.write() {
+                        Ok(mut shared_jobs_wg) => shared_jobs_wg.forget_client(client_socket),
+                        Err(e) => eprintln!("{}:{} {:?}", file!(), line!(), e),
+                    }
+                })
             })
             // Max 32 channels.
             .buffer_unordered(32)
@@ -260,6 +379,8 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
                         let server = oliana_server_lib::OlianaServer::new(
                             channel.transport().peer_addr().expect("IPv4 Client had no peer_addr!"),
                             shareable_procs.clone(),
+                            shared_jobs.clone(),
+                            workdir_watcher.clone(),
                             &shareable_ipv4_ai_workdir_images[..],
                             &shareable_ipv4_ai_workdir_text[..]
                         );
@@ -269,7 +390,15 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
                                 write_guard.set_procs_should_be_stopped(false);
                             }
                         }
-                        channel.execute(server.serve()).for_each(spawn)
+                        let client_socket = server.client_socket;
+                        let closing_shared_jobs = shared_jobs.clone();
+                        channel.execute(server.serve()).for_each(spawn).map(move |_| {
+                            // The stream of requests ends when the client disconnects
+                            match closing_shared_jobs.write() {
+                                Ok(mut shared_jobs_wg) => shared_jobs_wg.forget_client(client_socket),
+                                Err(e) => eprintln!("{}:{} {:?}", file!(), line!(), e),
+                            }
+                        })
                     })
                     // Max 32 channels.
                     .buffer_unordered(32)
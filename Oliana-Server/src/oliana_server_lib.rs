@@ -7,25 +7,260 @@ use tarpc::{
     server::{self, Channel},
 };
 
+pub mod job_queue;
+pub use job_queue::{ImageBackendPolicy, JobPriority, QueuePosition};
+pub mod sessions;
+pub mod history;
+pub use sessions::{Session, SessionId, SessionSettings, SessionSummary};
+pub use oliana_lib::jobs::{ImageOptions, TextMessage, TextRole, TextSampling};
+pub use oliana_lib::launchers::{ProcExit, ProcState, ProcStatus};
+pub use oliana_lib::proc_logs::{LogLine, LogStream};
+
+// How long generate_text_next_token() holds on to a call which has no new text, before returning Ok(Some("")) so the caller asks again well inside its RPC deadline.
+pub const NEXT_TOKEN_LONG_POLL_MS: u64 = 2000;
+// Image jobs write a preview after every this many diffusion steps unless the server binary is given OLIANA_IMAGE_PREVIEW_INTERVAL; see generate_image_next_preview().
+pub const DEFAULT_IMAGE_PREVIEW_INTERVAL: u32 = 4;
+// How often an RPC waiting on a job with nothing changing checks that the job's backend is still alive.
+pub const BACKEND_LIVENESS_CHECK_MS: u64 = 1000;
+// The most lines one backend_log() call returns.
+pub const BACKEND_LOG_MAX_LINES: usize = 500;
+
 // This is the service definition. It looks a lot like a trait definition.
-// It defines one RPC, hello, which takes one arg, name, and returns a String.
+// Every generate_*_begin() call allocates a JobId which the other generate_* calls take; job IDs are unique for the lifetime of
+// the server, so several jobs may be in flight at once and a client may re-connect and read a job it began earlier again.
 #[tarpc::service]
 pub trait Oliana {
-    /// Runs an LLM and returns immediately; callers should concatinate results of generate_text_next_token() until it returns None for the reply. Return is some diagnostic text from server.
-    async fn generate_text_begin(system_prompt: String, user_prompt: String) -> String;
-    /// Returns None when token generation is complete
-    async fn generate_text_next_token() -> Option<String>;
-
-    /// Runs an AI model and returns immediately; callers should wait on generate_image_get_result() to read a .png vector of bytes back
-    async fn generate_image_begin(prompt: String, negative_prompt: String, guidance_scale: f32, num_inference_steps: u32) -> String;
-    /// Returns true if image gen is complete, else false. Useful for determining when generate_image_get_result() can be called w/o waiting
-    async fn generate_image_result_exists() -> bool;
-    /// Waits until image has completed and returns result.
-    async fn generate_image_get_result() -> Vec<u8>;
+    /// Runs an LLM and returns immediately; callers should concatinate results of generate_text_next_token(job) until it returns Ok(None) for the reply.
+    /// messages is the conversation so far, oldest first, and must end with a TextRole::User message; to continue a conversation send its
+    /// earlier turns again, with each previous reply as a TextRole::Assistant message.
+    /// Pass TextSampling::default() to use the backend's default sampling settings.
+    async fn generate_text_begin(messages: Vec<TextMessage>, sampling: TextSampling) -> Result<JobId, OlianaError>;
+    /// Returns Ok(None) when token generation is complete, and Ok(Some("")) if no new text arrived within NEXT_TOKEN_LONG_POLL_MS (eg while the job is still waiting in the queue); callers should simply ask again
+    /// How far the reply has been read is kept per connection, so every connection reading the same job (eg a client which re-connected,
+    /// or a second device following a session) gets the whole reply from its first token; a connection reading it again after Ok(None), or after the job was cancelled, starts over.
+    async fn generate_text_next_token(job: JobId) -> Result<Option<String>, OlianaError>;
+
+    /// Runs an AI model and returns immediately; callers should wait on generate_image_get_result(job) to read the .png bytes back.
+    /// With transparent_background the .png is RGBA and everything but the prompt's subject has alpha 0, eg for character sprites.
+    /// options sets the size, how many images to make and the seed; pass ImageOptions::default() for one 1024x1024 image from a random seed.
+    async fn generate_image_begin(prompt: String, negative_prompt: String, guidance_scale: f32, num_inference_steps: u32, transparent_background: bool, options: ImageOptions) -> Result<JobId, OlianaError>;
+    /// Like generate_image_begin(), but repaints init.png towards the prompt instead of starting from noise (img2img), or only its masked part
+    /// when init.mask_png is set (inpainting). The output has options' size, so init images of another size are stretched to it first.
+    async fn edit_image_begin(prompt: String, negative_prompt: String, guidance_scale: f32, num_inference_steps: u32, transparent_background: bool, options: ImageOptions, init: InitImage) -> Result<JobId, OlianaError>;
+    /// Returns true if image gen is complete (successfully or not), else false. Useful for determining when generate_image_get_result(job) can be called w/o waiting
+    async fn generate_image_result_exists(job: JobId) -> Result<bool, OlianaError>;
+    /// Waits until image has completed and returns options.num_images images, each with the seed which makes it again. There is no server-side time limit while the backend is alive, so callers with short RPC deadlines should wait for generate_image_result_exists(job) first.
+    async fn generate_image_get_result(job: JobId) -> Result<Vec<GeneratedImage>, OlianaError>;
+    /// Returns the job's previews in step order while it diffuses, each once, then Ok(None) once the job is done and the final image may be read with
+    /// generate_image_get_result(job). Returns a preview with an empty png if none arrived within NEXT_TOKEN_LONG_POLL_MS; callers should simply ask again.
+    /// Like generate_text_next_token(), every connection gets every preview.
+    async fn generate_image_next_preview(job: JobId) -> Result<Option<ImagePreview>, OlianaError>;
+
+    /// Starts a new game session; any connection which knows the returned SessionId may continue it, including after a server restart.
+    async fn session_create(settings: SessionSettings) -> Result<SessionId, OlianaError>;
+    /// Returns the session's settings and conversation so far. If a reply is still being generated, pending_turn names its job, which may be read with generate_text_next_token(job).
+    async fn session_resume(session: SessionId) -> Result<Session, OlianaError>;
+    /// Every session on the server, most recently played first.
+    async fn session_list() -> Result<Vec<SessionSummary>, OlianaError>;
+    /// Replaces the session's settings; they apply from the next session_say() on.
+    async fn session_set_settings(session: SessionId, settings: SessionSettings) -> Result<(), OlianaError>;
+    /// Forgets the session, cancelling any reply still being generated for it.
+    async fn session_delete(session: SessionId) -> Result<(), OlianaError>;
+    /// Begins the session's next turn: a text job replying to user_message after the conversation so far, read with generate_text_next_token(job) like any other.
+    /// The reply joins the session once the job finishes or is cancelled (keeping whatever was generated by then); until then further calls for the same session fail.
+    async fn session_say(session: SessionId, user_message: String) -> Result<JobId, OlianaError>;
+
+    /// Asks the backend working on job to stop early. Jobs which were cancelled report OlianaError::Cancelled from then on.
+    async fn cancel(job: JobId) -> Result<(), OlianaError>;
+
+    /// Sets the priority of every job this connection begins from now on; connections start out as JobPriority::Batch.
+    async fn set_job_priority(priority: JobPriority) -> Result<(), OlianaError>;
+    /// Reports how many jobs will be handed to the job's backend before it, or whether it is already running.
+    async fn job_queue_position(job: JobId) -> Result<QueuePosition, OlianaError>;
+    /// Reports each backend process by binary name: whether it is starting, healthy, waiting to be restarted or has failed for good, and how it last exited.
+    async fn backend_status() -> Result<std::collections::HashMap<String, ProcStatus>, OlianaError>;
+    /// Returns the lines backend (eg "oliana_text") wrote to stdout and stderr after the line numbered after_seq, oldest first; pass 0 for
+    /// everything the server still holds, then the last line's seq to follow along. Waits up to NEXT_TOKEN_LONG_POLL_MS for a new line before returning none.
+    async fn backend_log(backend: String, after_seq: u64) -> Result<Vec<LogLine>, OlianaError>;
 
    /// Reads PCI data from the host the server is running on & returns a list of hardware attached
-    async fn fetch_pci_hw_device_names() -> Vec<String>;
+    async fn fetch_pci_hw_device_names() -> Result<Vec<String>, OlianaError>;
+
+}
 
+// Every RPC failure is reported as one of these so clients can tell the user why something did not work instead of getting an empty reply.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum OlianaError {
+    /// The backend process (eg oliana_text) is not running; usually it is crashing at start-up, see the server's log output.
+    BackendDown { backend: String },
+    /// The caller passed arguments the server or backend cannot use.
+    InvalidParams(String),
+    /// The backend tried and failed; traceback holds everything it wrote about the failure, and retryable whether submitting
+    /// the same job again might succeed.
+    BackendException { backend: String, kind: oliana_lib::jobs::JobErrorKind, message: String, traceback: String, retryable: bool },
+    /// The backend ran out of (GPU) memory; smaller images, fewer images per job or waiting for other jobs to finish may help.
+    OutOfMemory { backend: String, message: String },
+    /// The server failed to read or write something in its work directories.
+    Io(String),
+    /// Someone called cancel() on the job before it finished.
+    Cancelled,
+    /// Too many jobs are already waiting; try again once some have finished.
+    QueueFull { max_depth: usize },
+}
+
+impl std::fmt::Display for OlianaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OlianaError::BackendDown { backend } => write!(f, "{} is not running", backend),
+            OlianaError::InvalidParams(msg) => write!(f, "Invalid parameters: {}", msg),
+            OlianaError::BackendException { backend, message, retryable, .. } => write!(f, "{} failed: {}{}", backend, message, if *retryable { " (retrying may help)" } else { "" }),
+            OlianaError::Io(msg) => write!(f, "Server IO error: {}", msg),
+            OlianaError::Cancelled => write!(f, "The job was cancelled"),
+            OlianaError::QueueFull { max_depth } => write!(f, "The server already has {} jobs waiting, try again later", max_depth),
+            OlianaError::OutOfMemory { backend, message } => write!(f, "{} ran out of memory: {}", backend, message),
+        }
+    }
+}
+
+impl std::error::Error for OlianaError { }
+
+impl From<std::io::Error> for OlianaError {
+    fn from(e: std::io::Error) -> Self {
+        OlianaError::Io(format!("{:?}", e))
+    }
+}
+
+impl OlianaError {
+    // Turns the NAME.error.json a backend wrote for a failed job into the error its caller gets; see oliana_lib::jobs::JobError.
+    pub fn from_job_error(backend: &str, job_error: oliana_lib::jobs::JobError) -> Self {
+        match job_error.kind {
+            oliana_lib::jobs::JobErrorKind::InvalidInput => OlianaError::InvalidParams(job_error.message),
+            oliana_lib::jobs::JobErrorKind::BackendDown => OlianaError::BackendDown {
+                backend: backend.to_string(),
+            },
+            oliana_lib::jobs::JobErrorKind::OutOfMemory => OlianaError::OutOfMemory {
+                backend: backend.to_string(),
+                message: job_error.message,
+            },
+            kind => OlianaError::BackendException {
+                backend: backend.to_string(),
+                kind: kind,
+                message: job_error.message,
+                traceback: job_error.traceback,
+                retryable: job_error.retryable,
+            },
+        }
+    }
+
+    // Whether the same call might succeed if made again later, eg once the backend has restarted or the queue has room.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            OlianaError::BackendDown { .. } | OlianaError::QueueFull { .. } | OlianaError::OutOfMemory { .. } => true,
+            OlianaError::BackendException { retryable, .. } => *retryable,
+            OlianaError::InvalidParams(_) | OlianaError::Io(_) | OlianaError::Cancelled => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum JobKind {
+    Text,
+    Image,
+}
+
+// A JobId names one .json file in either the text or the image work directory; nonce is the file stem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct JobId {
+    pub kind: JobKind,
+    pub nonce: u64,
+}
+
+impl JobKind {
+    // Name of the tracked process which handles this kind of job
+    pub fn backend_bin_name(&self) -> &'static str {
+        match self {
+            JobKind::Text => "oliana_text",
+            JobKind::Image => "oliana_images",
+        }
+    }
+}
+
+impl std::fmt::Display for JobId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            JobKind::Text => write!(f, "text-{}", self.nonce),
+            JobKind::Image => write!(f, "image-{}", self.nonce),
+        }
+    }
+}
+
+// Parses the Display form, eg "text-12" or "image-3", so clients can pass job IDs around on the command line.
+impl std::str::FromStr for JobId {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind_s, nonce_s) = s.rsplit_once('-').ok_or_else(|| format!("Expected a job ID like text-12 or image-3, got {:?}", s))?;
+        let kind = match kind_s {
+            "text" => JobKind::Text,
+            "image" => JobKind::Image,
+            unk => return Err(format!("Unknown job kind {:?} in {:?}", unk, s)),
+        };
+        let nonce = nonce_s.parse::<u64>().map_err(|e| format!("{:?} in {:?}", e, s))?;
+        Ok(JobId { kind, nonce })
+    }
+}
+
+// One finished image of an image job. Passing seed back in ImageOptions (with num_images 1 and the same prompts and settings) makes the same image again.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct GeneratedImage {
+    pub seed: u64,
+    pub png: Vec<u8>,
+}
+
+// The picture an edit_image_begin() job starts from.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct InitImage {
+    pub png: Vec<u8>,
+    pub strength: f32, // How much of png to repaint, above 0 and at most 1; see oliana_lib::jobs::DEFAULT_IMAGE_STRENGTH
+    pub mask_png: Option<Vec<u8>>, // White where png should be repainted and black where it should be kept; None repaints all of it
+}
+
+// An image job's picture part-way through diffusion; png holds the NAME.step-N.png bytes.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ImagePreview {
+    pub step: u32,
+    pub png: Vec<u8>,
+}
+
+// State shared by every connection; one of these is created by the server binary and handed to each OlianaServer.
+#[derive(Debug, Default)]
+pub struct SharedJobState {
+    pub next_job_nonce: u64,
+    pub text_read_offsets: std::collections::HashMap<(std::net::SocketAddr, u64), usize>, // How far into each job's output .txt file each connection has read; see OlianaServer::forget_job_reads()
+    pub image_preview_read_steps: std::collections::HashMap<(std::net::SocketAddr, u64), u32>, // The step of the last preview generate_image_next_preview() returned to each connection for each image job
+    pub image_preview_interval: u32, // Copied into every ImageJob; the server binary sets it to DEFAULT_IMAGE_PREVIEW_INTERVAL unless overridden
+    pub queue: job_queue::JobQueue,
+    pub client_priorities: std::collections::HashMap<std::net::SocketAddr, JobPriority>, // Set by set_job_priority(), read when a connection begins a job
+    pub image_backend_policy: ImageBackendPolicy,
+    pub sessions: sessions::SessionStore,
+}
+
+impl SharedJobState {
+    // Called by the server binary once a client's connection closes, so per-connection state does not pile up over the server's life.
+    pub fn forget_client(&mut self, client: std::net::SocketAddr) {
+        self.client_priorities.remove(&client);
+        self.text_read_offsets.retain(|(reader, _), _| *reader != client);
+        self.image_preview_read_steps.retain(|(reader, _), _| *reader != client);
+        self.queue.forget_client(client);
+    }
+}
+
+// The files describing job inside workdir; see oliana_lib::jobs for what each of them holds.
+pub fn job_files(workdir: &str, job: JobId) -> oliana_lib::jobs::JobFiles {
+    oliana_lib::jobs::JobFiles::new(workdir, &job.nonce.to_string())
+}
+
+pub fn job_is_finished(job_files: &oliana_lib::jobs::JobFiles) -> bool {
+    job_files.read_state().map(|state| state.is_finished()).unwrap_or(false)
 }
 
 // This is the type that implements the generated World trait. It is the business logic
@@ -44,15 +279,18 @@ pub struct OlianaServer {
     #[serde(skip)]
     pub ai_workdir_text: String,
 
-    pub text_input_nonce: std::sync::Arc<std::sync::RwLock<usize>>,
-    pub generate_text_next_byte_i: std::sync::Arc<std::sync::RwLock<usize>>, // Keeps track of how far into the output .txt file we have read for streaming purposes
+    #[serde(skip)]
+    pub shared_jobs: std::sync::Arc<std::sync::RwLock<SharedJobState>>,
 
-    pub image_input_nonce: std::sync::Arc<std::sync::RwLock<usize>>,
+    #[serde(skip)]
+    pub workdir_watcher: std::sync::Arc<oliana_lib::watch::WorkdirWatcher>, // Watches both work directories; RPCs wait on this instead of polling job files
 }
 
 impl OlianaServer {
     pub fn new(client_socket: std::net::SocketAddr,
                shareable_procs: std::sync::Arc<std::sync::RwLock<oliana_lib::launchers::TrackedProcs>>,
+               shared_jobs: std::sync::Arc<std::sync::RwLock<SharedJobState>>,
+               workdir_watcher: std::sync::Arc<oliana_lib::watch::WorkdirWatcher>,
                ai_workdir_images: &str,
                ai_workdir_text: &str
         ) -> Self {
@@ -63,64 +301,324 @@ impl OlianaServer {
             ai_workdir_images: ai_workdir_images.to_string(),
             ai_workdir_text: ai_workdir_text.to_string(),
 
-            text_input_nonce: std::sync::Arc::new(std::sync::RwLock::new( 0 )),
-            generate_text_next_byte_i: std::sync::Arc::new(std::sync::RwLock::new( 0 )),
+            shared_jobs: shared_jobs,
+            workdir_watcher: workdir_watcher,
+        }
+    }
+
+    pub fn get_job_workdir(&self, kind: JobKind) -> &str {
+        match kind {
+            JobKind::Text => &self.ai_workdir_text,
+            JobKind::Image => &self.ai_workdir_images,
+        }
+    }
 
-            image_input_nonce: std::sync::Arc::new(std::sync::RwLock::new( 0 )),
+    // Reserves the next nonce that has no .json file in the job's work directory; nonces are never handed out twice, even across connections.
+    pub async fn allocate_job_id(&self, kind: JobKind) -> Result<JobId, Box<dyn std::error::Error>> {
+        loop {
+            let nonce = match self.shared_jobs.write() {
+                Ok(mut shared_jobs_wg) => {
+                    let nonce = shared_jobs_wg.next_job_nonce;
+                    shared_jobs_wg.next_job_nonce += 1;
+                    nonce
+                }
+                Err(e) => {
+                    return Err(format!("{}:{} {:?}", file!(), line!(), e).into());
+                }
+            };
+            let job = JobId { kind, nonce };
+            if !tokio::fs::try_exists( self.job_files(job).input_path() ).await? {
+                return Ok(job);
+            }
+        }
+    }
 
+    // Returns Ok(()) if the job is of the expected kind, else an InvalidParams error naming the RPC that was misused.
+    pub fn check_job_kind(&self, job: JobId, expected_kind: JobKind, rpc_name: &str) -> Result<(), OlianaError> {
+        if job.kind != expected_kind {
+            return Err(OlianaError::InvalidParams(format!("{} called with job {} which is not a {:?} job", rpc_name, job, expected_kind)));
         }
+        Ok(())
     }
 
-    pub fn read_text_input_nonce(&self) -> usize {
-        let mut ret_val: usize = 0;
-        match self.text_input_nonce.read() {
-            Ok(text_input_nonce_rg) => {
-                ret_val = *text_input_nonce_rg;
+    // Called while waiting on a backend which has not written anything for a while; returns BackendDown if the process running job is not alive,
+    // so RPCs stop waiting on jobs nothing will ever finish. If the process table is busy we assume the backend is fine and check again later.
+    pub fn check_backend_running(&self, job: JobId) -> Result<(), OlianaError> {
+        let backend = self.get_job_backend(job);
+        if let Some(ref shareable_procs) = self.shareable_procs {
+            if let Ok(mut procs_wg) = shareable_procs.try_write() {
+                match procs_wg.is_named_proc_running(&backend) {
+                    Ok(true) => { }
+                    Ok(false) => return Err(OlianaError::BackendDown { backend }),
+                    Err(e) => {
+                        eprintln!("{}:{} {:?}", file!(), line!(), e);
+                    }
+                }
             }
+        }
+        Ok(())
+    }
+
+    pub fn job_files(&self, job: JobId) -> oliana_lib::jobs::JobFiles {
+        job_files(self.get_job_workdir(job.kind), job)
+    }
+
+    // Image jobs may have been routed to a fallback backend; the dispatcher records which one in the job's files.
+    pub fn get_job_backend(&self, job: JobId) -> String {
+        match self.job_files(job).read_backend() {
+            Ok(Some(backend)) => backend,
+            Ok(None) => job.kind.backend_bin_name().to_string(),
             Err(e) => {
                 eprintln!("{}:{} {:?}", file!(), line!(), e);
+                job.kind.backend_bin_name().to_string()
             }
         }
-        ret_val
     }
 
-    pub async fn increment_to_next_free_text_input_nonce(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
-        while tokio::fs::try_exists( self.get_current_text_input_json_path() ).await? {
-            if let Ok(ref mut text_input_nonce_wg) = self.text_input_nonce.write() {
-                **text_input_nonce_wg += 1;
+    pub fn get_client_priority(&self) -> JobPriority {
+        match self.shared_jobs.read() {
+            Ok(shared_jobs_rg) => shared_jobs_rg.client_priorities.get(&self.client_socket).copied().unwrap_or_default(),
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+                JobPriority::default()
             }
         }
-        Ok(self.read_text_input_nonce())
     }
-    pub fn get_current_text_input_json_path(&self) -> std::path::PathBuf {
-        std::path::Path::new(&self.ai_workdir_text).join(format!("{}.json", self.read_text_input_nonce()))
+
+    // Jobs are not written to their backend's work directory here; job_queue::dispatch_jobs_forever() does that once it is the job's turn.
+    pub fn enqueue_job(&self, job: JobId, input: oliana_lib::jobs::JobInput) -> Result<(), OlianaError> {
+        let queued_job = job_queue::QueuedJob {
+            job: job,
+            client: self.client_socket,
+            priority: self.get_client_priority(),
+            input: input,
+        };
+        match self.shared_jobs.write() {
+            Ok(mut shared_jobs_wg) => shared_jobs_wg.queue.push(queued_job)?,
+            Err(e) => return Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
+        }
+        // Wake the dispatcher so an idle backend gets the job straight away
+        self.workdir_watcher.poke();
+        Ok(())
     }
-    pub fn get_current_text_output_txt_path(&self) -> std::path::PathBuf {
-        std::path::Path::new(&self.ai_workdir_text).join(format!("{}.txt", self.read_text_input_nonce()))
+
+    // Shared by the cancel() RPC and session_delete()
+    pub fn cancel_job(&self, job: JobId) -> Result<(), OlianaError> {
+        let job_files = self.job_files(job);
+        let (removed_from_queue, is_running) = match self.shared_jobs.write() {
+            Ok(mut shared_jobs_wg) => (shared_jobs_wg.queue.remove_waiting(job), shared_jobs_wg.queue.is_running(job)),
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+                (false, false)
+            }
+        };
+        self.forget_all_job_reads(job);
+        if removed_from_queue {
+            // The backend never saw this job, so we report the cancellation on its behalf; writing the state also wakes anyone waiting on the job
+            job_files.write_state(oliana_lib::jobs::JobState::Cancelled)?;
+            return Ok(());
+        }
+        if !is_running && job_files.read_status()?.is_none() {
+            return Err(OlianaError::InvalidParams(format!("No such job {}", job)));
+        }
+        // Cancelling a finished job is a no-op
+        if job_is_finished(&job_files) {
+            return Ok(());
+        }
+        job_files.request_cancel()?;
+        Ok(())
     }
-    pub fn get_current_text_output_done_path(&self) -> std::path::PathBuf {
-        std::path::Path::new(&self.ai_workdir_text).join(format!("{}.done", self.read_text_input_nonce()))
+
+    // None while job may still write to its NAME.txt, else the job's final state (None if the job vanished, eg in a server restart) and
+    // everything it wrote. Jobs which were asked to cancel count as finished, keeping whatever they wrote by then.
+    pub fn read_finished_text_job(&self, job: JobId) -> Result<Option<(Option<oliana_lib::jobs::JobState>, String)>, OlianaError> {
+        let job_files = self.job_files(job);
+        let final_state = job_files.read_state();
+        let job_vanished = self.get_queue_position(job) == QueuePosition::NotQueued && job_files.read_status()?.is_none();
+        if !(final_state.map(|state| state.is_finished()).unwrap_or(false) || job_files.is_cancel_requested() || job_vanished) {
+            return Ok(None);
+        }
+        let text = match std::fs::read_to_string(job_files.text_output_path()) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some((final_state, text)))
     }
 
-    pub fn read_generate_text_next_byte_i(&self) -> usize {
-        let mut ret_val: usize = 0;
-        match self.generate_text_next_byte_i.read() {
-            Ok(generate_text_next_byte_i_rg) => {
-                ret_val = *generate_text_next_byte_i_rg;
+    // Moves a session's pending turn into its messages once the turn's job is finished or has been asked to cancel, keeping whatever reply
+    // was written by then. Turns which produced no text are dropped, so the conversation never holds a user message without a reply.
+    pub fn settle_pending_turn(&self, session: SessionId) -> Result<(), OlianaError> {
+        let pending_turn = match self.shared_jobs.read() {
+            Ok(shared_jobs_rg) => shared_jobs_rg.sessions.get(session)?.pending_turn.clone(),
+            Err(e) => return Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
+        };
+        let pending_turn = match pending_turn {
+            Some(pending_turn) => pending_turn,
+            None => return Ok(()),
+        };
+        let reply = match self.read_finished_text_job(pending_turn.job)? {
+            Some((_final_state, reply)) => reply,
+            None => return Ok(()),
+        };
+        match self.shared_jobs.write() {
+            Ok(mut shared_jobs_wg) => {
+                let session_state = shared_jobs_wg.sessions.get_mut(session)?;
+                // Another connection may have settled this turn while we read the reply
+                if session_state.pending_turn.as_ref() != Some(&pending_turn) {
+                    return Ok(());
+                }
+                session_state.pending_turn = None;
+                if reply.trim().len() > 0 {
+                    session_state.messages.push(oliana_lib::jobs::TextMessage::user(pending_turn.user_message));
+                    session_state.messages.push(oliana_lib::jobs::TextMessage::assistant(reply));
+                }
+                shared_jobs_wg.sessions.save(session)
+            }
+            Err(e) => Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
+        }
+    }
+
+    // Once a session's summary job is Done its text becomes the story so far and the messages it covers are dropped. Summaries which
+    // failed or were cancelled are forgotten, and maybe_begin_summary() tries again after the next turn.
+    pub fn settle_pending_summary(&self, session: SessionId) -> Result<(), OlianaError> {
+        let pending_summary = match self.shared_jobs.read() {
+            Ok(shared_jobs_rg) => shared_jobs_rg.sessions.get(session)?.pending_summary.clone(),
+            Err(e) => return Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
+        };
+        let pending_summary = match pending_summary {
+            Some(pending_summary) => pending_summary,
+            None => return Ok(()),
+        };
+        let (final_state, summary) = match self.read_finished_text_job(pending_summary.job)? {
+            Some(finished_job) => finished_job,
+            None => return Ok(()),
+        };
+        match self.shared_jobs.write() {
+            Ok(mut shared_jobs_wg) => {
+                let session_state = shared_jobs_wg.sessions.get_mut(session)?;
+                if session_state.pending_summary.as_ref() != Some(&pending_summary) {
+                    return Ok(());
+                }
+                session_state.pending_summary = None;
+                if final_state == Some(oliana_lib::jobs::JobState::Done) && summary.trim().len() > 0 {
+                    session_state.story_so_far = summary.trim().to_string();
+                    let num_messages = pending_summary.num_messages.min(session_state.messages.len());
+                    session_state.messages.drain(..num_messages);
+                }
+                else {
+                    eprintln!("Summary job {} for {} ended as {:?}; it will be retried after the next turn", pending_summary.job, session, final_state);
+                }
+                shared_jobs_wg.sessions.save(session)
+            }
+            Err(e) => Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
+        }
+    }
+
+    // Queues a SummaryJob for the session if its conversation has outgrown the context token budget and no summary is already on the way.
+    // Called after a turn is queued, so the player's reply is not held up behind the summary.
+    pub async fn maybe_begin_summary(&self, session: SessionId) -> Result<(), OlianaError> {
+        let (num_messages, summary_job) = match self.shared_jobs.read() {
+            Ok(shared_jobs_rg) => {
+                let session_state = shared_jobs_rg.sessions.get(session)?;
+                if session_state.pending_summary.is_some() {
+                    return Ok(());
+                }
+                let fixed_tokens = session_state.system_message().as_ref().map(history::message_tokens).unwrap_or(0);
+                match history::messages_to_summarize(fixed_tokens, &session_state.messages, shared_jobs_rg.sessions.context_token_budget) {
+                    Some(num_messages) => (num_messages, oliana_lib::jobs::SummaryJob {
+                        story_so_far: session_state.story_so_far.clone(),
+                        summarize: session_state.messages[..num_messages].to_vec(),
+                        max_tokens: history::SUMMARY_MAX_TOKENS,
+                    }),
+                    None => return Ok(()),
+                }
             }
+            Err(e) => return Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
+        };
+
+        let job = match self.allocate_job_id(JobKind::Text).await {
+            Ok(job) => job,
             Err(e) => {
-                eprintln!("{}:{} {:?}", file!(), line!(), e);
+                eprintln!("[ allocate_job_id ] {:?}", e);
+                return Err(OlianaError::Io(format!("[ allocate_job_id ] {:?}", e)));
+            }
+        };
+        let queued_job = job_queue::QueuedJob {
+            job: job,
+            client: self.client_socket,
+            priority: self.get_client_priority(),
+            input: oliana_lib::jobs::JobInput::Summary(summary_job),
+        };
+        match self.shared_jobs.write() {
+            Ok(mut shared_jobs_wg) => {
+                if shared_jobs_wg.sessions.get(session)?.pending_summary.is_some() {
+                    return Ok(()); // Another connection got there first
+                }
+                shared_jobs_wg.queue.push(queued_job)?;
+                shared_jobs_wg.sessions.get_mut(session)?.pending_summary = Some(sessions::PendingSummary {
+                    job: job,
+                    num_messages: num_messages,
+                });
+                shared_jobs_wg.sessions.save(session)?;
             }
+            Err(e) => return Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
         }
-        ret_val
+        println!("Summarizing the oldest {} messages of {} in job {}", num_messages, session, job);
+        self.workdir_watcher.poke();
+        Ok(())
     }
 
+    // Shared by generate_image_begin() and edit_image_begin(): checks image_job, picks its seed and queues it.
+    pub async fn begin_image_job(&self, mut image_job: oliana_lib::jobs::ImageJob) -> Result<JobId, OlianaError> {
+        if image_job.prompt.trim().len() < 1 {
+            return Err(OlianaError::InvalidParams("prompt must not be empty".into()));
+        }
+        if !image_job.guidance_scale.is_finite() || image_job.guidance_scale < 0.0 {
+            return Err(OlianaError::InvalidParams(format!("guidance_scale must be a positive number, got {}", image_job.guidance_scale)));
+        }
+        if image_job.num_inference_steps < 1 {
+            return Err(OlianaError::InvalidParams("num_inference_steps must be at least 1".into()));
+        }
+        image_job.options.validate().map_err(OlianaError::InvalidParams)?;
+        image_job.validate_mode().map_err(OlianaError::InvalidParams)?;
+        // Picked here rather than by the backend so we can report it with the images
+        if image_job.options.seed.is_none() {
+            image_job.options.seed = Some(oliana_lib::jobs::random_image_seed());
+        }
 
-    pub fn read_image_input_nonce(&self) -> usize {
+        let job = match self.allocate_job_id(JobKind::Image).await {
+            Ok(job) => job,
+            Err(e) => {
+                eprintln!("[ allocate_job_id ] {:?}", e);
+                return Err(OlianaError::Io(format!("[ allocate_job_id ] {:?}", e)));
+            }
+        };
+
+        image_job.preview_interval = match self.shared_jobs.read() {
+            Ok(shared_jobs_rg) => shared_jobs_rg.image_preview_interval,
+            Err(e) => return Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
+        };
+        self.enqueue_job(job, oliana_lib::jobs::JobInput::Image(image_job))?;
+
+        Ok(job)
+    }
+
+    pub fn get_queue_position(&self, job: JobId) -> QueuePosition {
+        match self.shared_jobs.read() {
+            Ok(shared_jobs_rg) => shared_jobs_rg.queue.position(job),
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+                QueuePosition::NotQueued
+            }
+        }
+    }
+
+    pub fn read_text_read_offset(&self, job: JobId) -> usize {
         let mut ret_val: usize = 0;
-        match self.image_input_nonce.read() {
-            Ok(image_input_nonce_rg) => {
-                ret_val = *image_input_nonce_rg;
+        match self.shared_jobs.read() {
+            Ok(shared_jobs_rg) => {
+                ret_val = *shared_jobs_rg.text_read_offsets.get(&(self.client_socket, job.nonce)).unwrap_or(&0);
             }
             Err(e) => {
                 eprintln!("{}:{} {:?}", file!(), line!(), e);
@@ -129,216 +627,450 @@ impl OlianaServer {
         ret_val
     }
 
-    pub async fn increment_to_next_free_image_input_nonce(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
-        while tokio::fs::try_exists( self.get_current_image_input_json_path() ).await? {
-            if let Ok(ref mut image_input_nonce_wg) = self.image_input_nonce.write() {
-                **image_input_nonce_wg += 1;
+    pub fn write_text_read_offset(&self, job: JobId, offset: usize) {
+        match self.shared_jobs.write() {
+            Ok(mut shared_jobs_wg) => {
+                shared_jobs_wg.text_read_offsets.insert((self.client_socket, job.nonce), offset);
+            }
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
             }
         }
-        Ok(self.read_image_input_nonce())
     }
-    pub fn get_current_image_input_json_path(&self) -> std::path::PathBuf {
-        std::path::Path::new(&self.ai_workdir_images).join(format!("{}.json", self.read_image_input_nonce()))
+
+    // Called once this connection has streamed everything a job will produce; if it reads the job's stream again it starts over.
+    // Other connections reading the same job keep their place.
+    pub fn forget_job_reads(&self, job: JobId) {
+        match self.shared_jobs.write() {
+            Ok(mut shared_jobs_wg) => {
+                shared_jobs_wg.text_read_offsets.remove(&(self.client_socket, job.nonce));
+                shared_jobs_wg.image_preview_read_steps.remove(&(self.client_socket, job.nonce));
+            }
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+            }
+        }
     }
-    pub fn get_current_image_output_png_path(&self) -> std::path::PathBuf {
-        std::path::Path::new(&self.ai_workdir_images).join(format!("{}.png", self.read_image_input_nonce()))
+
+    // Called once a job is cancelled, which ends its stream for every connection.
+    pub fn forget_all_job_reads(&self, job: JobId) {
+        match self.shared_jobs.write() {
+            Ok(mut shared_jobs_wg) => {
+                shared_jobs_wg.text_read_offsets.retain(|(_, nonce), _| *nonce != job.nonce);
+                shared_jobs_wg.image_preview_read_steps.retain(|(_, nonce), _| *nonce != job.nonce);
+            }
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+            }
+        }
     }
-    pub fn get_current_image_output_txt_path(&self) -> std::path::PathBuf {
-        std::path::Path::new(&self.ai_workdir_images).join(format!("{}.txt", self.read_image_input_nonce()))
+
+    // 0 until the job's first preview has been returned
+    pub fn read_image_preview_step(&self, job: JobId) -> u32 {
+        match self.shared_jobs.read() {
+            Ok(shared_jobs_rg) => *shared_jobs_rg.image_preview_read_steps.get(&(self.client_socket, job.nonce)).unwrap_or(&0),
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+                0
+            }
+        }
     }
 
+    pub fn write_image_preview_step(&self, job: JobId, step: u32) {
+        match self.shared_jobs.write() {
+            Ok(mut shared_jobs_wg) => {
+                shared_jobs_wg.image_preview_read_steps.insert((self.client_socket, job.nonce), step);
+            }
+            Err(e) => {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+            }
+        }
+    }
 
 }
 
 // These methods are run in the context of the client connection, on the server.
 impl Oliana for OlianaServer {
-    async fn generate_text_begin(mut self, _: context::Context, system_prompt: String, user_prompt: String) -> String {
+    async fn generate_text_begin(mut self, _: context::Context, messages: Vec<TextMessage>, sampling: TextSampling) -> Result<JobId, OlianaError> {
+        let text_job = oliana_lib::jobs::TextJob {
+            messages: messages,
+            sampling: sampling,
+        };
+        text_job.validate().map_err(OlianaError::InvalidParams)?;
 
-        if let Ok(ref mut generate_text_next_byte_i_wg) = self.generate_text_next_byte_i.write() {
-            **generate_text_next_byte_i_wg = 0;
-        }
+        let job = match self.allocate_job_id(JobKind::Text).await {
+            Ok(job) => job,
+            Err(e) => {
+                eprintln!("[ allocate_job_id ] {:?}", e);
+                return Err(OlianaError::Io(format!("[ allocate_job_id ] {:?}", e)));
+            }
+        };
 
-        if let Err(e) = self.increment_to_next_free_text_input_nonce().await {
-            eprintln!("[ increment_to_next_free_text_input_nonce ] {:?}", e);
-            return format!("[ increment_to_next_free_text_input_nonce ] {:?}", e);
-        }
+        self.enqueue_job(job, oliana_lib::jobs::JobInput::Text(text_job))?;
+
+        Ok(job)
+    }
 
-        let input_data = serde_json::json!({
-            "system_prompt": system_prompt,
-            "user_prompt": user_prompt
-        });
-        let input_data_s = input_data.to_string();
+    async fn generate_text_next_token(mut self, _: context::Context, job: JobId) -> Result<Option<String>, OlianaError> {
+        self.check_job_kind(job, JobKind::Text, "generate_text_next_token")?;
+        let job_files = self.job_files(job);
+        let response_txt_file = job_files.text_output_path();
 
-        let current_text_input_json = self.get_current_text_input_json_path();
+        // Subscribe before the first look at the job's files, so a change landing between that look and the wait below still wakes us
+        let mut workdir_changes = self.workdir_watcher.subscribe();
+        let long_poll_deadline = tokio::time::Instant::now() + tokio::time::Duration::from_millis(NEXT_TOKEN_LONG_POLL_MS);
+        loop {
+            // A job waiting in the queue has no files in the work directory yet, so there is nothing to read
+            if !matches!(self.get_queue_position(job), QueuePosition::Waiting { .. }) {
+                // Read the state before the text; oliana_text finishes writing NAME.txt before it marks the job Done, so a Done read here means the read below gets every remaining byte.
+                let status = job_files.read_status()?;
+                let next_byte_i = self.read_text_read_offset(job);
+                match tokio::fs::read(&response_txt_file).await {
+                   Ok(file_bytes) => {
+                    if file_bytes.len() < next_byte_i {
+                        // Somehow the file was truncated! .len() should always grow; it is allowed to be == next_byte_i.
+                        return Err(OlianaError::Io(format!("{} shrank from {} to {} bytes while being read", response_txt_file.display(), next_byte_i, file_bytes.len())));
+                    }
+                    if let Ok(the_string) = std::str::from_utf8(&file_bytes[next_byte_i..]) {
 
-        let response_txt_file = self.get_current_text_output_txt_path();
-        if response_txt_file.exists() {
-            if let Err(e) = tokio::fs::remove_file(response_txt_file).await {
-                eprintln!("[ tokio::fs::remove_file ] {:?}", e);
-                return format!("[ tokio::fs::remove_file ] {:?}", e);
+                        // Update the index we know we have read to to file_bytes.len()
+                        self.write_text_read_offset(job, file_bytes.len());
+
+                        // It's possible to read 0 new bytes, in which case we do NOT want to return empty string; instead we fall down to the state checks below.
+                        if the_string.len() > 0 {
+                            return Ok(Some(the_string.to_string()));
+                        }
+                    }
+                  }
+                  Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    // The backend has not picked the job up yet
+                  }
+                  Err(e) => {
+                    eprintln!("{}:{} {:?}", file!(), line!(), e);
+                  }
+                }
+                if status.as_ref().map(|status| status.state.is_finished()).unwrap_or(false) {
+                    self.forget_job_reads(job);
+                }
+                match status {
+                    Some(oliana_lib::jobs::JobStatus { state: oliana_lib::jobs::JobState::Done, .. }) => {
+                        break;
+                    }
+                    Some(oliana_lib::jobs::JobStatus { state: oliana_lib::jobs::JobState::Cancelled, .. }) => {
+                        return Err(OlianaError::Cancelled);
+                    }
+                    Some(oliana_lib::jobs::JobStatus { state: oliana_lib::jobs::JobState::Failed, .. }) => {
+                        return Err(OlianaError::from_job_error(JobKind::Text.backend_bin_name(), job_files.read_error()?));
+                    }
+                    Some(_) => { } // Queued or Running
+                    None => {
+                        return Err(OlianaError::InvalidParams(format!("No such job {}", job)));
+                    }
+                }
+            }
+            let now = tokio::time::Instant::now();
+            if now >= long_poll_deadline {
+                // Nothing new; hand control back to the caller so it can ask again before its RPC deadline passes
+                self.check_backend_running(job)?;
+                return Ok(Some(String::new()));
             }
+            workdir_changes.wait(long_poll_deadline - now).await;
         }
+        return Ok(None);
+    }
 
-        if let Err(e) = tokio::fs::write(current_text_input_json, input_data_s.as_bytes()).await {
-            eprintln!("[ tokio::fs::write ] {:?}", e);
-            return format!("[ tokio::fs::write ] {:?}", e);
-        }
+    async fn generate_image_begin(self, _: tarpc::context::Context, prompt: String, negative_prompt: String, guidance_scale: f32, num_inference_steps: u32, transparent_background: bool, options: ImageOptions) -> Result<JobId, OlianaError> {
+        self.begin_image_job(oliana_lib::jobs::ImageJob {
+            prompt: prompt,
+            negative_prompt: negative_prompt,
+            guidance_scale: guidance_scale,
+            num_inference_steps: num_inference_steps,
+            transparent_background: transparent_background,
+            options: options,
+            ..oliana_lib::jobs::ImageJob::default()
+        }).await
+    }
 
-        String::new()
+    async fn edit_image_begin(self, _: tarpc::context::Context, prompt: String, negative_prompt: String, guidance_scale: f32, num_inference_steps: u32, transparent_background: bool, options: ImageOptions, init: InitImage) -> Result<JobId, OlianaError> {
+        let mode = match init.mask_png {
+            Some(_) => oliana_lib::jobs::ImageMode::Inpaint,
+            None => oliana_lib::jobs::ImageMode::Img2Img,
+        };
+        self.begin_image_job(oliana_lib::jobs::ImageJob {
+            prompt: prompt,
+            negative_prompt: negative_prompt,
+            guidance_scale: guidance_scale,
+            num_inference_steps: num_inference_steps,
+            transparent_background: transparent_background,
+            options: options,
+            mode: mode,
+            strength: init.strength,
+            init_png: init.png,
+            mask_png: init.mask_png.unwrap_or_default(),
+            ..oliana_lib::jobs::ImageJob::default()
+        }).await
     }
 
-    async fn generate_text_next_token(mut self, _: context::Context) -> Option<String> {
-        // Right now we just wait for get_current_text_output_txt_path() to be created + return one giant chunk, but eventually Oliana-Text should iteratively update the file
-        // so we can poll & return a streamed response.
-        let response_txt_file = self.get_current_text_output_txt_path();
+    async fn generate_image_result_exists(self, _: tarpc::context::Context, job: JobId) -> Result<bool, OlianaError> {
+        self.check_job_kind(job, JobKind::Image, "generate_image_result_exists")?;
+        return Ok(job_is_finished(&self.job_files(job)));
+    }
 
-        let mut remaining_polls_before_give_up: usize = 3 * 10; // 3 seconds worth at 10 polls/sec
-        while !response_txt_file.exists() && remaining_polls_before_give_up > 0 {
-            tokio::time::sleep( tokio::time::Duration::from_millis(100) ).await;
-            remaining_polls_before_give_up -= 1;
+    async fn generate_image_get_result(self, _: tarpc::context::Context, job: JobId) -> Result<Vec<GeneratedImage>, OlianaError> {
+        self.check_job_kind(job, JobKind::Image, "generate_image_get_result")?;
+        let job_files = self.job_files(job);
+
+        // Subscribe before the first look at the job's files, so a change landing between that look and the wait below still wakes us
+        let mut workdir_changes = self.workdir_watcher.subscribe();
+        let mut last_liveness_check = tokio::time::Instant::now();
+        loop {
+            // A job waiting in the queue has no files in the work directory yet
+            if !matches!(self.get_queue_position(job), QueuePosition::Waiting { .. }) {
+                match job_files.read_status()? {
+                    Some(status) if !status.state.is_finished() => { } // Queued or Running
+                    _ => break, // Finished, or no such job
+                }
+                if last_liveness_check.elapsed() >= tokio::time::Duration::from_millis(BACKEND_LIVENESS_CHECK_MS) {
+                    self.check_backend_running(job)?;
+                    last_liveness_check = tokio::time::Instant::now();
+                }
+            }
+            workdir_changes.wait( tokio::time::Duration::from_millis(BACKEND_LIVENESS_CHECK_MS) ).await;
         }
-        if !response_txt_file.exists() {
-            return None;
+
+        match job_files.read_status()? {
+            Some(oliana_lib::jobs::JobStatus { state: oliana_lib::jobs::JobState::Done, .. }) => {
+                // oliana_images renames every finished .png into place before marking the job Done, so they are complete by now
+                let image_job: oliana_lib::jobs::ImageJob = job_files.read_input()?;
+                let first_seed = image_job.options.seed.unwrap_or_default(); // Always set by generate_image_begin()
+                let mut images = Vec::with_capacity(image_job.options.num_images as usize);
+                for image_i in 0..image_job.options.num_images {
+                    images.push(GeneratedImage {
+                        seed: first_seed + image_i as u64,
+                        png: tokio::fs::read(job_files.nth_image_output_path(image_i)).await?,
+                    });
+                }
+                Ok(images)
+            }
+            Some(oliana_lib::jobs::JobStatus { state: oliana_lib::jobs::JobState::Cancelled, .. }) => {
+                Err(OlianaError::Cancelled)
+            }
+            Some(oliana_lib::jobs::JobStatus { state: oliana_lib::jobs::JobState::Failed, .. }) => {
+                let job_error = job_files.read_error()?;
+                let backend = self.get_job_backend(job);
+                eprintln!("Got error from {}: {:?}", backend, job_error);
+                Err(OlianaError::from_job_error(&backend, job_error))
+            }
+            Some(_) | None => { // The loop above only stops early for jobs which do not exist
+                Err(OlianaError::InvalidParams(format!("No such job {}", job)))
+            }
         }
+    }
 
-        let response_done_file = self.get_current_text_output_done_path();
+    async fn generate_image_next_preview(self, _: tarpc::context::Context, job: JobId) -> Result<Option<ImagePreview>, OlianaError> {
+        self.check_job_kind(job, JobKind::Image, "generate_image_next_preview")?;
+        let job_files = self.job_files(job);
 
-        // Wait until the file's size is > self.read_generate_text_next_byte_i()
-        let mut remaining_polls_before_give_up: isize = 3 * 10; // 3 seconds worth at 10 polls/sec
+        // Subscribe before the first look at the job's files, so a change landing between that look and the wait below still wakes us
+        let mut workdir_changes = self.workdir_watcher.subscribe();
+        let long_poll_deadline = tokio::time::Instant::now() + tokio::time::Duration::from_millis(NEXT_TOKEN_LONG_POLL_MS);
         loop {
-            let next_byte_i = self.read_generate_text_next_byte_i();
-            match tokio::fs::read(&response_txt_file).await {
-               Ok(file_bytes) => {
-                if file_bytes.len() < next_byte_i {
-                    return None; // Somehow the file was truncated! .len() should always grow; it is allowed to be == next_byte_i.
+            // A job waiting in the queue has no files in the work directory yet, so there is nothing to read
+            if !matches!(self.get_queue_position(job), QueuePosition::Waiting { .. }) {
+                // Read the state before listing previews; backends write their last preview before NAME.png, so once the job is Done the list below is complete.
+                let status = job_files.read_status()?;
+                let last_step = self.read_image_preview_step(job);
+                if let Some((step, preview_path)) = job_files.list_previews()?.into_iter().find(|(step, _)| *step > last_step) {
+                    let png = tokio::fs::read(&preview_path).await?;
+                    self.write_image_preview_step(job, step);
+                    return Ok(Some(ImagePreview {
+                        step: step,
+                        png: png,
+                    }));
                 }
-                if let Ok(the_string) = std::str::from_utf8(&file_bytes[next_byte_i..]) {
-
-                    // Update the index we know we have read to to file_bytes.len()
-                    match self.generate_text_next_byte_i.write() {
-                        Ok(mut generate_text_next_byte_i_wg) => {
-                            *generate_text_next_byte_i_wg = file_bytes.len();
-                        }
-                        Err(e) => {
-                            eprintln!("{}:{} {:?}", file!(), line!(), e);
-                        }
+                if status.as_ref().map(|status| status.state.is_finished()).unwrap_or(false) {
+                    self.forget_job_reads(job);
+                }
+                match status {
+                    Some(oliana_lib::jobs::JobStatus { state: oliana_lib::jobs::JobState::Done, .. }) => {
+                        return Ok(None);
                     }
-
-                    // It's possible to read 0 new bytes, in which case we do NOT want to return empty string; instead we fall down to the `response_done_file.exists() || remaining_polls_before_give_up < 1` check below.
-                    if the_string.len() > 0 {
-                        return Some(the_string.to_string());
+                    Some(oliana_lib::jobs::JobStatus { state: oliana_lib::jobs::JobState::Cancelled, .. }) => {
+                        return Err(OlianaError::Cancelled);
                     }
-                    else {
-                        remaining_polls_before_give_up -= 1; // Count a stale read as an error
+                    Some(oliana_lib::jobs::JobStatus { state: oliana_lib::jobs::JobState::Failed, .. }) => {
+                        return Err(OlianaError::from_job_error(&self.get_job_backend(job), job_files.read_error()?));
+                    }
+                    Some(_) => { } // Queued or Running
+                    None => {
+                        return Err(OlianaError::InvalidParams(format!("No such job {}", job)));
                     }
                 }
-              }
-              Err(e) => {
-                eprintln!("{}:{} {:?}", file!(), line!(), e);
-                remaining_polls_before_give_up -= 1;
-              }
             }
-            if response_done_file.exists() || remaining_polls_before_give_up < 1 { // What we just read must be the remaining bytes, because .done is created AFTER a write to .txt
-                break;
+            let now = tokio::time::Instant::now();
+            if now >= long_poll_deadline {
+                // Nothing new; hand control back to the caller so it can ask again before its RPC deadline passes
+                self.check_backend_running(job)?;
+                return Ok(Some(ImagePreview {
+                    step: self.read_image_preview_step(job),
+                    png: vec![],
+                }));
             }
-            tokio::time::sleep( tokio::time::Duration::from_millis(100) ).await;
-            remaining_polls_before_give_up -= 1;
+            workdir_changes.wait(long_poll_deadline - now).await;
         }
-        return None;
     }
 
-    async fn generate_image_begin(mut self, _: tarpc::context::Context, prompt: String, negative_prompt: String, guidance_scale: f32, num_inference_steps: u32) -> std::string::String {
-        if let Err(e) = self.increment_to_next_free_image_input_nonce().await {
-            eprintln!("[ increment_to_next_free_image_input_nonce ] {:?}", e);
-            return format!("[ increment_to_next_free_image_input_nonce ] {:?}", e);
+    async fn session_create(self, _: tarpc::context::Context, settings: SessionSettings) -> Result<SessionId, OlianaError> {
+        settings.sampling.validate().map_err(OlianaError::InvalidParams)?;
+        match self.shared_jobs.write() {
+            Ok(mut shared_jobs_wg) => shared_jobs_wg.sessions.create(settings),
+            Err(e) => Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
         }
+    }
 
-        let input_data = serde_json::json!({
-            "prompt": prompt,
-            "negative_prompt": negative_prompt,
-            "guidance_scale": guidance_scale,
-            "num_inference_steps": num_inference_steps,
-        });
-        let input_data_s = input_data.to_string();
-
-        let current_text_input_json = self.get_current_image_input_json_path();
-
-        let response_txt_file = self.get_current_image_output_txt_path();
-        if response_txt_file.exists() {
-            if let Err(e) = tokio::fs::remove_file(response_txt_file).await {
-                eprintln!("[ tokio::fs::remove_file ] {:?}", e);
-                return format!("[ tokio::fs::remove_file ] {:?}", e);
-            }
+    async fn session_resume(self, _: tarpc::context::Context, session: SessionId) -> Result<Session, OlianaError> {
+        self.settle_pending_summary(session)?;
+        self.settle_pending_turn(session)?;
+        match self.shared_jobs.read() {
+            Ok(shared_jobs_rg) => Ok(shared_jobs_rg.sessions.get(session)?.clone()),
+            Err(e) => Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
         }
+    }
 
-        let response_png_file = self.get_current_image_output_png_path();
-        if response_png_file.exists() {
-            if let Err(e) = tokio::fs::remove_file(response_png_file).await {
-                eprintln!("[ tokio::fs::remove_file ] {:?}", e);
-                return format!("[ tokio::fs::remove_file ] {:?}", e);
-            }
+    async fn session_list(self, _: tarpc::context::Context) -> Result<Vec<SessionSummary>, OlianaError> {
+        match self.shared_jobs.read() {
+            Ok(shared_jobs_rg) => Ok(shared_jobs_rg.sessions.list()),
+            Err(e) => Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
         }
+    }
 
-        if let Err(e) = tokio::fs::write(current_text_input_json, input_data_s.as_bytes()).await {
-            eprintln!("[ tokio::fs::write ] {:?}", e);
-            return format!("[ tokio::fs::write ] {:?}", e);
+    async fn session_set_settings(self, _: tarpc::context::Context, session: SessionId, settings: SessionSettings) -> Result<(), OlianaError> {
+        settings.sampling.validate().map_err(OlianaError::InvalidParams)?;
+        match self.shared_jobs.write() {
+            Ok(mut shared_jobs_wg) => {
+                shared_jobs_wg.sessions.get_mut(session)?.settings = settings;
+                shared_jobs_wg.sessions.save(session)
+            }
+            Err(e) => Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
         }
-
-        String::new()
     }
 
-    async fn generate_image_result_exists(self, _: tarpc::context::Context) -> bool {
-        let response_txt_file = self.get_current_image_output_txt_path(); // created if error
-        let response_png_file = self.get_current_image_output_png_path(); // created if success
-        return response_txt_file.exists() || response_png_file.exists();
+    async fn session_delete(self, _: tarpc::context::Context, session: SessionId) -> Result<(), OlianaError> {
+        let deleted_session = match self.shared_jobs.write() {
+            Ok(mut shared_jobs_wg) => shared_jobs_wg.sessions.delete(session)?,
+            Err(e) => return Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
+        };
+        let pending_jobs = [deleted_session.pending_turn.map(|pending_turn| pending_turn.job), deleted_session.pending_summary.map(|pending_summary| pending_summary.job)];
+        for pending_job in pending_jobs.into_iter().flatten() {
+            if let Err(e) = self.cancel_job(pending_job) {
+                eprintln!("{}:{} {:?}", file!(), line!(), e);
+            }
+        }
+        Ok(())
     }
 
-    async fn generate_image_get_result(self, _: tarpc::context::Context) -> Vec<u8> {
-        let mut result_bytes: Vec<u8> = Vec::with_capacity(1024 * 1024);
+    async fn session_say(self, _: tarpc::context::Context, session: SessionId, user_message: String) -> Result<JobId, OlianaError> {
+        self.settle_pending_summary(session)?;
+        self.settle_pending_turn(session)?;
+        let text_job = match self.shared_jobs.read() {
+            Ok(shared_jobs_rg) => shared_jobs_rg.sessions.get(session)?.text_job_for(&user_message, shared_jobs_rg.sessions.context_token_budget),
+            Err(e) => return Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
+        };
+        text_job.validate().map_err(OlianaError::InvalidParams)?;
 
-        let response_txt_file = self.get_current_image_output_txt_path();
-        let response_png_file = self.get_current_image_output_png_path();
-
-        let mut remaining_polls_before_give_up: usize = 24 * 10; // 24 seconds worth at 10 polls/sec
-        while !response_txt_file.exists() && !response_png_file.exists() && remaining_polls_before_give_up > 1 {
-            tokio::time::sleep( tokio::time::Duration::from_millis(100) ).await;
-            remaining_polls_before_give_up -= 1;
-        }
+        let job = match self.allocate_job_id(JobKind::Text).await {
+            Ok(job) => job,
+            Err(e) => {
+                eprintln!("[ allocate_job_id ] {:?}", e);
+                return Err(OlianaError::Io(format!("[ allocate_job_id ] {:?}", e)));
+            }
+        };
 
-        if response_png_file.exists() {
-            // Just because it _exists_ doesn't mean we're done writing to it. Give the OS a tick to flush writes and continue when 100ms elapses w/ identical length values for the file
-            remaining_polls_before_give_up = 4 * 10; // 4 seconds at 10 polls/sec
-            let mut last_file_len: u64 = 0;
-            while remaining_polls_before_give_up > 1 {
-                tokio::time::sleep( tokio::time::Duration::from_millis(100) ).await;
-                let mut this_file_len: u64 = 1;
-                if let Ok(mut metadata) = tokio::fs::metadata(&response_png_file).await {
-                    this_file_len = metadata.len();
+        // Claiming the session's turn and queueing the job under one lock means two devices speaking at once cannot both begin a reply,
+        // and nobody settling the turn in between can mistake the job for one which vanished.
+        let queued_job = job_queue::QueuedJob {
+            job: job,
+            client: self.client_socket,
+            priority: self.get_client_priority(),
+            input: oliana_lib::jobs::JobInput::Text(text_job),
+        };
+        match self.shared_jobs.write() {
+            Ok(mut shared_jobs_wg) => {
+                if let Some(ref pending_turn) = shared_jobs_wg.sessions.get(session)?.pending_turn {
+                    return Err(OlianaError::InvalidParams(format!("{} is still replying in job {}; cancel it or wait for it to finish", session, pending_turn.job)));
                 }
-                if this_file_len == last_file_len {
-                    break; // Success!
+                shared_jobs_wg.queue.push(queued_job)?;
+                shared_jobs_wg.sessions.get_mut(session)?.pending_turn = Some(sessions::PendingTurn {
+                    job: job,
+                    user_message: user_message,
+                });
+                // The turn has begun either way; failing to save it only risks losing it if the server restarts before it is settled
+                if let Err(e) = shared_jobs_wg.sessions.save(session) {
+                    eprintln!("{}:{} {:?}", file!(), line!(), e);
                 }
-                last_file_len = this_file_len;
-                remaining_polls_before_give_up -= 1;
             }
-            tokio::time::sleep( tokio::time::Duration::from_millis(100) ).await;
+            Err(e) => return Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
+        }
+        // Wake the dispatcher so an idle backend gets the job straight away
+        self.workdir_watcher.poke();
 
-            if let Ok(mut fd) = tokio::fs::File::open(&response_png_file).await {
-                if let Err(e) = fd.read_to_end(&mut result_bytes).await {
-                    eprintln!("{}:{} {:?}", file!(), line!(), e);
-                }
+        // The turn has begun either way, so a summary which cannot be queued now is retried after the next one
+        if let Err(e) = self.maybe_begin_summary(session).await {
+            eprintln!("{}:{} {:?}", file!(), line!(), e);
+        }
+
+        Ok(job)
+    }
+
+    async fn cancel(self, _: tarpc::context::Context, job: JobId) -> Result<(), OlianaError> {
+        self.cancel_job(job)
+    }
+
+    async fn set_job_priority(self, _: tarpc::context::Context, priority: JobPriority) -> Result<(), OlianaError> {
+        match self.shared_jobs.write() {
+            Ok(mut shared_jobs_wg) => {
+                shared_jobs_wg.client_priorities.insert(self.client_socket, priority);
+                Ok(())
             }
+            Err(e) => Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
         }
+    }
 
-        if response_txt_file.exists() {
-            let response_err_msg = std::fs::read_to_string(&response_txt_file).unwrap_or_else(|_| String::new());
-            eprintln!("Got error from Oliana-Images: {:?}", response_err_msg);
+    async fn job_queue_position(self, _: tarpc::context::Context, job: JobId) -> Result<QueuePosition, OlianaError> {
+        Ok(self.get_queue_position(job))
+    }
+
+    async fn backend_status(self, _: tarpc::context::Context) -> Result<std::collections::HashMap<String, ProcStatus>, OlianaError> {
+        match self.shareable_procs {
+            Some(ref shareable_procs) => match shareable_procs.read() {
+                Ok(procs_rg) => Ok(procs_rg.get_proc_statuses()),
+                Err(e) => Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
+            },
+            None => Ok(std::collections::HashMap::new()),
         }
+    }
 
-        return result_bytes;
+    async fn backend_log(self, _: tarpc::context::Context, backend: String, after_seq: u64) -> Result<Vec<LogLine>, OlianaError> {
+        let proc_log = match self.shareable_procs {
+            Some(ref shareable_procs) => match shareable_procs.read() {
+                Ok(procs_rg) => procs_rg.get_proc_log(&backend),
+                Err(e) => return Err(OlianaError::Io(format!("{}:{} {:?}", file!(), line!(), e))),
+            },
+            None => None,
+        };
+        let proc_log = proc_log.ok_or_else(|| OlianaError::InvalidParams(format!("No backend named {:?} has been started", backend)))?;
+        // Subscribe before reading the ring buffers, so a line written between the two still wakes us
+        let mut line_rx = proc_log.subscribe();
+        let lines = proc_log.lines_after(after_seq, BACKEND_LOG_MAX_LINES);
+        if lines.len() > 0 {
+            return Ok(lines);
+        }
+        // Either a new line arrived or we waited long enough; a lagged or closed channel is no different, the ring buffers have everything
+        let _ = tokio::time::timeout(tokio::time::Duration::from_millis(NEXT_TOKEN_LONG_POLL_MS), line_rx.recv()).await;
+        Ok(proc_log.lines_after(after_seq, BACKEND_LOG_MAX_LINES))
     }
 
-    async fn fetch_pci_hw_device_names(self, _: tarpc::context::Context) -> Vec<String> {
+    async fn fetch_pci_hw_device_names(self, _: tarpc::context::Context) -> Result<Vec<String>, OlianaError> {
         let mut result = vec![];
         match pci_info::PciInfo::enumerate_pci() {
             Ok(pcie_devices) => {
@@ -380,10 +1112,10 @@ impl Oliana for OlianaServer {
             }
             Err(e) => {
                 eprintln!("{}:{} {:?}", file!(), line!(), e);
-                result.push(format!("{:?}", e));
+                return Err(OlianaError::Io(format!("Cannot enumerate PCI devices: {:?}", e)));
             }
         }
-        return result;
+        return Ok(result);
     }
 }
 
@@ -0,0 +1,239 @@
+// Game sessions hold a conversation and its settings on the server, so any connection can continue the same story:
+// a client which re-connects, or a second device which was given the session's ID.
+//
+// Each session is one session-N.json file in the sessions directory, re-written after every change, so sessions also outlive a
+// server restart. Finished turns live in Session::messages; the turn being replied to lives in Session::pending_turn until its
+// text job finishes, at which point the server moves the reply into messages (see OlianaServer::settle_pending_turn()).
+// Long conversations are kept within the model's context window by summarizing their oldest turns, see history.rs.
+
+use crate::{JobId, OlianaError};
+use oliana_lib::jobs::{TextMessage, TextSampling};
+
+// Used by sessions whose creator did not pick a system prompt; this is the storyteller the game talks to.
+pub const DEFAULT_SYSTEM_PROMPT: &str = "You are an ancient storytelling diety named Olly who answers in parables and short stories.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+pub struct SessionId {
+    pub nonce: u64,
+}
+
+impl std::fmt::Display for SessionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "session-{}", self.nonce)
+    }
+}
+
+// Parses the Display form, eg "session-3", so players can pass a session to another device or on the command line.
+impl std::str::FromStr for SessionId {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let nonce_s = s.strip_prefix("session-").ok_or_else(|| format!("Expected a session ID like session-3, got {:?}", s))?;
+        let nonce = nonce_s.parse::<u64>().map_err(|e| format!("{:?} in {:?}", e, s))?;
+        Ok(SessionId { nonce })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SessionSettings {
+    pub title: String, // Shown by session_list(); may be empty
+    pub system_prompt: String, // Sent ahead of the conversation in every text job the session begins
+    pub sampling: TextSampling,
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        Self {
+            title: String::new(),
+            system_prompt: DEFAULT_SYSTEM_PROMPT.to_string(),
+            sampling: TextSampling::default(),
+        }
+    }
+}
+
+// A SummaryJob folding the oldest num_messages of Session::messages into Session::story_so_far.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PendingSummary {
+    pub job: JobId,
+    pub num_messages: usize,
+}
+
+// A user message whose reply is still being generated by job.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PendingTurn {
+    pub job: JobId,
+    pub user_message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Session {
+    pub id: SessionId,
+    pub settings: SessionSettings,
+    pub messages: Vec<TextMessage>, // Finished user + assistant turns, oldest first; the system prompt comes from settings
+    pub pending_turn: Option<PendingTurn>,
+    #[serde(default)]
+    pub story_so_far: String, // A summary of the turns which have been removed from messages; empty until the conversation outgrows its token budget
+    #[serde(default)]
+    pub pending_summary: Option<PendingSummary>,
+    pub created_unix_s: u64,
+    pub updated_unix_s: u64,
+}
+
+impl Session {
+    // The system prompt followed by the story so far, if there is one.
+    pub fn system_message(&self) -> Option<TextMessage> {
+        match (self.settings.system_prompt.len() > 0, self.story_so_far.len() > 0) {
+            (_, true) => Some(TextMessage::system(format!("{}\n\nThe story so far: {}", self.settings.system_prompt, self.story_so_far).trim().to_string())),
+            (true, false) => Some(TextMessage::system(self.settings.system_prompt.clone())),
+            (false, false) => None,
+        }
+    }
+
+    // Everything a text job needs to reply to user_message as the next turn of this session, leaving out the oldest messages which do not fit in context_token_budget.
+    pub fn text_job_for(&self, user_message: &str, context_token_budget: usize) -> oliana_lib::jobs::TextJob {
+        let system_message = self.system_message();
+        let fixed_tokens = system_message.as_ref().map(crate::history::message_tokens).unwrap_or(0)
+                         + crate::history::estimate_tokens(user_message) + crate::history::TOKENS_PER_MESSAGE_OVERHEAD;
+        let kept_messages = crate::history::trim_to_budget(fixed_tokens, &self.messages, context_token_budget);
+        let mut messages = Vec::with_capacity(kept_messages.len() + 2);
+        messages.extend(system_message);
+        messages.extend(kept_messages.iter().cloned());
+        messages.push(TextMessage::user(user_message));
+        oliana_lib::jobs::TextJob {
+            messages: messages,
+            sampling: self.settings.sampling.clone(),
+        }
+    }
+}
+
+// What session_list() reports about each session, without the whole conversation.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SessionSummary {
+    pub id: SessionId,
+    pub title: String,
+    pub num_messages: usize,
+    pub pending_job: Option<JobId>,
+    pub updated_unix_s: u64,
+}
+
+// Every session the server knows about. Sessions are only written to disk when dir is set; the server binary always sets it.
+#[derive(Debug)]
+pub struct SessionStore {
+    pub dir: Option<std::path::PathBuf>,
+    pub next_session_nonce: u64,
+    pub sessions: std::collections::HashMap<SessionId, Session>,
+    pub context_token_budget: usize, // See history::DEFAULT_CONTEXT_TOKEN_BUDGET
+}
+
+impl Default for SessionStore {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            next_session_nonce: 0,
+            sessions: std::collections::HashMap::new(),
+            context_token_budget: crate::history::DEFAULT_CONTEXT_TOKEN_BUDGET,
+        }
+    }
+}
+
+impl SessionStore {
+    // Reads every session-N.json in dir, creating dir if it does not exist yet.
+    pub fn load(dir: impl Into<std::path::PathBuf>) -> Result<Self, Box<dyn std::error::Error>> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).map_err(oliana_lib::eloc!())?;
+        let mut store = Self {
+            dir: Some(dir.clone()),
+            ..Self::default()
+        };
+        for entry in std::fs::read_dir(&dir).map_err(oliana_lib::eloc!())? {
+            let entry_path = entry.map_err(oliana_lib::eloc!())?.path();
+            let is_session_file = entry_path.extension().map(|e| e == "json").unwrap_or(false)
+                && entry_path.file_stem().and_then(|s| s.to_str()).map(|s| s.parse::<SessionId>().is_ok()).unwrap_or(false);
+            if !is_session_file {
+                continue;
+            }
+            let session_json = std::fs::read_to_string(&entry_path).map_err(oliana_lib::eloc!())?;
+            match serde_json::from_str::<Session>(&session_json) {
+                Ok(mut session) => {
+                    // The server empties its work directories at start-up, so a reply or summary in progress when it stopped is gone
+                    session.pending_turn = None;
+                    session.pending_summary = None;
+                    store.next_session_nonce = store.next_session_nonce.max(session.id.nonce + 1);
+                    store.sessions.insert(session.id, session);
+                }
+                Err(e) => {
+                    eprintln!("{}:{} Skipping {}: {:?}", file!(), line!(), entry_path.display(), e);
+                }
+            }
+        }
+        Ok(store)
+    }
+
+    pub fn create(&mut self, settings: SessionSettings) -> Result<SessionId, OlianaError> {
+        let id = SessionId { nonce: self.next_session_nonce };
+        self.next_session_nonce += 1;
+        let now = unix_now_s();
+        self.sessions.insert(id, Session {
+            id: id,
+            settings: settings,
+            messages: vec![],
+            pending_turn: None,
+            story_so_far: String::new(),
+            pending_summary: None,
+            created_unix_s: now,
+            updated_unix_s: now,
+        });
+        self.save(id)?;
+        Ok(id)
+    }
+
+    pub fn get(&self, id: SessionId) -> Result<&Session, OlianaError> {
+        self.sessions.get(&id).ok_or_else(|| OlianaError::InvalidParams(format!("No such session {}", id)))
+    }
+
+    // Callers must save() once they are done changing the session.
+    pub fn get_mut(&mut self, id: SessionId) -> Result<&mut Session, OlianaError> {
+        self.sessions.get_mut(&id).ok_or_else(|| OlianaError::InvalidParams(format!("No such session {}", id)))
+    }
+
+    // Most recently played first
+    pub fn list(&self) -> Vec<SessionSummary> {
+        let mut summaries: Vec<SessionSummary> = self.sessions.values().map(|session| SessionSummary {
+            id: session.id,
+            title: session.settings.title.clone(),
+            num_messages: session.messages.len(),
+            pending_job: session.pending_turn.as_ref().map(|pending_turn| pending_turn.job),
+            updated_unix_s: session.updated_unix_s,
+        }).collect();
+        summaries.sort_by(|a, b| b.updated_unix_s.cmp(&a.updated_unix_s).then(b.id.cmp(&a.id)));
+        summaries
+    }
+
+    // Stamps the session as updated and writes it to disk.
+    pub fn save(&mut self, id: SessionId) -> Result<(), OlianaError> {
+        let session = self.get_mut(id)?;
+        session.updated_unix_s = unix_now_s();
+        if let Some(ref dir) = self.dir {
+            let session = self.get(id)?;
+            let session_json = serde_json::to_vec_pretty(session).map_err(|e| OlianaError::Io(format!("{:?}", e)))?;
+            oliana_lib::jobs::write_atomically(&dir.join(format!("{}.json", id)), &session_json)?;
+        }
+        Ok(())
+    }
+
+    pub fn delete(&mut self, id: SessionId) -> Result<Session, OlianaError> {
+        let session = self.sessions.remove(&id).ok_or_else(|| OlianaError::InvalidParams(format!("No such session {}", id)))?;
+        if let Some(ref dir) = self.dir {
+            match std::fs::remove_file(dir.join(format!("{}.json", id))) {
+                Ok(()) => { }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => { }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(session)
+    }
+}
+
+fn unix_now_s() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
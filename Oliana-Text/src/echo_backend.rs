@@ -0,0 +1,159 @@
+
+// A stand-in for the LLM which needs no model files or GPU; run oliana_text with `--backend echo` (or OLIANA_TEXT_BACKEND=echo).
+// It speaks the same work directory protocol as the real backend (see oliana_lib::jobs) and replies to each TextJob by streaming
+// its last user message back one word at a time, so tests can predict every token; earlier messages are ignored, and a SummaryJob echoes
+// the transcript it asks to have summarized. Each word counts as one token for sampling.max_tokens, and the reply ends before the first
+// of sampling.stop it would contain; the other sampling settings are ignored.
+//
+// Words in the last user message written as [[directive]] are acted on instead of echoed, which lets a test script a failure from its prompt:
+//   [[sleep-ms=N]]  pause for N milliseconds before the next word
+//   [[fail]]        stop and mark the job failed, after the words before it have been streamed
+//   [[fail-oom]]    the same, but failing the way a real backend which ran out of GPU memory does (a retryable OutOfMemory JobError)
+//   [[crash]]       exit the whole process, as if oliana_text had crashed mid-job
+
+pub const DEFAULT_TOKEN_DELAY_MS: u64 = 50;
+
+#[derive(Debug, Clone)]
+pub struct EchoConfig {
+    pub token_delay_ms: u64, // Pause before each streamed word; set by --echo-token-delay-ms or OLIANA_ECHO_TOKEN_DELAY_MS
+    pub load_delay_ms: u64, // How long to pretend to load a model before taking jobs; set by --echo-load-delay-ms or OLIANA_ECHO_LOAD_DELAY_MS, default 0
+}
+
+impl EchoConfig {
+    pub fn from_args(args: &[String]) -> Self {
+        Self {
+            token_delay_ms: ms_arg(args, "--echo-token-delay-ms", "OLIANA_ECHO_TOKEN_DELAY_MS", DEFAULT_TOKEN_DELAY_MS),
+            load_delay_ms: ms_arg(args, "--echo-load-delay-ms", "OLIANA_ECHO_LOAD_DELAY_MS", 0),
+        }
+    }
+}
+
+// The number of milliseconds after flag in args, else in the environment variable env_var_name, else default_ms
+fn ms_arg(args: &[String], flag: &str, env_var_name: &str, default_ms: u64) -> u64 {
+    let mut ms: u64 = std::env::var(env_var_name).ok().and_then(|v| v.parse().ok()).unwrap_or(default_ms);
+    if let Some(flag_i) = args.iter().position(|n| n == flag) {
+        if flag_i < args.len()-1 {
+            match args[flag_i+1].parse::<u64>() {
+                Ok(parsed_ms) => { ms = parsed_ms; }
+                Err(e) => { eprintln!("{}:{} Ignoring {} {:?}: {:?}", file!(), line!(), flag, args[flag_i+1], e); }
+            }
+        }
+    }
+    ms
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum EchoStep<'a> {
+    Token(&'a str),
+    SleepMs(u64),
+    Fail,
+    FailOutOfMemory,
+    Crash,
+}
+
+// Splits a prompt into the words we stream back, keeping the whitespace after each word so the reply concatenates back into the prompt.
+fn echo_steps(user_prompt: &str) -> Vec<EchoStep<'_>> {
+    let mut steps = vec![];
+    for token in user_prompt.split_inclusive(char::is_whitespace) {
+        let directive = token.trim().strip_prefix("[[").and_then(|t| t.strip_suffix("]]"));
+        match directive {
+            Some("fail") => steps.push(EchoStep::Fail),
+            Some("fail-oom") => steps.push(EchoStep::FailOutOfMemory),
+            Some("crash") => steps.push(EchoStep::Crash),
+            Some(d) if d.starts_with("sleep-ms=") => {
+                match d["sleep-ms=".len()..].parse::<u64>() {
+                    Ok(sleep_ms) => steps.push(EchoStep::SleepMs(sleep_ms)),
+                    Err(_) => steps.push(EchoStep::Token(token)), // Not a directive we understand, so echo it like any other word
+                }
+            }
+            _ => steps.push(EchoStep::Token(token)),
+        }
+    }
+    steps
+}
+
+pub async fn process_echo_job(config: &EchoConfig, job_files: &oliana_lib::jobs::JobFiles, heartbeat: &oliana_lib::heartbeat::Heartbeat) -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::io::AsyncWriteExt;
+
+    println!("Echoing {}", job_files.input_path().display());
+    let activity = format!("working on {}", job_files.name);
+    heartbeat.beat(&activity);
+
+    // Same guarantee as process_text_job(): once we are done with a job it is never left Running.
+    let mut final_state_writer = oliana_lib::jobs::WriteStatusOnDropped::new(job_files.clone(), "oliana_text");
+
+    tokio::fs::write(job_files.text_output_path(), "".as_bytes()).await?;
+    job_files.write_state(oliana_lib::jobs::JobState::Running)?;
+
+    // Summaries are ordinary chat requests once SummaryJob::to_text_job() has written their prompt
+    let text_job = match job_files.read_input::<oliana_lib::jobs::TextBackendJob>() {
+        Ok(text_backend_job) => text_backend_job.into_text_job(),
+        Err(e) => {
+            final_state_writer.set_outcome(Err(oliana_lib::jobs::JobError::new(oliana_lib::jobs::JobErrorKind::InvalidInput, format!("Cannot read {}: {:?}", job_files.input_path().display(), e))));
+            return Err(e.into());
+        }
+    };
+    eprintln!("Read text_job = {:?}", &text_job);
+
+    let mut out_txt_fd = tokio::fs::File::options()
+                            .append(true)
+                            .open(job_files.text_output_path()).await?;
+
+    let mut final_outcome: Result<oliana_lib::jobs::JobState, oliana_lib::jobs::JobError> = Ok(oliana_lib::jobs::JobState::Done);
+    let mut reply = String::new();
+    let mut num_tokens: usize = 0;
+    let user_prompt = text_job.messages.iter().rev()
+                        .find(|message| message.role == oliana_lib::jobs::TextRole::User)
+                        .map(|message| &message.content[..])
+                        .unwrap_or("");
+    for step in echo_steps(user_prompt) {
+        // [[sleep-ms=N]] does not beat, so a long enough one looks like a hang to oliana_server
+        heartbeat.beat(&activity);
+        if job_files.is_cancel_requested() {
+            println!("Cancelling {} because {} exists", job_files.input_path().display(), job_files.cancel_request_path().display());
+            final_outcome = Ok(oliana_lib::jobs::JobState::Cancelled);
+            break;
+        }
+        match step {
+            EchoStep::Token(token) => {
+                if text_job.sampling.max_tokens.map(|max_tokens| num_tokens >= max_tokens).unwrap_or(false) {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(config.token_delay_ms)).await;
+                let reply_len_before = reply.len();
+                reply.push_str(token);
+                num_tokens += 1;
+                let stop_i = text_job.sampling.stop.iter().filter_map(|stop_string| reply.find(&stop_string[..])).min();
+                // A stop string may have begun in an earlier token, in which case none of this token is written
+                let token_end_i = stop_i.unwrap_or(reply.len()).max(reply_len_before);
+                out_txt_fd.write_all(reply[reply_len_before..token_end_i].as_bytes()).await?;
+                out_txt_fd.flush().await?;
+                if stop_i.is_some() {
+                    break;
+                }
+            }
+            EchoStep::SleepMs(sleep_ms) => {
+                tokio::time::sleep(std::time::Duration::from_millis(sleep_ms)).await;
+            }
+            EchoStep::Fail => {
+                final_outcome = Err(oliana_lib::jobs::JobError::new(oliana_lib::jobs::JobErrorKind::Exception, "EchoBackendError: the prompt asked the echo backend to fail with [[fail]]"));
+                break;
+            }
+            EchoStep::FailOutOfMemory => {
+                final_outcome = Err(oliana_lib::jobs::JobError::from_traceback(oliana_lib::jobs::JobErrorKind::Exception, "EchoBackendError: CUDA out of memory, as the prompt asked with [[fail-oom]]"));
+                break;
+            }
+            EchoStep::Crash => {
+                eprintln!("Exiting because the prompt of {} asked the echo backend to crash with [[crash]]", job_files.input_path().display());
+                out_txt_fd.flush().await?;
+                // Skips WriteStatusOnDropped, so the job is left Running exactly as a real crash would leave it
+                std::process::exit(1);
+            }
+        }
+    }
+
+    out_txt_fd.flush().await?;
+    final_state_writer.set_outcome(final_outcome);
+
+    Ok(())
+}
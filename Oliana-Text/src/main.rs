@@ -17,9 +17,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
   Ok(())
 }
 
+mod echo_backend;
+
 use mistralrs::{
     MemoryGpuConfig,
-    IsqType, PagedAttentionMetaBuilder, TextMessageRole, TextMessages, TextModelBuilder,
+    IsqType, PagedAttentionMetaBuilder, RequestBuilder, StopTokens, TextMessageRole, TextMessages, TextModelBuilder,
 };
 use tokio::io::AsyncWriteExt;
 
@@ -39,13 +41,23 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
     return Ok(());
   }
 
+  // "mistralrs" runs the real LLM; "echo" swaps it for echo_backend, which needs no model files or GPU and is meant for testing
+  let mut backend_name = std::env::var("OLIANA_TEXT_BACKEND").unwrap_or("mistralrs".to_string());
+  if let Some(backend_i) = args.iter().position(|n| n == "--backend") {
+    if backend_i < args.len()-1 {
+      backend_name = args[backend_i+1].clone();
+    }
+  }
+
   println!("");
   println!("Using {env_var_work_dir} as a work directory.");
   println!("write files named 'NAME.json' containing objects like:");
-  println!(r#" {{"system_prompt": "You are an AI agent with a specialty in cooking.", "user_prompt": "Hello! How are you? I'd like to bake a pie but do not know how, please help me!", }}"#);
-  println!("and wait for 'NAME.txt' to be written back from this process.");
-  println!("'NAME.json' will remain post-generation, and if the file's mtime becomes newer it will be processed again with the new contents.");
-  println!("If 'NAME.json' has an mtime older than this process's start time, it will not be processed.");
+  println!(r#" {{"messages": [{{"role": "system", "content": "You are an AI agent with a specialty in cooking."}}, {{"role": "user", "content": "Hello! How are you? I'd like to bake a pie but do not know how, please help me!"}}] }}"#);
+  println!(r#"(or {{"story_so_far": "", "summarize": [ ...messages... ], "max_tokens": 384 }} to have the conversation summarized instead of answered)"#);
+  println!(r#"followed by 'NAME.state' containing {{"state": "queued"}}, and wait for 'NAME.txt' to be written back from this process."#);
+  println!("'NAME.state' becomes running, then done once 'NAME.txt' is complete (or failed, after writing why to 'NAME.error.json').");
+  println!("Creating 'NAME.cancel' stops generation early, in which case 'NAME.state' becomes cancelled instead of done.");
+  println!("Only queued jobs are processed, so finished jobs are never re-run after a restart; see oliana_lib::jobs for the details.");
   println!("");
 
   tokio::fs::create_dir_all(&env_var_work_dir[..]).await?;
@@ -54,6 +66,73 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
     "WORK_DIR", env_var_work_dir.clone()
   );
 
+  // oliana_server counts us as starting until heartbeat.ready() below, see oliana_lib::heartbeat
+  let heartbeat = oliana_lib::heartbeat::Heartbeat::from_env();
+
+  let backend = match &backend_name[..] {
+    "mistralrs" => {
+      let _loading_beats = heartbeat.keep_beating("loading model");
+      TextBackend::Mistral(load_mistralrs_model().await?)
+    }
+    "echo" => {
+      let echo_config = echo_backend::EchoConfig::from_args(&args);
+      if echo_config.load_delay_ms > 0 {
+        let _loading_beats = heartbeat.keep_beating("loading model");
+        println!("Pretending to load a model for {}ms", echo_config.load_delay_ms);
+        tokio::time::sleep(std::time::Duration::from_millis(echo_config.load_delay_ms)).await;
+      }
+      println!("Using the echo backend; replies repeat the last user message one word every {}ms", echo_config.token_delay_ms);
+      TextBackend::Echo(echo_config)
+    }
+    unk => {
+      return Err(format!("Unknown backend {:?} from --backend or OLIANA_TEXT_BACKEND, expected mistralrs or echo", unk).into());
+    }
+  };
+
+  // Subscribing before the first scan means a job queued while we scan still wakes the wait below
+  let workdir_watcher = oliana_lib::watch::WorkdirWatcher::new(&[&env_var_work_dir]).map_err(oliana_lib::eloc!())?;
+  let mut workdir_changes = workdir_watcher.subscribe();
+  println!("Watching {env_var_work_dir} for new jobs ({:?})", workdir_watcher.backend);
+  heartbeat.ready("idle");
+
+  let mut allowed_errors_remaining = 100;
+  loop {
+    match oliana_lib::jobs::find_queued_jobs(&env_var_work_dir, "oliana_text") {
+      Ok(queued_jobs) => {
+        for job_files in queued_jobs {
+          let job_result = match &backend {
+            TextBackend::Mistral(model) => process_text_job(model, &job_files, &heartbeat).await,
+            TextBackend::Echo(echo_config) => echo_backend::process_echo_job(echo_config, &job_files, &heartbeat).await,
+          };
+          if let Err(e) = job_result {
+            allowed_errors_remaining -= 1;
+            eprintln!("{}:{} {:?}", file!(), line!(), e);
+          }
+        }
+      }
+      Err(e) => {
+        allowed_errors_remaining -= 1;
+        eprintln!("{}:{} {:?}", file!(), line!(), e);
+      }
+    }
+    if allowed_errors_remaining < 1 {
+        break;
+    }
+    heartbeat.beat("idle");
+    // Sleep until the work directory changes; the timeout is only a safety net for a watch which missed an event
+    workdir_changes.wait(std::time::Duration::from_secs(5)).await;
+  }
+
+  Ok(())
+}
+
+enum TextBackend {
+  Mistral(mistralrs::Model),
+  Echo(echo_backend::EchoConfig),
+}
+
+// Points HF_HOME at our cache folder and loads the LLM, downloading model files on first run.
+async fn load_mistralrs_model() -> Result<mistralrs::Model, Box<dyn std::error::Error>> {
   let hf_home = oliana_lib::files::get_cache_file("Oliana-Text-hf_home").map_err(oliana_lib::eloc!())?;
   let hf_home = hf_home.to_string_lossy();
   tokio::fs::create_dir_all(&hf_home[..]).await?;
@@ -77,164 +156,150 @@ async fn main_async() -> Result<(), Box<dyn std::error::Error>> {
         .build()
         .await.map_err(oliana_lib::eloc!())?;
 
-  let our_start_time = std::time::SystemTime::now();
-  let mut last_seen_mtime = std::collections::HashMap::<std::path::PathBuf, std::time::SystemTime>::new();
-  let mut allowed_errors_remaining = 100;
-  loop {
-    let mut dir_iterator = tokio::fs::read_dir(&env_var_work_dir).await?;
-    while let Some(entry) = dir_iterator.next_entry().await? {
-        let entry_path = entry.path();
-        if entry_path.is_file() && entry_path.extension().and_then(std::ffi::OsStr::to_str).unwrap_or("").ends_with("json") {
-            // Have JSON, is it new?
-            match tokio::fs::metadata(&entry_path).await {
-                Ok(entry_metadata) => {
-                    if let Ok(file_mtime) = entry_metadata.modified() {
-                        if file_mtime > our_start_time && file_mtime > *last_seen_mtime.get(&entry_path).unwrap_or(&std::time::SystemTime::UNIX_EPOCH) {
-                            // we're newer than this process's begin and we're newer than the last mtime we saw, falling back to Jan 01 1970 if never seen file before.
-                            println!("Processing {}", entry_path.display());
-                            last_seen_mtime.insert(entry_path.clone(), std::time::SystemTime::now());
-
-                            let mut out_txt_file = entry_path.clone();
-                            out_txt_file.set_extension("txt");
-                            let out_txt_file = out_txt_file;
-
-                            let mut out_done_file = entry_path.clone();
-                            out_done_file.set_extension("done");
-                            let out_done_file = out_done_file;
-                            if out_done_file.exists() {
-                                tokio::fs::remove_file(&out_done_file).await?;
-                            }
-
-                            // This has a Drop trait which creates the passed-in file when it is no longer in scope; combined with the error? returns below,
-                            // this guarantees when the computation is done, out_done_file exists.
-                            let out_done_writer = CreateFileOnDropped::new(out_done_file);
-
-                            let input_json_text = tokio::fs::read_to_string(&entry_path).await?;
-                            let input_data: serde_json::Value = serde_json::from_str(&input_json_text)?;
-                            eprintln!("Read input_data = {input_json_text}");
-
-                            let mut system_prompt = "".to_string();
-                            let mut user_prompt = "".to_string();
-
-                            if let serde_json::value::Value::Object(input_obj) = input_data {
-                                if let Some(serde_json::value::Value::String(input_system_prompt)) = input_obj.get("system_prompt") {
-                                    system_prompt = input_system_prompt.to_string();
-                                }
-                                if let Some(serde_json::value::Value::String(input_user_prompt)) = input_obj.get("user_prompt") {
-                                    user_prompt = input_user_prompt.to_string();
-                                }
-                            }
-
-                            let messages = TextMessages::new()
-                            .add_message(
-                                TextMessageRole::System,
-                                &system_prompt[..],
-                            )
-                            .add_message(
-                                TextMessageRole::User,
-                                &user_prompt[..],
-                            );
-
-                            // First zero the file we write chunks to
-                            tokio::fs::write(out_txt_file.as_path(), "".as_bytes()).await?;
-                            // Then open in append mode
-                            let mut out_txt_fd = tokio::fs::File::options()
-                                                    .append(true)
-                                                    .open(out_txt_file.as_path()).await?;
-
-                            match model.stream_chat_request(messages).await.map_err(oliana_lib::eloc!()) {
-                                Ok(mut response_stream) => {
-                                    while let Some(ref response) = response_stream.next().await {
-                                        match response {
-                                            mistralrs::Response::InternalError(err) => {
-                                                out_txt_fd.write_all(format!("\n{:?}\n", err).as_bytes()).await?;
-                                                break;
-                                            },
-                                            mistralrs::Response::ValidationError(err) => {
-                                                out_txt_fd.write_all(format!("\n{:#?}\n", err).as_bytes()).await?;
-                                                break;
-                                            },
-                                            mistralrs::Response::ModelError(s, completion_response) => {
-                                                out_txt_fd.write_all(format!("\n{:#?},{:#?}\n", s, completion_response).as_bytes()).await?;
-                                                break;
-                                            },
-                                            mistralrs::Response::Done(_completion_response) => {
-                                                //out_txt_fd.write_all(format!("\n{:#?}\n", completion_response).as_bytes()).await?;
-                                                break;
-                                            },
-                                            mistralrs::Response::Chunk(chunk) => {
-                                                //out_txt_fd.write_all(format!("\n{:#?}\n", chunk).as_bytes()).await?;
-                                                for choice in chunk.choices.iter() {
-                                                    out_txt_fd.write_all(format!("{}", choice.delta.content ).as_bytes()).await?;
-                                                }
-                                            },
-                                            mistralrs::Response::CompletionModelError(s, completion_response) => {
-                                                out_txt_fd.write_all(format!("\n{:#?},{:#?}\n", s, completion_response).as_bytes()).await?;
-                                            },
-                                            mistralrs::Response::CompletionDone(_completion_response) => {
-                                                //out_txt_fd.write_all(format!("\n{:#?}\n", completion_response).as_bytes()).await?;
-                                                break;
-                                            },
-                                            mistralrs::Response::CompletionChunk(chunk) => {
-                                                out_txt_fd.write_all(format!("\n{:#?}\n", chunk).as_bytes()).await?;
-                                            },
-                                            mistralrs::Response::ImageGeneration(image_gen_response) => {
-                                                out_txt_fd.write_all(format!("\n{:#?}\n", image_gen_response).as_bytes()).await?;
-                                            },
-                                            _unused_raw => { /* NOP */ }
-                                        }
-                                        out_txt_fd.flush().await?;
-
-                                    }
-
-                                }
-                                Err(e) => {
-                                    allowed_errors_remaining -= 1;
-                                    eprintln!("{}:{} {:?}", file!(), line!(), e);
-                                    out_txt_fd.write_all(format!("\n{:#?}\n", e).as_bytes()).await?;
-                                }
-                            }
-
-                            std::mem::drop(out_done_writer);
+  Ok(model)
+}
 
-                        }
+async fn process_text_job(model: &mistralrs::Model, job_files: &oliana_lib::jobs::JobFiles, heartbeat: &oliana_lib::heartbeat::Heartbeat) -> Result<(), Box<dyn std::error::Error>> {
+    println!("Processing {}", job_files.input_path().display());
+    let activity = format!("working on {}", job_files.name);
+    heartbeat.beat(&activity);
+
+    // This has a Drop trait which writes the job's final state when it is no longer in scope; combined with the error? returns below,
+    // this guarantees that once we are done with a job it is never left Running.
+    let mut final_state_writer = oliana_lib::jobs::WriteStatusOnDropped::new(job_files.clone(), "oliana_text");
+
+    // First zero the file we write chunks to, so it exists as soon as the job is Running
+    tokio::fs::write(job_files.text_output_path(), "".as_bytes()).await?;
+    job_files.write_state(oliana_lib::jobs::JobState::Running)?;
+
+    // Summaries are ordinary chat requests once SummaryJob::to_text_job() has written their prompt
+    let text_job = match job_files.read_input::<oliana_lib::jobs::TextBackendJob>() {
+        Ok(text_backend_job) => text_backend_job.into_text_job(),
+        Err(e) => {
+            final_state_writer.set_outcome(Err(oliana_lib::jobs::JobError::new(oliana_lib::jobs::JobErrorKind::InvalidInput, format!("Cannot read {}: {:?}", job_files.input_path().display(), e))));
+            return Err(e.into());
+        }
+    };
+    eprintln!("Read text_job = {:?}", &text_job);
+
+    let mut messages = TextMessages::new();
+    for message in text_job.messages.iter() {
+        let role = match message.role {
+            oliana_lib::jobs::TextRole::System => TextMessageRole::System,
+            oliana_lib::jobs::TextRole::User => TextMessageRole::User,
+            oliana_lib::jobs::TextRole::Assistant => TextMessageRole::Assistant,
+        };
+        messages = messages.add_message(role, &message.content[..]);
+    }
+    let request = apply_sampling(RequestBuilder::from(messages), &text_job.sampling);
+
+    // Then open in append mode
+    let mut out_txt_fd = tokio::fs::File::options()
+                            .append(true)
+                            .open(job_files.text_output_path()).await?;
+
+    if job_files.is_cancel_requested() {
+        println!("Not processing {} because it was cancelled", job_files.input_path().display());
+        final_state_writer.set_outcome(Ok(oliana_lib::jobs::JobState::Cancelled));
+        return Ok(());
+    }
 
-                    }
+    // Done unless the stream tells us otherwise
+    let mut final_outcome: Result<oliana_lib::jobs::JobState, oliana_lib::jobs::JobError> = Ok(oliana_lib::jobs::JobState::Done);
+    match model.stream_chat_request(request).await.map_err(oliana_lib::eloc!()) {
+        Ok(mut response_stream) => {
+            while let Some(ref response) = response_stream.next().await {
+                heartbeat.beat(&activity);
+                if job_files.is_cancel_requested() {
+                    // Dropping response_stream at the end of this block tells mistralrs to stop generating for us
+                    println!("Cancelling {} because {} exists", job_files.input_path().display(), job_files.cancel_request_path().display());
+                    final_outcome = Ok(oliana_lib::jobs::JobState::Cancelled);
+                    break;
                 }
-                Err(e) => {
-                    allowed_errors_remaining -= 1;
-                    eprintln!("{}:{} {:?}", file!(), line!(), e);
+                match response {
+                    mistralrs::Response::InternalError(err) => {
+                        final_outcome = Err(job_error(oliana_lib::jobs::JobErrorKind::Exception, err));
+                        break;
+                    },
+                    mistralrs::Response::ValidationError(err) => {
+                        final_outcome = Err(job_error(oliana_lib::jobs::JobErrorKind::InvalidInput, err));
+                        break;
+                    },
+                    mistralrs::Response::ModelError(s, completion_response) => {
+                        final_outcome = Err(oliana_lib::jobs::JobError::from_traceback(oliana_lib::jobs::JobErrorKind::Exception, s).with_traceback(format!("{:#?},{:#?}", s, completion_response)));
+                        break;
+                    },
+                    mistralrs::Response::Done(_completion_response) => {
+                        //out_txt_fd.write_all(format!("\n{:#?}\n", completion_response).as_bytes()).await?;
+                        break;
+                    },
+                    mistralrs::Response::Chunk(chunk) => {
+                        //out_txt_fd.write_all(format!("\n{:#?}\n", chunk).as_bytes()).await?;
+                        for choice in chunk.choices.iter() {
+                            out_txt_fd.write_all(format!("{}", choice.delta.content ).as_bytes()).await?;
+                        }
+                    },
+                    mistralrs::Response::CompletionModelError(s, completion_response) => {
+                        final_outcome = Err(oliana_lib::jobs::JobError::from_traceback(oliana_lib::jobs::JobErrorKind::Exception, s).with_traceback(format!("{:#?},{:#?}", s, completion_response)));
+                        break;
+                    },
+                    mistralrs::Response::CompletionDone(_completion_response) => {
+                        //out_txt_fd.write_all(format!("\n{:#?}\n", completion_response).as_bytes()).await?;
+                        break;
+                    },
+                    mistralrs::Response::CompletionChunk(chunk) => {
+                        out_txt_fd.write_all(format!("\n{:#?}\n", chunk).as_bytes()).await?;
+                    },
+                    mistralrs::Response::ImageGeneration(image_gen_response) => {
+                        out_txt_fd.write_all(format!("\n{:#?}\n", image_gen_response).as_bytes()).await?;
+                    },
+                    _unused_raw => { /* NOP */ }
                 }
+                out_txt_fd.flush().await?;
+
             }
-        }
 
+        }
+        Err(e) => {
+            final_state_writer.set_outcome(Err(job_error(oliana_lib::jobs::JobErrorKind::Exception, &e)));
+            return Err(e.into());
+        }
     }
-    if allowed_errors_remaining < 1 {
-        break;
-    }
-    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-  }
 
-  Ok(())
-}
+    // NAME.txt must be complete before the job is marked Done; the server stops reading once it sees Done.
+    out_txt_fd.flush().await?;
+    final_state_writer.set_outcome(final_outcome);
 
-#[clippy::has_significant_drop]
-pub struct CreateFileOnDropped {
-    pub file_path: std::path::PathBuf,
+    Ok(())
 }
 
-impl CreateFileOnDropped {
-    pub fn new(file_path: std::path::PathBuf) -> Self {
-        Self {
-            file_path: file_path
+// Settings left as None keep mistralrs' defaults.
+fn apply_sampling(mut request: RequestBuilder, sampling: &oliana_lib::jobs::TextSampling) -> RequestBuilder {
+    if sampling.deterministic {
+        // TextSampling::validate() keeps temperature, top_p and top_k out of deterministic jobs.
+        request = request.set_deterministic_sampler();
+    }
+    else {
+        if let Some(temperature) = sampling.temperature {
+            request = request.set_sampler_temperature(temperature);
         }
+        if let Some(top_p) = sampling.top_p {
+            request = request.set_sampler_topp(top_p);
+        }
+        if let Some(top_k) = sampling.top_k {
+            request = request.set_sampler_topk(top_k);
+        }
+    }
+    if let Some(max_tokens) = sampling.max_tokens {
+        request = request.set_sampler_max_len(max_tokens);
     }
+    if sampling.stop.len() > 0 {
+        request = request.set_sampler_stop_toks(StopTokens::Seqs(sampling.stop.clone()));
+    }
+    request
 }
 
-impl Drop for CreateFileOnDropped {
-    fn drop(&mut self) {
-        if let Err(e) = std::fs::write(self.file_path.as_path(), " ".as_bytes()) {
-            eprintln!("{:?} when creating file {}", e, self.file_path.display());
-        }
-    }
+// mistralrs reports errors as values rather than tracebacks, so the one-line debug print becomes the message and the pretty one the traceback.
+fn job_error(kind: oliana_lib::jobs::JobErrorKind, error: &impl std::fmt::Debug) -> oliana_lib::jobs::JobError {
+    oliana_lib::jobs::JobError::from_traceback(kind, &format!("{:?}", error)).with_traceback(format!("{:#?}", error))
 }